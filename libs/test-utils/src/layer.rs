@@ -0,0 +1,58 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Layer tarballs for the tests of unpacking and snapshots, and the
+//! entries of image archives.
+
+use std::io::Write;
+
+/// Build a layer tarball of the entries in order, the entries without
+/// data are directories.
+pub fn layer_tarball(entries: &[(&str, Option<&str>)]) -> Vec<u8> {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|(path, data)| (*path, *data, if data.is_some() { 0o644 } else { 0o755 }))
+        .collect();
+
+    layer_tarball_with_owner(&entries, 0, 0)
+}
+
+/// Build a layer tarball like [`layer_tarball`], with the entries of the
+/// given modes and owned by uid and gid.
+pub fn layer_tarball_with_owner(
+    entries: &[(&str, Option<&str>, u32)],
+    uid: u64,
+    gid: u64,
+) -> Vec<u8> {
+    let mut ar = tar::Builder::new(Vec::new());
+
+    for (path, data, mode) in entries.iter() {
+        let mut header = tar::Header::new_gnu();
+        let data = match data {
+            Some(data) => {
+                header.set_entry_type(tar::EntryType::Regular);
+                data.as_bytes()
+            }
+            None => {
+                header.set_entry_type(tar::EntryType::Directory);
+                &[]
+            }
+        };
+        header.set_mode(*mode);
+        header.set_uid(uid);
+        header.set_gid(gid);
+        header.set_size(data.len() as u64);
+        ar.append_data(&mut header, path, data).unwrap();
+    }
+
+    ar.into_inner().unwrap()
+}
+
+/// Append a regular file of the data to an image archive or a layer.
+pub fn append_file<W: Write>(ar: &mut tar::Builder<W>, path: &str, data: &[u8]) {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    ar.append_data(&mut header, path, data).unwrap();
+}
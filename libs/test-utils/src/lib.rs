@@ -2,6 +2,9 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod layer;
+pub mod registry;
+
 // Parameters:
 //
 // 1: expected Result
@@ -26,3 +29,23 @@ macro_rules! assert_result {
         }
     };
 }
+
+#[macro_export]
+macro_rules! skip_if_root {
+    () => {
+        if nix::unistd::Uid::effective().is_root() {
+            println!("INFO: skipping {} which needs non-root", module_path!());
+            return;
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! skip_if_not_root {
+    () => {
+        if !nix::unistd::Uid::effective().is_root() {
+            println!("INFO: skipping {} which needs root", module_path!());
+            return;
+        }
+    };
+}
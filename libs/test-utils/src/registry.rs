@@ -0,0 +1,348 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A minimal plain HTTP stand-in of an OCI distribution registry, which
+//! serves the manifests and blobs added by the tests.
+
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+
+/// The media type of the layers pushed by `TestRegistry::push_image`.
+pub const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+
+#[derive(Default)]
+struct State {
+    // manifests holds map of "<repository>/<tag or digest>" with the
+    // media type, digest and content of the manifest.
+    manifests: HashMap<String, (String, String, Vec<u8>)>,
+
+    // blobs holds map of "<repository>/<digest>" with the blob content.
+    blobs: HashMap<String, Vec<u8>>,
+
+    // faults holds map of request path with the faults to inject into
+    // the next requests of the path.
+    faults: HashMap<String, VecDeque<Fault>>,
+
+    // requests holds the "<method> <path>" of the served requests.
+    requests: Vec<String>,
+
+    // authorized holds the "<method> <path>" of the served requests with
+    // an Authorization header.
+    authorized: Vec<String>,
+
+    // in_flight is the number of the requests received but not responded
+    // yet, and peak_in_flight is its max so far.
+    in_flight: usize,
+    peak_in_flight: usize,
+}
+
+/// A fault injected into a response of the registry.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Respond with the status code and an empty body.
+    Status(u16),
+
+    /// Close the connection after sending the given number of body bytes.
+    Truncate(usize),
+
+    /// Respond after the delay.
+    Delay(Duration),
+}
+
+/// A registry listening on a random local port.
+pub struct TestRegistry {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl TestRegistry {
+    /// Start serving on the current tokio runtime.
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, state).await;
+                });
+            }
+        });
+
+        TestRegistry { addr, state }
+    }
+
+    /// The `host:port` of the registry to use in image references.
+    pub fn host(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Add a blob to the repository and return its digest.
+    pub fn add_blob(&self, repository: &str, data: &[u8]) -> String {
+        let digest = sha256_digest(data);
+        self.state
+            .lock()
+            .unwrap()
+            .blobs
+            .insert(format!("{}/{}", repository, digest), data.to_vec());
+
+        digest
+    }
+
+    /// Add a manifest to the repository under the tag and its digest,
+    /// and return the digest.
+    pub fn add_manifest(
+        &self,
+        repository: &str,
+        tag: &str,
+        media_type: &str,
+        data: &[u8],
+    ) -> String {
+        let digest = sha256_digest(data);
+        let manifest = (media_type.to_string(), digest.clone(), data.to_vec());
+
+        let mut state = self.state.lock().unwrap();
+        for reference in [tag, digest.as_str()] {
+            state
+                .manifests
+                .insert(format!("{}/{}", repository, reference), manifest.clone());
+        }
+
+        digest
+    }
+
+    /// Push a single layer image of the layer tarball under the tag, with
+    /// a linux/amd64 config of the layer, and return the layer digest.
+    pub fn push_image(&self, repository: &str, tag: &str, layer: &[u8]) -> String {
+        let layer_digest = self.add_blob(repository, layer);
+
+        let config = serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": [layer_digest]}
+        })
+        .to_string();
+        let config_digest = self.add_blob(repository, config.as_bytes());
+
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "config": {
+                "mediaType": CONFIG_MEDIA_TYPE,
+                "digest": config_digest,
+                "size": config.len()
+            },
+            "layers": [{
+                "mediaType": LAYER_MEDIA_TYPE,
+                "digest": layer_digest,
+                "size": layer.len()
+            }]
+        });
+        self.add_manifest(
+            repository,
+            tag,
+            MANIFEST_MEDIA_TYPE,
+            manifest.to_string().as_bytes(),
+        );
+
+        layer_digest
+    }
+
+    /// Inject a fault into the next request of the path which has not
+    /// been injected with a fault yet.
+    pub fn add_fault(&self, path: &str, fault: Fault) {
+        self.state
+            .lock()
+            .unwrap()
+            .faults
+            .entry(path.to_string())
+            .or_default()
+            .push_back(fault);
+    }
+
+    /// The "<method> <path>" of all the requests served so far, with a
+    /// " <range>" suffix for range requests.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The "<method> <path>" of the requests with an Authorization header.
+    pub fn authorized_requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().authorized.clone()
+    }
+
+    /// The max number of the requests which have been received and not
+    /// responded yet at the same time.
+    pub fn peak_requests(&self) -> usize {
+        self.state.lock().unwrap().peak_in_flight
+    }
+}
+
+/// Get the "sha256:<hex>" digest of the data.
+pub fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+async fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request).to_string();
+    let mut lines = request.lines();
+    let mut parts = lines.next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    let headers: Vec<(&str, &str)> = lines.filter_map(|line| line.split_once(':')).collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+    let range_start = header("range")
+        .and_then(|value| value.strip_prefix("bytes="))
+        .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok());
+    let authorized = header("authorization").is_some();
+
+    let (response, fault) = {
+        let mut state = state.lock().unwrap();
+        match range_start {
+            Some(start) => state
+                .requests
+                .push(format!("{} {} bytes={}-", method, path, start)),
+            None => state.requests.push(format!("{} {}", method, path)),
+        }
+        if authorized {
+            state.authorized.push(format!("{} {}", method, path));
+        }
+        state.in_flight += 1;
+        state.peak_in_flight = state.peak_in_flight.max(state.in_flight);
+        let fault = state.faults.get_mut(&path).and_then(|f| f.pop_front());
+        (route(&state, &path), fault)
+    };
+
+    let (mut status, headers, mut body) = match (response, &fault) {
+        (_, Some(Fault::Status(code))) => (format!("{} Fault", code), Vec::new(), Vec::new()),
+        (Some((headers, body)), _) => ("200 OK".to_string(), headers, body),
+        (None, _) => (
+            "404 Not Found".to_string(),
+            vec![("Content-Type", "application/json".to_string())],
+            not_found(&path),
+        ),
+    };
+
+    let mut range_header = String::new();
+    if let (Some(start), true) = (range_start, status.starts_with("200")) {
+        let total = body.len();
+        if start >= total {
+            status = "416 Range Not Satisfiable".to_string();
+            body.clear();
+        } else {
+            status = "206 Partial Content".to_string();
+            body.drain(..start);
+            range_header.push_str(&format!(
+                "Content-Range: bytes {}-{}/{}\r\n",
+                start,
+                total - 1,
+                total
+            ));
+        }
+    }
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n{}",
+        status,
+        body.len(),
+        range_header
+    );
+    for (name, value) in headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    // The request is done once the client may get the response.
+    if let Some(Fault::Delay(delay)) = fault {
+        tokio::time::sleep(delay).await;
+    }
+    state.lock().unwrap().in_flight -= 1;
+
+    stream.write_all(head.as_bytes()).await?;
+    if method != "HEAD" {
+        if let Some(Fault::Truncate(len)) = fault {
+            body.truncate(len);
+        }
+        stream.write_all(&body).await?;
+    }
+    stream.shutdown().await
+}
+
+type Response = (Vec<(&'static str, String)>, Vec<u8>);
+
+// The OCI error response body of a missing manifest, blob or repository.
+fn not_found(path: &str) -> Vec<u8> {
+    let (code, message) = if path.contains("/manifests/") {
+        ("MANIFEST_UNKNOWN", "manifest unknown")
+    } else if path.contains("/blobs/") {
+        ("BLOB_UNKNOWN", "blob unknown to registry")
+    } else {
+        ("NAME_UNKNOWN", "repository name not known to registry")
+    };
+
+    serde_json::json!({"errors": [{"code": code, "message": message}]})
+        .to_string()
+        .into_bytes()
+}
+
+fn route(state: &State, path: &str) -> Option<Response> {
+    let path = path.strip_prefix("/v2/")?;
+    if path.is_empty() {
+        return Some((Vec::new(), Vec::new()));
+    }
+
+    if let Some((repository, reference)) = path.rsplit_once("/manifests/") {
+        let (media_type, digest, data) = state
+            .manifests
+            .get(&format!("{}/{}", repository, reference))?;
+        return Some((
+            vec![
+                ("Content-Type", media_type.clone()),
+                ("Docker-Content-Digest", digest.clone()),
+            ],
+            data.clone(),
+        ));
+    }
+
+    if let Some((repository, digest)) = path.rsplit_once("/blobs/") {
+        let data = state.blobs.get(&format!("{}/{}", repository, digest))?;
+        return Some((
+            vec![
+                ("Content-Type", "application/octet-stream".to_string()),
+                ("Docker-Content-Digest", digest.to_string()),
+            ],
+            data.clone(),
+        ));
+    }
+
+    None
+}
@@ -11,7 +11,7 @@ use std::str::FromStr;
 use std::string::ToString;
 
 // Supported digest algorithm types
-#[derive(EnumString, Display, Debug, PartialEq, Eq, EnumProperty)]
+#[derive(EnumString, Display, Debug, Clone, Copy, PartialEq, Eq, Hash, EnumProperty)]
 pub enum Algorithm {
     #[strum(serialize = "sha256", props(Length = "64"))]
     Sha256,
@@ -0,0 +1,356 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_distribution::{secrets::RegistryAuth, Reference};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The registry name of Docker Hub which all its aliases resolve to.
+const DOCKER_HUB_REGISTRY: &str = "docker.io";
+
+/// The server URL Docker uses to store the Docker Hub credentials.
+const DOCKER_HUB_SERVER_URL: &str = "https://index.docker.io/v1/";
+
+/// The hostnames that Docker Hub credentials are stored under.
+const DOCKER_HUB_ALIASES: &[&str] = &["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+/// The prefix of the credential helper executables.
+const CREDENTIAL_HELPER_PREFIX: &str = "docker-credential-";
+
+/// The username credential helpers return along with an identity token.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// The content of a Docker style `config.json` auth file.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DockerConfig {
+    /// The credentials of each registry.
+    #[serde(default)]
+    pub auths: HashMap<String, DockerAuthConfig>,
+
+    /// The credential helper of each registry.
+    #[serde(default, rename = "credHelpers")]
+    pub cred_helpers: HashMap<String, String>,
+
+    /// The credential helper for the registries without a credHelpers entry.
+    #[serde(default, rename = "credsStore")]
+    pub creds_store: Option<String>,
+
+    /// The search path of the credential helpers, like `PATH`, which is
+    /// used if it is not set.
+    #[serde(skip)]
+    pub helper_path: Option<OsString>,
+}
+
+/// The credentials of a registry in the `auths` map.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DockerAuthConfig {
+    /// The base64 encoded "username:password".
+    #[serde(default)]
+    pub auth: Option<String>,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// The token used to get a registry access token, which is not
+    /// supported.
+    #[serde(default)]
+    pub identitytoken: Option<String>,
+}
+
+/// The output of the `get` command of a credential helper.
+#[derive(Debug, Deserialize)]
+struct HelperCredential {
+    #[serde(rename = "Username")]
+    username: String,
+
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+impl TryFrom<&Path> for DockerConfig {
+    /// load `DockerConfig` from a local file
+    type Error = anyhow::Error;
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        let file =
+            File::open(path).map_err(|e| anyhow!("failed to open auth file {:?}: {}", path, e))?;
+        serde_json::from_reader::<File, DockerConfig>(file)
+            .map_err(|e| anyhow!("failed to parse auth file {:?}: {}", path, e))
+    }
+}
+
+impl DockerConfig {
+    /// Get the credential of the registry of the image reference. The
+    /// credential helpers take precedence over the `auths` map, just like
+    /// the docker cli. It falls back to anonymous access when no
+    /// credential is found.
+    pub fn credential(&self, reference: &Reference) -> Result<RegistryAuth> {
+        let registry = normalize_registry(reference.registry());
+
+        let helper = self
+            .cred_helpers
+            .iter()
+            .find(|(host, _)| normalize_registry(host) == registry)
+            .map(|(_, helper)| helper)
+            .or_else(|| self.creds_store.as_ref());
+        if let Some(helper) = helper {
+            return helper_credential(helper, &registry, self.helper_path.as_deref());
+        }
+
+        match self
+            .auths
+            .iter()
+            .find(|(host, _)| normalize_registry(host) == registry)
+        {
+            Some((host, auth)) => auth.credential(host),
+            None => Ok(RegistryAuth::Anonymous),
+        }
+    }
+}
+
+impl DockerAuthConfig {
+    fn credential(&self, host: &str) -> Result<RegistryAuth> {
+        // An identity token is an OAuth2 refresh token to exchange for an
+        // access token, which the registry clients can not do.
+        if let Some(token) = &self.identitytoken {
+            if !token.is_empty() {
+                return Err(anyhow!(
+                    "identity token of {} in auth file is not supported",
+                    host
+                ));
+            }
+        }
+
+        if let Some(auth) = &self.auth {
+            if !auth.is_empty() {
+                let decoded = base64::decode(auth.trim())
+                    .map_err(|e| anyhow!("invalid auth of {} in auth file: {}", host, e))?;
+                let decoded = String::from_utf8(decoded)
+                    .map_err(|e| anyhow!("invalid auth of {} in auth file: {}", host, e))?;
+                let (username, password) = decoded
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("invalid auth of {} in auth file", host))?;
+                return Ok(RegistryAuth::Basic(
+                    username.to_string(),
+                    password.to_string(),
+                ));
+            }
+        }
+
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => {
+                Ok(RegistryAuth::Basic(username.clone(), password.clone()))
+            }
+            _ => Ok(RegistryAuth::Anonymous),
+        }
+    }
+}
+
+/// Normalize a registry host or a server URL in the auth file, e.g.
+/// "https://index.docker.io/v1/" to "docker.io".
+fn normalize_registry(host: &str) -> String {
+    let host = host
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let host = host.split('/').next().unwrap_or_default();
+
+    if DOCKER_HUB_ALIASES.contains(&host) {
+        return DOCKER_HUB_REGISTRY.to_string();
+    }
+
+    host.to_string()
+}
+
+/// Get the credential of a registry from the credential helper by
+/// running `docker-credential-<helper> get`, which reads the server URL
+/// from stdin and writes the credential as JSON to stdout. The helper is
+/// looked up in `helper_path` if it is given.
+fn helper_credential(
+    helper: &str,
+    registry: &str,
+    helper_path: Option<&OsStr>,
+) -> Result<RegistryAuth> {
+    let server_url = match registry {
+        DOCKER_HUB_REGISTRY => DOCKER_HUB_SERVER_URL,
+        _ => registry,
+    };
+    let program = format!("{}{}", CREDENTIAL_HELPER_PREFIX, helper);
+
+    let mut command = Command::new(&program);
+    if let Some(path) = helper_path {
+        command.env("PATH", path);
+    }
+    let mut child = command
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("failed to run credential helper {}: {}", program, e))?;
+
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("failed to open stdin of credential helper {}", program))?
+        .write_all(server_url.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // The helpers report a missing credential on stdout.
+        if stdout.contains("credentials not found") {
+            return Ok(RegistryAuth::Anonymous);
+        }
+
+        return Err(anyhow!(
+            "credential helper {} failed for {}: {}{}",
+            program,
+            server_url,
+            stdout.trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let credential: HelperCredential = serde_json::from_slice(&output.stdout)
+        .map_err(|e| anyhow!("invalid output of credential helper {}: {}", program, e))?;
+    if credential.username == IDENTITY_TOKEN_USERNAME {
+        return Err(anyhow!(
+            "identity token of {} from credential helper {} is not supported",
+            server_url,
+            program
+        ));
+    }
+
+    Ok(RegistryAuth::Basic(credential.username, credential.secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn assert_basic(auth: RegistryAuth, username: &str, password: &str) {
+        match auth {
+            RegistryAuth::Basic(u, p) => {
+                assert_eq!(u, username);
+                assert_eq!(p, password);
+            }
+            _ => panic!("expect basic auth, got anonymous"),
+        }
+    }
+
+    #[test]
+    fn test_normalize_registry() {
+        let tests = [
+            ("docker.io", "docker.io"),
+            ("index.docker.io", "docker.io"),
+            ("https://index.docker.io/v1/", "docker.io"),
+            ("registry-1.docker.io", "docker.io"),
+            ("quay.io", "quay.io"),
+            ("http://localhost:5000/v2/", "localhost:5000"),
+        ];
+
+        for (host, registry) in tests.iter() {
+            assert_eq!(normalize_registry(host), *registry);
+        }
+    }
+
+    #[test]
+    fn test_docker_config_auths() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let auth_file = tempdir.path().join("config.json");
+        fs::write(
+            &auth_file,
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": {"auth": "Zm9vOmJhcg=="},
+                    "quay.io": {"username": "baz", "password": "qux"},
+                    "gcr.io": {"auth": "", "identitytoken": "token"},
+                    "bad.io": {"auth": "Zm9v"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let config = DockerConfig::try_from(auth_file.as_path()).unwrap();
+
+        let reference = Reference::try_from("busybox:latest").unwrap();
+        assert_basic(config.credential(&reference).unwrap(), "foo", "bar");
+
+        let reference = Reference::try_from("quay.io/foo/bar:latest").unwrap();
+        assert_basic(config.credential(&reference).unwrap(), "baz", "qux");
+
+        // The identity token is never sent as a password.
+        let reference = Reference::try_from("gcr.io/foo/bar:latest").unwrap();
+        assert!(config.credential(&reference).is_err());
+
+        let reference = Reference::try_from("bad.io/foo/bar:latest").unwrap();
+        assert!(config.credential(&reference).is_err());
+
+        let reference = Reference::try_from("example.com/foo/bar:latest").unwrap();
+        assert!(matches!(
+            config.credential(&reference).unwrap(),
+            RegistryAuth::Anonymous
+        ));
+    }
+
+    #[test]
+    fn test_docker_config_cred_helpers() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        // A fake credential helper which only knows the credential of
+        // example.com and an identity token of gcr.io.
+        let helper = tempdir.path().join("docker-credential-test");
+        fs::write(
+            &helper,
+            r#"#!/bin/sh
+read server
+if [ "$1" = "get" ] && [ "$server" = "example.com" ]; then
+    echo '{"ServerURL": "example.com", "Username": "foo", "Secret": "bar"}'
+elif [ "$1" = "get" ] && [ "$server" = "gcr.io" ]; then
+    echo '{"ServerURL": "gcr.io", "Username": "<token>", "Secret": "token"}'
+else
+    echo "credentials not found in native keychain"
+    exit 1
+fi
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&helper, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut config: DockerConfig = serde_json::from_str(
+            r#"{
+                "auths": {"example.com": {"auth": "YmF6OnF1eA=="}},
+                "credHelpers": {"example.com": "test", "quay.io": "test", "gcr.io": "test"}
+            }"#,
+        )
+        .unwrap();
+        config.helper_path = Some(tempdir.path().into());
+
+        let reference = Reference::try_from("example.com/foo/bar:latest").unwrap();
+        assert_basic(config.credential(&reference).unwrap(), "foo", "bar");
+
+        let reference = Reference::try_from("quay.io/foo/bar:latest").unwrap();
+        assert!(matches!(
+            config.credential(&reference).unwrap(),
+            RegistryAuth::Anonymous
+        ));
+
+        let reference = Reference::try_from("gcr.io/foo/bar:latest").unwrap();
+        assert!(config.credential(&reference).is_err());
+
+        let config: DockerConfig = serde_json::from_str(r#"{"credsStore": "not-exist"}"#).unwrap();
+        assert!(config.credential(&reference).is_err());
+    }
+}
@@ -3,20 +3,49 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use oci_distribution::Reference;
+use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
+use std::fmt;
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use crate::snapshots::SnapshotType;
+use crate::error::Error;
+use crate::event::PullEventSender;
+use crate::snapshots::{MountOptions, SnapshotType};
+use crate::unpack::{IdMappings, UnpackLimits};
 use crate::CC_IMAGE_WORK_DIR;
 
 const DEFAULT_WORK_DIR: &str = "/var/lib/image-rs/";
+const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 3;
+const DEFAULT_MAX_CONCURRENT_UNPACKS: usize = 2;
 
-/// `image-rs` configuration information.
+/// Environment variable overriding `default_snapshot`, like `overlay`.
+pub const CC_IMAGE_DEFAULT_SNAPSHOT: &str = "CC_IMAGE_DEFAULT_SNAPSHOT";
+
+/// Environment variable overriding `security_validate`, `true` or `false`.
+pub const CC_IMAGE_SECURITY_VALIDATE: &str = "CC_IMAGE_SECURITY_VALIDATE";
+
+/// Environment variable overriding `auth_file`.
+pub const CC_IMAGE_AUTH_FILE: &str = "CC_IMAGE_AUTH_FILE";
+
+/// Environment variable overriding `platform`, like `linux/arm64/v8`.
+pub const CC_IMAGE_PLATFORM: &str = "CC_IMAGE_PLATFORM";
+
+/// Environment variable overriding `max_concurrent_downloads`.
+pub const CC_IMAGE_MAX_CONCURRENT_DOWNLOADS: &str = "CC_IMAGE_MAX_CONCURRENT_DOWNLOADS";
+
+/// Environment variable overriding `max_concurrent_unpacks`.
+pub const CC_IMAGE_MAX_CONCURRENT_UNPACKS: &str = "CC_IMAGE_MAX_CONCURRENT_UNPACKS";
+
+/// `image-rs` configuration information. The fields missing in a
+/// configuration file take their default values.
 #[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
 pub struct ImageConfig {
-    /// The location for `image-rs` to store data.
+    /// The location for `image-rs` to store data. A work_dir is owned by
+    /// one `ImageClient` at a time and must not be shared between processes.
     pub work_dir: PathBuf,
 
     /// The default snapshot for `image-rs` to use.
@@ -24,6 +53,57 @@ pub struct ImageConfig {
 
     /// Security validation control
     pub security_validate: bool,
+
+    /// The platform to pull from a multi-platform image index,
+    /// defaults to the platform of the host.
+    pub platform: Platform,
+
+    /// The Docker style `config.json` file to get the registry
+    /// credentials from, when no auth info is given to pull an image.
+    pub auth_file: Option<PathBuf>,
+
+    /// The mirrors of registries, which are tried in order before the
+    /// registry in the image reference.
+    pub registries: Vec<RegistryConfig>,
+
+    /// The retry policy of the manifest and blob fetches.
+    pub retry: RetryConfig,
+
+    /// The max number of layer blobs to download at the same time, in
+    /// each image pull.
+    pub max_concurrent_downloads: usize,
+
+    /// The max number of layers to decrypt, decompress and unpack at
+    /// the same time, in each image pull.
+    pub max_concurrent_unpacks: usize,
+
+    /// The dir to download the layer blobs of registry images to, which
+    /// are verified as a whole before they are unpacked, `<work_dir>/downloads`
+    /// by default. It has to hold the compressed blobs of up to
+    /// `max_concurrent_downloads + max_concurrent_unpacks` layers, so it
+    /// is better put on a disk when the `work_dir` is in memory.
+    pub download_dir: Option<PathBuf>,
+
+    /// The uid and gid mappings to shift the file ownership of the
+    /// layers with, for rootless and user namespaced runtimes.
+    pub id_mappings: IdMappings,
+
+    /// The SELinux label of the container rootfs, like
+    /// `system_u:object_r:container_file_t:s0:c1,c2`. It is the `context=`
+    /// mount option of the overlay snapshot, and is set on every file of
+    /// the native snapshot.
+    pub selinux_label: Option<String>,
+
+    /// The limits of the layer tarballs to unpack.
+    pub unpack_limits: UnpackLimits,
+
+    /// The rootfs mount options of the bundles, which only the overlay
+    /// snapshot supports.
+    pub mount_options: MountOptions,
+
+    /// The sender to report the progress events of image pulls to.
+    #[serde(skip)]
+    pub event_sender: Option<PullEventSender>,
 }
 
 impl Default for ImageConfig {
@@ -37,7 +117,344 @@ impl Default for ImageConfig {
             work_dir,
             default_snapshot: SnapshotType::Overlay,
             security_validate: false,
+            platform: Platform::default(),
+            auth_file: None,
+            registries: Vec::new(),
+            retry: RetryConfig::default(),
+            max_concurrent_downloads: DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+            max_concurrent_unpacks: DEFAULT_MAX_CONCURRENT_UNPACKS,
+            download_dir: None,
+            id_mappings: IdMappings::default(),
+            selinux_label: None,
+            unpack_limits: UnpackLimits::default(),
+            mount_options: MountOptions::default(),
+            event_sender: None,
+        }
+    }
+}
+
+/// The platform of an image, with the values defined by the OCI image
+/// index specification, like `linux/arm64/v8`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Platform {
+    /// The operating system, like `linux`.
+    pub os: String,
+
+    /// The CPU architecture, like `amd64` or `arm64`.
+    pub architecture: String,
+
+    /// The variant of the CPU architecture, like `v8` for `arm64`.
+    /// Any variant is matched if it is not set.
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+impl Default for Platform {
+    // Construct the platform of the host.
+    fn default() -> Platform {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            "powerpc64" => "ppc64",
+            arch => arch,
+        };
+
+        let variant = match architecture {
+            "arm64" => Some("v8".to_string()),
+            "arm" => Some("v7".to_string()),
+            _ => None,
+        };
+
+        Platform {
+            os: std::env::consts::OS.to_string(),
+            architecture: architecture.to_string(),
+            variant,
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    // Parse a platform like `linux/amd64` or `linux/arm64/v8`.
+    fn from_str(platform: &str) -> Result<Self> {
+        let parts: Vec<&str> = platform.split('/').collect();
+        match parts[..] {
+            [os, architecture] | [os, architecture, _]
+                if !os.is_empty() && !architecture.is_empty() =>
+            {
+                Ok(Platform {
+                    os: os.to_string(),
+                    architecture: architecture.to_string(),
+                    variant: parts.get(2).map(|v| v.to_string()),
+                })
+            }
+            _ => Err(anyhow!("invalid platform {:?}", platform)),
+        }
+    }
+}
+
+impl Platform {
+    /// Check whether an image of the given os, architecture and variant
+    /// can be used for this platform.
+    pub fn matches(&self, os: &str, architecture: &str, variant: Option<&str>) -> bool {
+        if self.os != os || self.architecture != architecture {
+            return false;
+        }
+
+        match &self.variant {
+            Some(expected) => {
+                // `arm64` images without variant are `v8` images.
+                let variant = match variant {
+                    None if architecture == "arm64" => Some("v8"),
+                    v => v,
+                };
+                variant == Some(expected.as_str())
+            }
+            None => true,
+        }
+    }
+}
+
+/// The retry policy of the registry fetches. The delay before a retry
+/// starts from `initial_backoff_ms`, and doubles after each retry until
+/// `max_backoff_ms`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// The max number of retries after the first failed attempt.
+    pub max_retries: u32,
+
+    /// The delay in milliseconds before the first retry.
+    pub initial_backoff_ms: u64,
+
+    /// The max delay in milliseconds between two attempts.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_retries: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+/// The mirror configuration of the images under a reference prefix,
+/// similar to a `[[registry]]` table of containers-registries.conf.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct RegistryConfig {
+    /// The reference prefix the mirrors apply to, like `docker.io` or
+    /// `docker.io/library`.
+    pub prefix: String,
+
+    /// The mirrors to try in order before the upstream registry.
+    #[serde(default)]
+    pub mirrors: Vec<MirrorConfig>,
+}
+
+/// A mirror of a registry.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct MirrorConfig {
+    /// The location replacing the reference prefix, like
+    /// `mirror.example.com:5000/library`.
+    pub location: String,
+
+    /// Whether to talk to the mirror with plain HTTP.
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+/// An endpoint to pull an image from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    /// The image reference on the endpoint.
+    pub reference: Reference,
+
+    /// Whether to talk to the endpoint with plain HTTP.
+    pub insecure: bool,
+}
+
+impl ImageConfig {
+    /// Override the config with the `CC_IMAGE_*` environment variables
+    /// which are set, like `CC_IMAGE_WORK_DIR` and `CC_IMAGE_PLATFORM`.
+    pub fn with_env_overrides(self) -> crate::Result<ImageConfig> {
+        self.with_overrides(|name| std::env::var(name).ok())
+    }
+
+    // Override the config with the variables found by lookup.
+    fn with_overrides<F>(mut self, lookup: F) -> crate::Result<ImageConfig>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        fn parse<T: FromStr>(name: &str, value: &str) -> crate::Result<T> {
+            value
+                .parse()
+                .map_err(|_| Error::InvalidConfig(format!("invalid {} {:?}", name, value)))
+        }
+
+        if let Some(work_dir) = lookup(CC_IMAGE_WORK_DIR) {
+            self.work_dir = PathBuf::from(work_dir);
+        }
+        if let Some(snapshot) = lookup(CC_IMAGE_DEFAULT_SNAPSHOT) {
+            self.default_snapshot = serde_json::from_value(serde_json::Value::String(snapshot))
+                .map_err(|e| {
+                    Error::InvalidConfig(format!("invalid {}: {}", CC_IMAGE_DEFAULT_SNAPSHOT, e))
+                })?;
+        }
+        if let Some(value) = lookup(CC_IMAGE_SECURITY_VALIDATE) {
+            self.security_validate = parse(CC_IMAGE_SECURITY_VALIDATE, &value)?;
+        }
+        if let Some(auth_file) = lookup(CC_IMAGE_AUTH_FILE) {
+            self.auth_file = Some(PathBuf::from(auth_file));
+        }
+        if let Some(platform) = lookup(CC_IMAGE_PLATFORM) {
+            self.platform = parse(CC_IMAGE_PLATFORM, &platform)?;
+        }
+        if let Some(value) = lookup(CC_IMAGE_MAX_CONCURRENT_DOWNLOADS) {
+            self.max_concurrent_downloads = parse(CC_IMAGE_MAX_CONCURRENT_DOWNLOADS, &value)?;
+        }
+        if let Some(value) = lookup(CC_IMAGE_MAX_CONCURRENT_UNPACKS) {
+            self.max_concurrent_unpacks = parse(CC_IMAGE_MAX_CONCURRENT_UNPACKS, &value)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Validate the config, so that a bad config is rejected before any
+    /// image is pulled.
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| -> crate::Result<()> { Err(Error::InvalidConfig(msg)) };
+
+        if !self.work_dir.is_absolute() {
+            return invalid(format!("work_dir {:?} is not absolute", self.work_dir));
+        }
+
+        if let Some(download_dir) = &self.download_dir {
+            if !download_dir.is_absolute() {
+                return invalid(format!("download_dir {:?} is not absolute", download_dir));
+            }
+        }
+
+        if !self.default_snapshot.is_enabled() {
+            return invalid(format!(
+                "default_snapshot {} is not enabled by the cargo features",
+                self.default_snapshot
+            ));
+        }
+
+        if self.platform.os.is_empty() || self.platform.architecture.is_empty() {
+            return invalid(format!("invalid platform {:?}", self.platform));
+        }
+
+        if self.max_concurrent_downloads == 0 || self.max_concurrent_unpacks == 0 {
+            return invalid(
+                "max_concurrent_downloads and max_concurrent_unpacks must be greater than 0"
+                    .to_string(),
+            );
+        }
+
+        if let Err(e) = self.id_mappings.validate() {
+            return invalid(e.to_string());
+        }
+
+        if let Err(e) = self.unpack_limits.validate() {
+            return invalid(e.to_string());
+        }
+
+        // The label is quoted in the mount options.
+        if let Some(label) = &self.selinux_label {
+            if label.is_empty() || label.contains('"') {
+                return invalid(format!("invalid selinux_label {:?}", label));
+            }
+        }
+
+        if self.default_snapshot != SnapshotType::Overlay
+            && self.mount_options != MountOptions::default()
+        {
+            return invalid(format!(
+                "mount_options are not supported by the {} snapshot",
+                self.default_snapshot
+            ));
+        }
+
+        for registry in self.registries.iter() {
+            if registry.prefix.is_empty() {
+                return invalid("registry prefix is empty".to_string());
+            }
+            for mirror in registry.mirrors.iter() {
+                let mirrored = format!("{}/image", mirror.location);
+                if Reference::try_from(mirrored.as_str()).is_err() {
+                    return invalid(format!("invalid mirror location {:?}", mirror.location));
+                }
+            }
         }
+
+        Ok(())
+    }
+
+    /// Get the dir to download the layer blobs to.
+    pub fn download_dir(&self) -> PathBuf {
+        self.download_dir
+            .clone()
+            .unwrap_or_else(|| self.work_dir.join("downloads"))
+    }
+
+    /// Get the endpoints to pull the image reference from: the mirrors of
+    /// the longest matching registry prefix in order, followed by the
+    /// upstream registry in the reference.
+    pub fn endpoints(&self, reference: &Reference) -> Result<Vec<Endpoint>> {
+        let whole = reference.whole();
+        let registry = self
+            .registries
+            .iter()
+            .filter(|r| prefix_matches(&whole, &r.prefix))
+            .max_by_key(|r| r.prefix.len());
+
+        let mut endpoints = Vec::new();
+        if let Some(registry) = registry {
+            for mirror in registry.mirrors.iter() {
+                let mirrored = format!("{}{}", mirror.location, &whole[registry.prefix.len()..]);
+                let reference = Reference::try_from(mirrored.as_str())
+                    .map_err(|e| anyhow!("invalid mirror reference {}: {}", mirrored, e))?;
+                endpoints.push(Endpoint {
+                    reference,
+                    insecure: mirror.insecure,
+                });
+            }
+        }
+
+        endpoints.push(Endpoint {
+            reference: reference.clone(),
+            insecure: false,
+        });
+
+        Ok(endpoints)
+    }
+}
+
+// A prefix only matches whole components of a reference, so that
+// `docker.io/library` matches `docker.io/library/busybox:latest`
+// but not `docker.io/library-foo/bar:latest`.
+fn prefix_matches(reference: &str, prefix: &str) -> bool {
+    match reference.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with(&['/', ':', '@'][..]),
+        None => false,
     }
 }
 
@@ -60,6 +477,7 @@ impl TryFrom<&Path> for ImageConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::unpack::IdMapping;
     use std::fs::File;
     use std::io::prelude::*;
     use tempfile;
@@ -100,5 +518,296 @@ mod tests {
 
         assert_eq!(config.work_dir, work_dir);
         assert_eq!(config.default_snapshot, SnapshotType::Overlay);
+        assert_eq!(config.platform, Platform::default());
+        assert_eq!(
+            config.max_concurrent_downloads,
+            DEFAULT_MAX_CONCURRENT_DOWNLOADS
+        );
+        assert_eq!(
+            config.max_concurrent_unpacks,
+            DEFAULT_MAX_CONCURRENT_UNPACKS
+        );
+    }
+
+    #[test]
+    fn test_partial_config() {
+        let config: ImageConfig = serde_json::from_str(
+            r#"{"security_validate": true, "unpack_limits": {"max_depth": 64}}"#,
+        )
+        .unwrap();
+        assert!(config.security_validate);
+        assert_eq!(config.unpack_limits.max_depth, 64);
+        assert_eq!(
+            config.unpack_limits.max_entries,
+            UnpackLimits::default().max_entries
+        );
+        assert_eq!(config.default_snapshot, SnapshotType::Overlay);
+        assert_eq!(config.retry, RetryConfig::default());
+        assert_eq!(
+            config.max_concurrent_downloads,
+            DEFAULT_MAX_CONCURRENT_DOWNLOADS
+        );
+        assert_eq!(config.download_dir(), config.work_dir.join("downloads"));
+        assert!(config.validate().is_ok());
+
+        let config: ImageConfig =
+            serde_json::from_str(r#"{"download_dir": "/var/tmp/image-rs"}"#).unwrap();
+        assert_eq!(config.download_dir(), PathBuf::from("/var/tmp/image-rs"));
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let vars: std::collections::HashMap<&str, &str> = [
+            (CC_IMAGE_WORK_DIR, "/run/image-rs"),
+            (CC_IMAGE_DEFAULT_SNAPSHOT, "occlumunionfs"),
+            (CC_IMAGE_SECURITY_VALIDATE, "true"),
+            (CC_IMAGE_AUTH_FILE, "/run/auth.json"),
+            (CC_IMAGE_PLATFORM, "linux/arm64/v8"),
+            (CC_IMAGE_MAX_CONCURRENT_DOWNLOADS, "8"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+
+        let config = ImageConfig::default()
+            .with_overrides(|name| vars.get(name).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.work_dir, PathBuf::from("/run/image-rs"));
+        assert_eq!(config.default_snapshot, SnapshotType::OcclumUnionfs);
+        assert!(config.security_validate);
+        assert_eq!(config.auth_file, Some(PathBuf::from("/run/auth.json")));
+        assert_eq!(config.platform.to_string(), "linux/arm64/v8");
+        assert_eq!(config.max_concurrent_downloads, 8);
+        assert_eq!(
+            config.max_concurrent_unpacks,
+            DEFAULT_MAX_CONCURRENT_UNPACKS
+        );
+
+        for (name, value) in [
+            (CC_IMAGE_DEFAULT_SNAPSHOT, "foo"),
+            (CC_IMAGE_SECURITY_VALIDATE, "yes"),
+            (CC_IMAGE_PLATFORM, "linux"),
+            (CC_IMAGE_MAX_CONCURRENT_UNPACKS, "-1"),
+        ] {
+            let result =
+                ImageConfig::default().with_overrides(|n| (n == name).then(|| value.to_string()));
+            assert!(matches!(result, Err(Error::InvalidConfig(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config = ImageConfig {
+            work_dir: PathBuf::from("/var/lib/image-rs"),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let invalid_configs = [
+            ImageConfig {
+                work_dir: PathBuf::from("image-rs"),
+                ..config.clone()
+            },
+            ImageConfig {
+                max_concurrent_downloads: 0,
+                ..config.clone()
+            },
+            ImageConfig {
+                download_dir: Some(PathBuf::from("downloads")),
+                ..config.clone()
+            },
+            ImageConfig {
+                platform: Platform {
+                    os: "linux".to_string(),
+                    architecture: String::new(),
+                    variant: None,
+                },
+                ..config.clone()
+            },
+            ImageConfig {
+                registries: vec![RegistryConfig {
+                    prefix: "docker.io".to_string(),
+                    mirrors: vec![MirrorConfig {
+                        location: "Bad Mirror".to_string(),
+                        insecure: false,
+                    }],
+                }],
+                ..config.clone()
+            },
+            ImageConfig {
+                unpack_limits: UnpackLimits {
+                    max_depth: 0,
+                    ..Default::default()
+                },
+                ..config.clone()
+            },
+            ImageConfig {
+                selinux_label: Some("system_u:\"".to_string()),
+                ..config.clone()
+            },
+            ImageConfig {
+                default_snapshot: SnapshotType::Native,
+                mount_options: MountOptions {
+                    read_only: true,
+                    ..Default::default()
+                },
+                ..config.clone()
+            },
+            ImageConfig {
+                id_mappings: IdMappings {
+                    uid_mappings: vec![IdMapping {
+                        container_id: 0,
+                        host_id: 100000,
+                        size: 0,
+                    }],
+                    ..Default::default()
+                },
+                ..config.clone()
+            },
+        ];
+        for invalid in invalid_configs.iter() {
+            assert!(
+                matches!(invalid.validate(), Err(Error::InvalidConfig(_))),
+                "{:?}",
+                invalid
+            );
+        }
+
+        // A snapshot which is not compiled in is rejected.
+        let occlum = ImageConfig {
+            default_snapshot: SnapshotType::OcclumUnionfs,
+            ..config
+        };
+        assert_eq!(occlum.validate().is_ok(), cfg!(feature = "occlum_feature"));
+    }
+
+    #[test]
+    fn test_platform_from_str() {
+        let platform: Platform = "linux/arm64/v8".parse().unwrap();
+        assert_eq!(platform.architecture, "arm64");
+        assert_eq!(platform.variant, Some("v8".to_string()));
+
+        let platform: Platform = "linux/amd64".parse().unwrap();
+        assert_eq!(platform.variant, None);
+
+        for invalid in ["", "linux", "linux/", "/amd64", "linux/arm/v7/foo"] {
+            assert!(invalid.parse::<Platform>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_platform() {
+        let platform = Platform::default();
+        assert_eq!(platform.os, std::env::consts::OS);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(platform.to_string(), "linux/amd64");
+
+        let arm64 = Platform {
+            os: "linux".to_string(),
+            architecture: "arm64".to_string(),
+            variant: Some("v8".to_string()),
+        };
+        assert_eq!(arm64.to_string(), "linux/arm64/v8");
+        assert!(arm64.matches("linux", "arm64", Some("v8")));
+        assert!(arm64.matches("linux", "arm64", None));
+        assert!(!arm64.matches("linux", "arm64", Some("v9")));
+        assert!(!arm64.matches("linux", "amd64", None));
+        assert!(!arm64.matches("windows", "arm64", Some("v8")));
+
+        let arm = Platform {
+            os: "linux".to_string(),
+            architecture: "arm".to_string(),
+            variant: None,
+        };
+        assert!(arm.matches("linux", "arm", Some("v6")));
+        assert!(arm.matches("linux", "arm", None));
+
+        let arm_v7 = Platform {
+            variant: Some("v7".to_string()),
+            ..arm
+        };
+        assert!(arm_v7.matches("linux", "arm", Some("v7")));
+        assert!(!arm_v7.matches("linux", "arm", Some("v6")));
+        assert!(!arm_v7.matches("linux", "arm", None));
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let data = r#"{
+            "work_dir": "/var/lib/image-rs/",
+            "default_snapshot": "overlay",
+            "security_validate": false,
+            "registries": [
+                {
+                    "prefix": "docker.io",
+                    "mirrors": [{"location": "mirror.example.com"}]
+                },
+                {
+                    "prefix": "docker.io/library",
+                    "mirrors": [
+                        {"location": "localhost:5000/library", "insecure": true},
+                        {"location": "mirror.example.com/docker-library"}
+                    ]
+                }
+            ]
+        }"#;
+        let config: ImageConfig = serde_json::from_str(data).unwrap();
+
+        let endpoints = |image: &str| {
+            let reference = Reference::try_from(image).unwrap();
+            config
+                .endpoints(&reference)
+                .unwrap()
+                .iter()
+                .map(|e| (e.reference.whole(), e.insecure))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            endpoints("busybox:latest"),
+            vec![
+                ("localhost:5000/library/busybox:latest".to_string(), true),
+                (
+                    "mirror.example.com/docker-library/busybox:latest".to_string(),
+                    false
+                ),
+                ("docker.io/library/busybox:latest".to_string(), false),
+            ]
+        );
+        assert_eq!(
+            endpoints("docker.io/library-foo/bar:v1"),
+            vec![
+                ("mirror.example.com/library-foo/bar:v1".to_string(), false),
+                ("docker.io/library-foo/bar:v1".to_string(), false),
+            ]
+        );
+        assert_eq!(
+            endpoints("quay.io/foo/bar:v1"),
+            vec![("quay.io/foo/bar:v1".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn test_platform_from_file() {
+        let data = r#"{
+            "work_dir": "/var/lib/image-rs/",
+            "default_snapshot": "overlay",
+            "security_validate": false,
+            "platform": {
+                "os": "linux",
+                "architecture": "s390x"
+            }
+        }"#;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config_file = tempdir.path().join("config.json");
+
+        File::create(&config_file)
+            .unwrap()
+            .write_all(data.as_bytes())
+            .unwrap();
+
+        let config = ImageConfig::try_from(config_file.as_path()).unwrap();
+        assert_eq!(config.platform.to_string(), "linux/s390x");
     }
 }
@@ -2,21 +2,26 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{anyhow, Result};
 use flate2;
 use oci_distribution::manifest;
 use oci_spec::image::MediaType;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use std::fmt;
 use std::io;
 use zstd;
 
-pub const ERR_BAD_MEDIA_TYPE: &str = "unhandled media type";
+use crate::error::{Error, Result};
+
+/// The magic number at the beginning of gzip data.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// The magic number at the beginning of zstd data.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
 
 /// Represents the layer compression algorithm type,
 /// and allows to decompress corresponding compressed data.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Compression {
     Uncompressed,
     Gzip,
@@ -49,43 +54,60 @@ impl Compression {
         R: io::Read,
         W: io::Write,
     {
-        match *self {
-            Self::Gzip => gzip_decode(input, output),
-            Self::Zstd => zstd_decode(input, output),
-            Self::Uncompressed => Err(io::Error::new(
+        if *self == Self::Uncompressed {
+            return Err(io::Error::new(
                 io::ErrorKind::Other,
                 "uncompressed input data".to_string(),
-            )),
+            ));
         }
+
+        let mut decoder = self.decoder(input)?;
+        io::copy(&mut decoder, output)?;
+        Ok(())
     }
-}
 
-// Decompress a gzip encoded data with flate2 crate.
-fn gzip_decode<R, W>(input: R, output: &mut W) -> std::io::Result<()>
-where
-    R: io::Read,
-    W: io::Write,
-{
-    let mut decoder = flate2::read::GzDecoder::new(input);
-    io::copy(&mut decoder, output)?;
-    Ok(())
-}
+    /// Wrap a `Read` of compressed data into a `Read` of the decompressed
+    /// data, so that a layer can be decompressed while it is streamed.
+    /// Uncompressed data are passed through as is.
+    pub fn decoder<'a, R>(&self, input: R) -> std::io::Result<Box<dyn io::Read + 'a>>
+    where
+        R: io::Read + 'a,
+    {
+        match *self {
+            // Decompress a gzip encoded data with flate2 crate.
+            Self::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(input))),
+            // Decompress a zstd encoded data with zstd crate.
+            Self::Zstd => Ok(Box::new(zstd::Decoder::new(input)?)),
+            Self::Uncompressed => Ok(Box::new(input)),
+        }
+    }
 
-// Decompress a zstd encoded data with zstd crate.
-fn zstd_decode<R, W>(input: R, output: &mut W) -> std::io::Result<()>
-where
-    R: io::Read,
-    W: io::Write,
-{
-    let mut decoder = zstd::Decoder::new(input)?;
-    io::copy(&mut decoder, output)?;
-    Ok(())
+    /// Detect the compression algorithm from the header of a layer, for
+    /// the layers without a media type, like those of docker archives.
+    pub fn from_magic(header: &[u8]) -> Compression {
+        if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::Uncompressed
+        }
+    }
+
+    /// Get the OCI media type of the layers in this compression.
+    pub fn layer_media_type(&self) -> MediaType {
+        match *self {
+            Compression::Uncompressed => MediaType::ImageLayer,
+            Compression::Gzip => MediaType::ImageLayerGzip,
+            Compression::Zstd => MediaType::ImageLayerZstd,
+        }
+    }
 }
 
 impl TryFrom<&str> for Compression {
-    type Error = anyhow::Error;
+    type Error = Error;
 
-    fn try_from(s: &str) -> Result<Self, Self::Error> {
+    fn try_from(s: &str) -> Result<Self> {
         let mut media_type_str = s;
 
         // convert docker layer media type to oci format
@@ -105,7 +127,7 @@ impl TryFrom<&str> for Compression {
             MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => {
                 Compression::Zstd
             }
-            _ => return Err(anyhow!("{}: {}", ERR_BAD_MEDIA_TYPE, media_type)),
+            _ => return Err(Error::UnsupportedMediaType(media_type.to_string())),
         };
 
         Ok(decoder)
@@ -116,7 +138,7 @@ impl TryFrom<&str> for Compression {
 mod tests {
     use super::*;
     use flate2::write::GzEncoder;
-    use std::io::Write;
+    use std::io::{Read, Write};
 
     use test_utils::assert_result;
 
@@ -152,6 +174,57 @@ mod tests {
         assert_eq!(data, output);
     }
 
+    #[test]
+    fn test_stream_decoder() {
+        let data: Vec<u8> = b"This is some text!".to_vec();
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gzip_bytes = encoder.finish().unwrap();
+        let zstd_bytes = zstd::encode_all(&data[..], 1).unwrap();
+
+        let tests = [
+            (Compression::Uncompressed, data.clone()),
+            (Compression::Gzip, gzip_bytes),
+            (Compression::Zstd, zstd_bytes),
+        ];
+
+        for (compression, bytes) in tests.iter() {
+            let mut output = Vec::new();
+            compression
+                .decoder(bytes.as_slice())
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(data, output, "{}", compression);
+        }
+    }
+
+    #[test]
+    fn test_from_magic() {
+        let data: Vec<u8> = b"This is some text!".to_vec();
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gzip_bytes = encoder.finish().unwrap();
+        let zstd_bytes = zstd::encode_all(&data[..], 1).unwrap();
+
+        let tests = [
+            (data, Compression::Uncompressed),
+            (Vec::new(), Compression::Uncompressed),
+            (gzip_bytes, Compression::Gzip),
+            (zstd_bytes, Compression::Zstd),
+        ];
+
+        for (bytes, compression) in tests.iter() {
+            assert_eq!(Compression::from_magic(bytes), *compression);
+            assert_eq!(
+                Compression::try_from(compression.layer_media_type().to_string().as_str()).unwrap(),
+                *compression
+            );
+        }
+    }
+
     #[test]
     fn test_zstd_decode() {
         let data: Vec<u8> = b"This is some text!".to_vec();
@@ -178,15 +251,15 @@ mod tests {
         let tests = &[
             TestData {
                 media_type_str: "",
-                result: Err(anyhow!("{}: {}", ERR_BAD_MEDIA_TYPE, "")),
+                result: Err(Error::UnsupportedMediaType("".to_string())),
             },
             TestData {
                 media_type_str: "foo",
-                result: Err(anyhow!("{}: {}", ERR_BAD_MEDIA_TYPE, "foo")),
+                result: Err(Error::UnsupportedMediaType("foo".to_string())),
             },
             TestData {
                 media_type_str: "foo/ bar",
-                result: Err(anyhow!("{}: {}", ERR_BAD_MEDIA_TYPE, "foo/ bar")),
+                result: Err(Error::UnsupportedMediaType("foo/ bar".to_string())),
             },
             TestData {
                 media_type_str: manifest::IMAGE_LAYER_MEDIA_TYPE,
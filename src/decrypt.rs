@@ -2,7 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use ocicrypt_rs::config::CryptoConfig;
 use ocicrypt_rs::encryption::decrypt_layer;
 use ocicrypt_rs::helpers::create_decrypt_config;
@@ -16,6 +16,8 @@ use oci_distribution::manifest::OciDescriptor;
 
 use std::io::Read;
 
+use crate::error::{Error, Result};
+
 #[derive(Default, Clone)]
 pub struct Decryptor {
     /// The layer original media type before encryption.
@@ -49,9 +51,14 @@ impl Decryptor {
         self.encrypted
     }
 
-    /// get_plaintext_layer descrypts encrypted_layer data and return the
-    /// plaintext_layer data. descriptor and decrypt_config are required for
-    /// layer data decryption process.
+    /// get_plaintext_layer wraps the encrypted_layer reader into a reader of
+    /// the plaintext layer data, so that the layer is decrypted while it is
+    /// streamed. descriptor and decrypt_config are required for layer data
+    /// decryption process.
+    ///
+    /// ocicrypt-rs keyprovider module will create a new runtime to talk with
+    /// attestation agent, so to avoid starting a runtime within a runtime,
+    /// this must be called from a blocking thread rather than an async task.
     ///
     /// * `decrypt_config` - decryption key info in following format:\
     ///           - \<filename> \
@@ -60,52 +67,47 @@ impl Decryptor {
     ///           - \<filename>:fd=\<filedescriptor> \
     ///           - \<filename>:\<password> \
     ///           - provider:<cmd/gprc>
-    pub async fn get_plaintext_layer(
+    pub fn get_plaintext_layer<'a, R>(
         &self,
         descriptor: &OciDescriptor,
-        encrypted_layer: Vec<u8>,
+        encrypted_layer: R,
         decrypt_config: &str,
-    ) -> Result<Vec<u8>> {
+    ) -> Result<Box<dyn Read + 'a>>
+    where
+        R: Read + 'a,
+    {
         if !self.is_encrypted() {
-            return Err(anyhow!("unencrypted media type: {}", self.media_type));
+            return Err(Error::UnsupportedMediaType(descriptor.media_type.clone()));
         }
 
         if decrypt_config.is_empty() {
-            return Err(anyhow!("decrypt_config is empty"));
+            return Err(Error::DecryptionKeyUnavailable(descriptor.digest.clone()));
         }
 
-        let cc = create_decrypt_config(vec![decrypt_config.to_string()], vec![])?;
-        let descript = descriptor.clone();
-
-        // ocicrypt-rs keyprovider module will create a new runtime to talk with
-        // attestation agent, to avoid startup a runtime within a runtime, we
-        // spawn a new thread here.
-        let handler = tokio::task::spawn_blocking(move || {
-            decrypt_layer_data(&encrypted_layer, &descript, &cc)
-        });
-
-        if let Ok(decrypted_data) = handler.await? {
-            Ok(decrypted_data)
-        } else {
-            Err(anyhow!("decrypt failed!"))
-        }
+        create_decrypt_config(vec![decrypt_config.to_string()], vec![])
+            .map_err(anyhow::Error::from)
+            .and_then(|cc| decrypt_layer_data(encrypted_layer, descriptor, &cc))
+            .map_err(|source| Error::Decryption {
+                digest: descriptor.digest.clone(),
+                source,
+            })
     }
 }
 
-fn decrypt_layer_data(
-    encrypted_layer: &[u8],
+fn decrypt_layer_data<'a, R>(
+    encrypted_layer: R,
     descriptor: &OciDescriptor,
     crypto_config: &CryptoConfig,
-) -> Result<Vec<u8>> {
+) -> anyhow::Result<Box<dyn Read + 'a>>
+where
+    R: Read + 'a,
+{
     if let Some(decrypt_config) = &crypto_config.decrypt_config {
         let (layer_decryptor, _dec_digest) =
             decrypt_layer(decrypt_config, encrypted_layer, descriptor, false)?;
-        let mut plaintext_data: Vec<u8> = Vec::new();
-        let mut decryptor = layer_decryptor.ok_or_else(|| anyhow!("missing layer decryptor"))?;
-
-        decryptor.read_to_end(&mut plaintext_data)?;
+        let decryptor = layer_decryptor.ok_or_else(|| anyhow!("missing layer decryptor"))?;
 
-        Ok(plaintext_data)
+        Ok(Box::new(decryptor))
     } else {
         Err(anyhow!("no decrypt config available"))
     }
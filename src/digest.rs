@@ -0,0 +1,200 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use sha2::Digest as _;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::stream::HashReader;
+
+pub use signature::image::digest::Algorithm;
+
+/// A content digest in `algorithm:hex` format, like the digests of layer
+/// blobs, diff_ids, manifests and image ids. The digest strings are parsed
+/// by the parser of the `signature` crate, so sha256, sha384 and sha512
+/// are supported everywhere.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: Algorithm,
+    value: String,
+}
+
+impl Digest {
+    /// Get the algorithm of the digest.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Get the hex encoded value of the digest.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Calculate the digest of all the data read from reader with the
+    /// given algorithm.
+    pub fn from_reader<R: Read>(algorithm: Algorithm, reader: R) -> io::Result<Digest> {
+        let mut reader = HashReader::new(reader, DigestHasher::new(algorithm));
+        io::copy(&mut reader, &mut io::sink())?;
+
+        Ok(reader.digest())
+    }
+
+    /// Calculate the digest of data with the given algorithm.
+    pub fn from_bytes(algorithm: Algorithm, data: &[u8]) -> Digest {
+        let mut hasher = DigestHasher::new(algorithm);
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Verify data against the digest, with the algorithm of the digest.
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        let actual = Digest::from_bytes(self.algorithm, data);
+        if &actual != self {
+            return Err(Error::DigestMismatch {
+                expected: self.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.value)
+    }
+}
+
+impl TryFrom<&str> for Digest {
+    type Error = Error;
+
+    fn try_from(digest: &str) -> Result<Self> {
+        let parsed = signature::image::digest::Digest::try_from(digest)
+            .map_err(|_| Error::UnsupportedDigest(digest.to_string()))?;
+        let algorithm = Algorithm::from_str(&parsed.algorithm())
+            .map_err(|_| Error::UnsupportedDigest(digest.to_string()))?;
+
+        // The hex digits are compared in lower case.
+        Ok(Digest {
+            algorithm,
+            value: parsed.value().to_ascii_lowercase(),
+        })
+    }
+}
+
+impl FromStr for Digest {
+    type Err = Error;
+
+    fn from_str(digest: &str) -> Result<Self> {
+        Digest::try_from(digest)
+    }
+}
+
+impl PartialEq<str> for Digest {
+    fn eq(&self, other: &str) -> bool {
+        Digest::try_from(other).map_or(false, |other| &other == self)
+    }
+}
+
+impl PartialEq<String> for Digest {
+    fn eq(&self, other: &String) -> bool {
+        self == other.as_str()
+    }
+}
+
+/// Incrementally calculates a digest of one of the supported algorithms.
+#[derive(Clone)]
+pub enum DigestHasher {
+    Sha256(sha2::Sha256),
+    Sha384(sha2::Sha384),
+    Sha512(sha2::Sha512),
+}
+
+impl DigestHasher {
+    /// Construct a DigestHasher of the algorithm.
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => DigestHasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha384 => DigestHasher::Sha384(sha2::Sha384::new()),
+            Algorithm::Sha512 => DigestHasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            DigestHasher::Sha256(hasher) => hasher.update(data),
+            DigestHasher::Sha384(hasher) => hasher.update(data),
+            DigestHasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Digest {
+        let (algorithm, value) = match self {
+            DigestHasher::Sha256(hasher) => (Algorithm::Sha256, format!("{:x}", hasher.finalize())),
+            DigestHasher::Sha384(hasher) => (Algorithm::Sha384, format!("{:x}", hasher.finalize())),
+            DigestHasher::Sha512(hasher) => (Algorithm::Sha512, format!("{:x}", hasher.finalize())),
+        };
+
+        Digest { algorithm, value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest as _;
+
+    #[test]
+    fn test_digest() {
+        let data = b"This is some text!".to_vec();
+
+        let tests = [
+            (
+                Algorithm::Sha256,
+                format!("sha256:{:x}", sha2::Sha256::digest(&data)),
+            ),
+            (
+                Algorithm::Sha384,
+                format!("sha384:{:x}", sha2::Sha384::digest(&data)),
+            ),
+            (
+                Algorithm::Sha512,
+                format!("sha512:{:x}", sha2::Sha512::digest(&data)),
+            ),
+        ];
+
+        for (algorithm, expected) in tests.iter() {
+            let digest = Digest::try_from(expected.as_str()).unwrap();
+            assert_eq!(digest.algorithm(), *algorithm);
+            assert_eq!(digest.to_string(), *expected);
+
+            assert_eq!(Digest::from_bytes(*algorithm, &data), digest);
+            assert_eq!(
+                Digest::from_reader(*algorithm, data.as_slice()).unwrap(),
+                digest
+            );
+            assert!(digest.verify(&data).is_ok());
+            assert!(matches!(
+                digest.verify(b"foo"),
+                Err(Error::DigestMismatch { expected: e, .. }) if &e == expected
+            ));
+
+            assert!(digest == *expected);
+            assert!(digest == expected.to_uppercase().replace("SHA", "sha"));
+        }
+
+        assert!(matches!(
+            Digest::try_from(""),
+            Err(Error::UnsupportedDigest(_))
+        ));
+        assert!(Digest::try_from("sha256:").is_err());
+        assert!(Digest::try_from("md5:d41d8cd98f00b204e9800998ecf8427e").is_err());
+        assert!(Digest::try_from("sha256:0123abcd").is_err());
+        assert!(Digest::from_bytes(Algorithm::Sha256, &data) != *"sha256:0123abcd");
+    }
+}
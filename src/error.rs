@@ -0,0 +1,145 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// The result type of the `image-rs` API.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The errors of the `image-rs` API. The variants tell the failures a
+/// caller may handle apart, the others are wrapped into `Other` with their
+/// context chained.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The registry rejected the credential, or no credential could be
+    /// found for the registry.
+    #[error("authentication to {registry} failed")]
+    Auth {
+        registry: String,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    /// An image, a blob or a bundle is not found.
+    #[error("{0} not found")]
+    NotFound(String),
+
+    /// The digest is malformed or of an unsupported algorithm.
+    #[error("unsupported digest format: {0:?}")]
+    UnsupportedDigest(String),
+
+    /// The content mismatches with its expected digest.
+    #[error("unequal digest {actual:?} expected digest {expected:?}")]
+    DigestMismatch { expected: String, actual: String },
+
+    /// The blob size mismatches with its descriptor.
+    #[error("unequal size {actual} of blob {digest:?} descriptor size {expected}")]
+    SizeMismatch {
+        digest: String,
+        expected: i64,
+        actual: u64,
+    },
+
+    /// No key is provided to decrypt an encrypted layer.
+    #[error("decryption key unavailable for layer {0}")]
+    DecryptionKeyUnavailable(String),
+
+    /// The layer can not be decrypted with the provided key.
+    #[error("failed to decrypt layer {digest}")]
+    Decryption {
+        digest: String,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    /// The image is rejected by the security policy.
+    #[error("image {image} rejected by policy")]
+    PolicyRejected {
+        image: String,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    /// The media type of a layer is not handled.
+    #[error("unhandled media type: {0}")]
+    UnsupportedMediaType(String),
+
+    /// The rootfs of a bundle can not be mounted.
+    #[error("failed to mount {target:?}")]
+    Mount {
+        target: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The rootfs of a bundle can not be unmounted.
+    #[error("failed to unmount {target:?}")]
+    Unmount {
+        target: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The configuration or the arguments are invalid.
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+/// The internal helpers return `anyhow::Error`, which may carry an `Error`
+/// raised deeper down. It is recovered here so that the variant is not
+/// lost in `Other`.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => match err.downcast::<io::Error>() {
+                Ok(err) => Error::Io(err),
+                Err(err) => Error::Other(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_from_anyhow() {
+        let err: anyhow::Error = Error::NotFound("image foo".to_string()).into();
+        assert!(matches!(Error::from(err), Error::NotFound(what) if what == "image foo"));
+
+        let err: anyhow::Error = io::Error::from(io::ErrorKind::NotFound).into();
+        assert!(matches!(Error::from(err), Error::Io(_)));
+
+        let err = Error::from(anyhow::anyhow!("foo").context("bar"));
+        assert_eq!(err.to_string(), "bar");
+        assert_eq!(std::error::Error::source(&err).unwrap().to_string(), "foo");
+
+        let err = Error::Auth {
+            registry: "docker.io".to_string(),
+            source: anyhow::anyhow!("401 Unauthorized"),
+        };
+        assert_eq!(err.to_string(), "authentication to docker.io failed");
+        assert_eq!(
+            std::error::Error::source(&err).unwrap().to_string(),
+            "401 Unauthorized"
+        );
+
+        let err = Err::<(), _>(io::Error::from(io::ErrorKind::NotFound))
+            .context("read layer")
+            .map_err(Error::from)
+            .unwrap_err();
+        assert!(matches!(err, Error::Other(_)));
+    }
+}
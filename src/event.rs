@@ -0,0 +1,59 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::PathBuf;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// The progress events of an image pull. The image events carry the
+/// image reference as it is pulled and manifest digest, the layer events
+/// carry the compressed layer digest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PullEvent {
+    /// The image manifest and config are pulled from the endpoint, which
+    /// is the image reference on the mirror or registry serving it.
+    ManifestResolved {
+        image: String,
+        endpoint: String,
+        digest: String,
+    },
+
+    /// The bytes of a layer blob downloaded so far, and the blob size.
+    LayerDownloading {
+        digest: String,
+        downloaded: u64,
+        total: u64,
+    },
+
+    /// The layer is decrypted.
+    LayerDecrypted { digest: String },
+
+    /// The layer is decompressed.
+    LayerDecompressed { digest: String },
+
+    /// The compressed digest and the diff_id of the layer are verified.
+    LayerVerified { digest: String },
+
+    /// The layer is unpacked into the layer store.
+    LayerUnpacked { digest: String },
+
+    /// The image signature is checked against the policy.
+    SignatureChecked { image: String, digest: String },
+
+    /// The bundle of the image is created.
+    BundleCreated {
+        image_id: String,
+        bundle_dir: PathBuf,
+    },
+}
+
+/// The sending half of a pull event channel.
+pub type PullEventSender = UnboundedSender<PullEvent>;
+
+/// Send a pull event if there is a receiver. A pull never fails because
+/// the receiver is gone.
+pub(crate) fn emit(sender: &Option<PullEventSender>, event: PullEvent) {
+    if let Some(sender) = sender {
+        let _ = sender.send(event);
+    }
+}
@@ -0,0 +1,642 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_distribution::{secrets::RegistryAuth, Reference};
+use reqwest::header::{RANGE, WWW_AUTHENTICATE};
+use reqwest::{Response, StatusCode, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::RetryConfig;
+use crate::error::Error;
+
+/// The file name suffix of a blob being downloaded.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// The number of downloaded bytes between two progress reports.
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+/// The token servers trusted with the credentials of a registry, besides
+/// the registry host itself.
+const TRUSTED_REALMS: &[(&str, &str)] = &[("docker.io", "auth.docker.io")];
+
+/// An error which won't go away by retrying, like a missing blob.
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PermanentError {}
+
+/// Backoff tracks the retries of an operation and the delay before the
+/// next retry, which starts from `initial_backoff_ms` and doubles after
+/// each retry until `max_backoff_ms`.
+pub struct Backoff {
+    max_retries: u32,
+    retries: u32,
+    delay: Duration,
+    max_delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(config: &RetryConfig) -> Backoff {
+        let max_delay = Duration::from_millis(config.max_backoff_ms);
+        Backoff {
+            max_retries: config.max_retries,
+            retries: 0,
+            delay: Duration::from_millis(config.initial_backoff_ms).min(max_delay),
+            max_delay,
+        }
+    }
+
+    /// Get the delay before retrying the failed operation `what`, or None
+    /// if the error is permanent or there are too many retries. Besides a
+    /// `PermanentError`, an authentication failure or a missing manifest
+    /// or blob is permanent, including those reported by the registry
+    /// client.
+    pub fn next_delay(&mut self, what: &str, err: &anyhow::Error) -> Option<Duration> {
+        if self.retries >= self.max_retries || is_permanent(err) {
+            return None;
+        }
+
+        self.retries += 1;
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.max_delay);
+
+        log::warn!(
+            "{} failed, retry {}/{} in {:?}: {}",
+            what,
+            self.retries,
+            self.max_retries,
+            delay,
+            err
+        );
+
+        Some(delay)
+    }
+}
+
+/// The `oci-distribution` errors only carry the registry response in
+/// their messages, which tell an unauthorized request and the OCI error
+/// codes (or their messages) of a missing or forbidden manifest or blob.
+const PERMANENT_REGISTRY_ERRORS: &[&str] = &[
+    "not authorized",
+    "unauthorized",
+    "denied",
+    "manifest unknown",
+    "manifest_unknown",
+    "blob unknown",
+    "blob_unknown",
+    "name unknown",
+    "name_unknown",
+];
+
+fn is_permanent(err: &anyhow::Error) -> bool {
+    if err.downcast_ref::<PermanentError>().is_some()
+        || matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Auth { .. }) | Some(Error::NotFound(_))
+        )
+    {
+        return true;
+    }
+
+    err.chain().any(|cause| {
+        if let Some(status) = cause
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+        {
+            return matches!(
+                status,
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+            );
+        }
+
+        let message = cause.to_string().to_lowercase();
+        PERMANENT_REGISTRY_ERRORS
+            .iter()
+            .any(|pattern| message.contains(pattern))
+    })
+}
+
+/// retry runs `op` until it succeeds, fails with a `PermanentError`, or
+/// fails more than `max_retries` times, with an exponential backoff
+/// between the attempts.
+pub async fn retry<T, F, Fut>(config: &RetryConfig, what: &str, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = Backoff::new(config);
+
+    loop {
+        match op().await {
+            Err(e) => match backoff.next_delay(what, &e) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(e),
+            },
+            result => return result,
+        }
+    }
+}
+
+/// The response of a registry token server.
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+/// The BlobFetcher downloads blobs from OCI registries into local files.
+/// A blob is written to a partial file first, so that an interrupted
+/// download is resumed with an HTTP Range request by the next attempt.
+pub struct BlobFetcher {
+    http: reqwest::Client,
+
+    /// The registries to talk to with plain HTTP.
+    insecure_registries: Vec<String>,
+
+    retry: RetryConfig,
+
+    /// The bearer tokens of each "<registry>/<repository>".
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl BlobFetcher {
+    pub fn new(insecure_registries: Vec<String>, retry: RetryConfig) -> Result<BlobFetcher> {
+        let http = reqwest::Client::builder()
+            .build()
+            .map_err(|e| anyhow!("failed to create http client: {}", e))?;
+
+        Ok(BlobFetcher {
+            http,
+            insecure_registries,
+            retry,
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// fetch_blob downloads the blob of `digest` in the repository of
+    /// `reference` to `path`, retrying and resuming on failures. Nothing
+    /// is downloaded if `path` already exists. The content is not
+    /// verified against the digest here. The downloaded bytes are
+    /// reported to `progress` every `PROGRESS_INTERVAL` bytes.
+    pub async fn fetch_blob(
+        &self,
+        reference: &Reference,
+        auth: &RegistryAuth,
+        digest: &str,
+        size: i64,
+        path: &Path,
+        progress: &(dyn Fn(u64) + Sync),
+    ) -> Result<()> {
+        if fs::metadata(path).await.is_ok() {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(PARTIAL_SUFFIX);
+        let partial = PathBuf::from(partial);
+
+        let what = format!("pull blob {} of {}", digest, reference);
+        let partial_path = partial.as_path();
+        retry(&self.retry, &what, move || {
+            self.fetch_once(reference, auth, digest, size, partial_path, progress)
+        })
+        .await?;
+
+        fs::rename(&partial, path).await?;
+
+        Ok(())
+    }
+
+    async fn fetch_once(
+        &self,
+        reference: &Reference,
+        auth: &RegistryAuth,
+        digest: &str,
+        size: i64,
+        partial: &Path,
+        progress: &(dyn Fn(u64) + Sync),
+    ) -> Result<()> {
+        let offset = match fs::metadata(partial).await {
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        };
+
+        if size > 0 {
+            if offset == size as u64 {
+                progress(offset);
+                return Ok(());
+            }
+
+            // Something is wrong with the partial file, start over.
+            if offset > size as u64 {
+                fs::remove_file(partial).await?;
+                return Err(anyhow!("partial blob {:?} exceeds size {}", partial, size));
+            }
+        }
+
+        let mut response = self.send(reference, auth, digest, offset).await?;
+
+        let (mut file, mut downloaded) = match response.status() {
+            StatusCode::PARTIAL_CONTENT => (
+                fs::OpenOptions::new().append(true).open(partial).await?,
+                offset,
+            ),
+            // The registry doesn't support range requests.
+            StatusCode::OK => (fs::File::create(partial).await?, 0),
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                fs::remove_file(partial).await?;
+                return Err(anyhow!("invalid range of partial blob {:?}", partial));
+            }
+            status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+                return Err(Error::Auth {
+                    registry: reference.registry().to_string(),
+                    source: anyhow!(
+                        "failed to pull blob {} of {}: {}",
+                        digest,
+                        reference,
+                        status
+                    ),
+                }
+                .into());
+            }
+            StatusCode::NOT_FOUND => {
+                return Err(Error::NotFound(format!("blob {} of {}", digest, reference)).into());
+            }
+            status
+                if status.is_client_error()
+                    && status != StatusCode::REQUEST_TIMEOUT
+                    && status != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                return Err(PermanentError(format!(
+                    "failed to pull blob {} of {}: {}",
+                    digest, reference, status
+                ))
+                .into());
+            }
+            status => {
+                return Err(anyhow!(
+                    "failed to pull blob {} of {}: {}",
+                    digest,
+                    reference,
+                    status
+                ))
+            }
+        };
+
+        let mut reported = downloaded;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            downloaded += chunk.len() as u64;
+            if downloaded - reported >= PROGRESS_INTERVAL {
+                progress(downloaded);
+                reported = downloaded;
+            }
+        }
+        file.sync_all().await?;
+        progress(downloaded);
+
+        Ok(())
+    }
+
+    // send sends the blob request, and answers the authentication
+    // challenge of the registry if it is asked for.
+    async fn send(
+        &self,
+        reference: &Reference,
+        auth: &RegistryAuth,
+        digest: &str,
+        offset: u64,
+    ) -> Result<Response> {
+        let registry = reference.resolve_registry();
+        let scheme = if self.is_insecure(reference) {
+            "http"
+        } else {
+            "https"
+        };
+        let url = format!(
+            "{}://{}/v2/{}/blobs/{}",
+            scheme,
+            registry,
+            reference.repository(),
+            digest
+        );
+        let token_key = format!("{}/{}", registry, reference.repository());
+
+        let request = |token: Option<&String>| {
+            let mut request = self.http.get(&url);
+            if offset > 0 {
+                request = request.header(RANGE, format!("bytes={}-", offset));
+            }
+            match (token, auth) {
+                (Some(token), _) => request.bearer_auth(token),
+                (None, RegistryAuth::Basic(username, password)) => {
+                    request.basic_auth(username, Some(password))
+                }
+                _ => request,
+            }
+        };
+
+        let token = self.tokens.lock().unwrap().get(&token_key).cloned();
+        let response = request(token.as_ref()).send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_challenge)
+            .ok_or_else(|| Error::Auth {
+                registry: reference.registry().to_string(),
+                source: anyhow!("unauthorized to pull blob {} of {}", digest, reference),
+            })?;
+
+        let (scheme, params) = challenge;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Err(Error::Auth {
+                registry: reference.registry().to_string(),
+                source: anyhow!("unauthorized to pull blob {} of {}", digest, reference),
+            }
+            .into());
+        }
+
+        let token = self.fetch_token(reference, auth, &params).await?;
+        self.tokens.lock().unwrap().insert(token_key, token.clone());
+
+        Ok(request(Some(&token)).send().await?)
+    }
+
+    fn is_insecure(&self, reference: &Reference) -> bool {
+        self.insecure_registries
+            .iter()
+            .any(|r| r == reference.resolve_registry() || r == reference.registry())
+    }
+
+    // fetch_token gets a bearer token from the token server in the
+    // authentication challenge. The registry credentials are only sent to
+    // a token server trusted by the registry, see `check_realm`.
+    async fn fetch_token(
+        &self,
+        reference: &Reference,
+        auth: &RegistryAuth,
+        params: &HashMap<String, String>,
+    ) -> Result<String> {
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| anyhow!("no realm in authentication challenge"))?;
+        let scope = params
+            .get("scope")
+            .cloned()
+            .unwrap_or_else(|| format!("repository:{}:pull", reference.repository()));
+
+        let mut query = vec![("scope", scope)];
+        if let Some(service) = params.get("service") {
+            query.push(("service", service.clone()));
+        }
+
+        let (url, trusted) = check_realm(reference, realm, self.is_insecure(reference))?;
+        let mut request = self.http.get(url).query(&query);
+        if let RegistryAuth::Basic(username, password) = auth {
+            if trusted {
+                request = request.basic_auth(username, Some(password));
+            } else {
+                log::warn!(
+                    "not sending the credentials of {} to token server {}",
+                    reference.registry(),
+                    realm
+                );
+            }
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(Error::Auth {
+                registry: reference.registry().to_string(),
+                source: anyhow!("failed to get token from {}: {}", realm, response.status()),
+            }
+            .into());
+        }
+
+        let token: TokenResponse = response.json().await?;
+        token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| anyhow!("no token in response of {}", realm))
+    }
+}
+
+// Check the token server `realm` of the registry of `reference`, and tell
+// whether the registry credentials may be sent to it. The token server has
+// to be on https, unless the registry itself is insecure, and only the
+// registry host or a token server in TRUSTED_REALMS gets the credentials.
+fn check_realm(reference: &Reference, realm: &str, insecure: bool) -> Result<(Url, bool)> {
+    let auth_error = |source| Error::Auth {
+        registry: reference.registry().to_string(),
+        source,
+    };
+
+    let url = Url::parse(realm)
+        .map_err(|e| auth_error(anyhow!("invalid token server {}: {}", realm, e)))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if insecure => {}
+        _ => {
+            return Err(auth_error(anyhow!("insecure token server {}", realm)).into());
+        }
+    }
+
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(auth_error(anyhow!("invalid token server {}", realm)).into()),
+    };
+    let trusted = host == reference.registry()
+        || host == reference.resolve_registry()
+        || TRUSTED_REALMS
+            .iter()
+            .any(|(registry, server)| *registry == reference.registry() && *server == host);
+
+    Ok((url, trusted))
+}
+
+// Parse an authentication challenge like:
+// Bearer realm="https://auth.docker.io/token",service="registry.docker.io"
+fn parse_challenge(header: &str) -> Option<(String, HashMap<String, String>)> {
+    let (scheme, rest) = header.trim().split_once(' ')?;
+    let mut params = HashMap::new();
+
+    let mut rest = rest.trim();
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let key = key.trim().to_lowercase();
+        let value = value.trim_start();
+
+        let (value, remain) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => match value.find(',') {
+                Some(end) => (&value[..end], &value[end..]),
+                None => (value, ""),
+            },
+        };
+
+        params.insert(key, value.to_string());
+        rest = remain.trim_start().trim_start_matches(',').trim_start();
+    }
+
+    Some((scheme.to_string(), params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_parse_challenge() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/busybox:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/busybox:pull,push");
+
+        let (scheme, params) = parse_challenge(r#"Basic realm=registry"#).unwrap();
+        assert_eq!(scheme, "Basic");
+        assert_eq!(params["realm"], "registry");
+
+        assert!(parse_challenge("Bearer").is_none());
+    }
+
+    #[test]
+    fn test_check_realm() {
+        let reference = Reference::try_from("quay.io/foo/bar").unwrap();
+        let (url, trusted) = check_realm(&reference, "https://quay.io/v2/auth", false).unwrap();
+        assert_eq!(url.as_str(), "https://quay.io/v2/auth");
+        assert!(trusted);
+
+        let (_, trusted) =
+            check_realm(&reference, "https://evil.example.com/token", false).unwrap();
+        assert!(!trusted);
+
+        let (_, trusted) = check_realm(&reference, "https://auth.docker.io/token", false).unwrap();
+        assert!(!trusted);
+
+        let reference = Reference::try_from("busybox").unwrap();
+        let (_, trusted) = check_realm(&reference, "https://auth.docker.io/token", false).unwrap();
+        assert!(trusted);
+
+        // The credentials never go over plain HTTP to a secure registry.
+        let err = check_realm(&reference, "http://auth.docker.io/token", false)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Auth { .. })
+        ));
+
+        let reference = Reference::try_from("localhost:5000/foo").unwrap();
+        let (_, trusted) = check_realm(&reference, "http://localhost:5000/token", true).unwrap();
+        assert!(trusted);
+        let (_, trusted) = check_realm(&reference, "http://localhost/token", true).unwrap();
+        assert!(!trusted);
+        assert!(check_realm(&reference, "http://localhost:5000/token", false).is_err());
+        assert!(check_realm(&reference, "token", true).is_err());
+    }
+
+    #[test]
+    fn test_is_permanent() {
+        assert!(is_permanent(&anyhow!(
+            "OCI API error: manifest unknown on http://localhost:5000/v2/foo/manifests/latest"
+        )));
+        assert!(is_permanent(&anyhow!(
+            "Not authorized: url https://example.com/v2/foo/manifests/latest"
+        )));
+        assert!(is_permanent(
+            &anyhow!("BLOB_UNKNOWN").context("failed to pull blob")
+        ));
+        assert!(is_permanent(
+            &Error::NotFound("manifest".to_string()).into()
+        ));
+
+        assert!(!is_permanent(&anyhow!(
+            "Server error at https://example.com/v2/foo/manifests/latest: 503"
+        )));
+        assert!(!is_permanent(&anyhow!("connection reset by peer")));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let config = RetryConfig {
+            max_retries: 2,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 2,
+        };
+
+        let attempts = &AtomicU32::new(0);
+        let result = retry(&config, "test", move || async move {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(anyhow!("transient")),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+
+        let attempts = &AtomicU32::new(0);
+        let result: Result<()> = retry(&config, "test", move || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow!("transient"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = &AtomicU32::new(0);
+        let result: Result<()> = retry(&config, "test", move || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(PermanentError("not found".to_string()).into())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let attempts = &AtomicU32::new(0);
+        let result: Result<()> = retry(&config, "test", move || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(Error::NotFound("blob".to_string()).into())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -2,21 +2,25 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use oci_spec::image::{ImageConfiguration, Os};
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 
 use crate::bundle::{create_runtime_config, BUNDLE_ROOTFS};
-use crate::config::ImageConfig;
+use crate::config::{ImageConfig, Platform};
 use crate::decoder::Compression;
+use crate::error::{Error, Result};
+use crate::event::{emit, PullEvent, PullEventSender};
 use crate::meta_store::{MetaStore, METAFILE};
 use crate::pull::PullClient;
+use crate::singleflight::SingleFlight;
 
 #[cfg(feature = "overlay_feature")]
 use crate::snapshots::overlay::OverLay;
@@ -24,10 +28,16 @@ use crate::snapshots::overlay::OverLay;
 #[cfg(feature = "occlum_feature")]
 use crate::snapshots::occlum::unionfs::Unionfs;
 
-use crate::snapshots::{SnapshotType, Snapshotter};
+#[cfg(feature = "native_feature")]
+use crate::snapshots::native::Native;
+
+use crate::snapshots::{MountOptions, MountPoint, SnapshotType, Snapshotter};
+use crate::source::archive::ArchiveSource;
+use crate::source::{ImageSource, OCI_LAYOUT_TRANSPORT};
+use crate::unpack::{IdMappings, WhiteoutFormat};
 
 /// The metadata info for container image layer.
-#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct LayerMeta {
     /// Image layer compression algorithm type.
     pub decoder: Compression,
@@ -43,10 +53,42 @@ pub struct LayerMeta {
 
     /// The image layer storage path.
     pub store_path: String,
+
+    /// The id mappings the file ownership of the layer is shifted with.
+    #[serde(default)]
+    pub id_mappings: IdMappings,
+
+    /// The format the whiteouts of the layer are unpacked in.
+    #[serde(default)]
+    pub whiteout: WhiteoutFormat,
+}
+
+impl LayerMeta {
+    /// Get the key of the layer in the layer db.
+    pub fn key(&self) -> String {
+        layer_key(&self.compressed_digest, self.whiteout, &self.id_mappings)
+    }
+}
+
+/// Get the key in the layer db of the layer with the compressed digest,
+/// unpacked in the whiteout format with the id mappings. The layers
+/// unpacked with overlay whiteouts and without mappings are keyed by the
+/// digest alone.
+pub fn layer_key(digest: &str, whiteout: WhiteoutFormat, id_mappings: &IdMappings) -> String {
+    let mut key = digest.to_string();
+    if whiteout == WhiteoutFormat::Oci {
+        key.push_str("@oci");
+    }
+    if !id_mappings.is_empty() {
+        key.push('@');
+        key.push_str(&id_mappings.id());
+    }
+
+    key
 }
 
 /// The metadata info for container image.
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ImageMeta {
     /// The digest of the image configuration.
     pub id: String,
@@ -63,13 +105,43 @@ pub struct ImageMeta {
     /// Whether image is signed.
     pub signed: bool,
 
+    /// The platform of the image.
+    #[serde(default)]
+    pub platform: Platform,
+
     /// The metadata of image layers.
     pub layer_metas: Vec<LayerMeta>,
 }
 
+impl ImageMeta {
+    /// Whether all the layers of the image are unpacked in the whiteout
+    /// format with the id mappings, so that a bundle can be created from
+    /// them as they are.
+    pub fn unpacked_with(&self, whiteout: WhiteoutFormat, id_mappings: &IdMappings) -> bool {
+        self.layer_metas
+            .iter()
+            .all(|layer| layer.whiteout == whiteout && &layer.id_mappings == id_mappings)
+    }
+}
+
+/// The metadata info for a bundle prepared by `image-rs`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BundleMeta {
+    /// The ID of the image of the bundle rootfs.
+    pub image_id: String,
+
+    /// The snapshot used to mount the bundle rootfs.
+    pub snapshot: SnapshotType,
+
+    /// The mount point of the bundle rootfs.
+    pub mount_point: MountPoint,
+}
+
 /// The`image-rs` client will support OCI image
 /// pulling, image signing verfication, image layer
 /// decryption/unpack/store and management.
+/// The client can be shared as `Arc<ImageClient>` to pull images
+/// concurrently, a shared image or layer is only pulled once.
 pub struct ImageClient {
     /// The config for `image-rs` client.
     pub config: ImageConfig,
@@ -79,13 +151,53 @@ pub struct ImageClient {
 
     /// The supported snapshots for `image-rs` client.
     pub snapshots: HashMap<SnapshotType, Box<dyn Snapshotter>>,
+
+    /// The images being populated, by image ID.
+    pub image_flights: SingleFlight,
+
+    /// The layers being pulled, by layer digest.
+    pub layer_flights: SingleFlight,
+
+    /// Held for read by the pulls and for write by the garbage collection,
+    /// so that the layers of a pull in progress are not collected.
+    pub gc_lock: RwLock<()>,
 }
 
 impl Default for ImageClient {
-    // construct a default instance of `ImageClient`
+    // construct a default instance of `ImageClient`, it panics if the
+    // metadata database under the default work_dir fails to load.
     fn default() -> ImageClient {
-        let config = ImageConfig::default();
-        let meta_store = MetaStore::try_from(Path::new(METAFILE)).unwrap_or_default();
+        ImageClient::with_config(ImageConfig::default())
+            .expect("failed to load the image-rs metadata database")
+    }
+}
+
+impl ImageClient {
+    /// Construct an `ImageClient` with the config, which is validated
+    /// first. The pulled images and layers recorded in the metadata
+    /// database under the config work_dir are loaded, it fails if the
+    /// metadata database exists but can not be loaded, so that its layers
+    /// are not taken as stale and removed.
+    pub fn new(config: ImageConfig) -> Result<ImageClient> {
+        config.validate()?;
+
+        ImageClient::with_config(config)
+    }
+
+    /// Get an `ImageClientBuilder` to construct an `ImageClient`.
+    pub fn builder() -> ImageClientBuilder {
+        ImageClientBuilder::default()
+    }
+
+    // Construct an `ImageClient` with the config as is.
+    fn with_config(config: ImageConfig) -> Result<ImageClient> {
+        let meta_file = config.work_dir.join(METAFILE);
+        let meta_store = if meta_file.exists() {
+            MetaStore::try_from(meta_file.as_path())
+                .map_err(|e| anyhow!("failed to load {:?}: {}", meta_file, e))?
+        } else {
+            MetaStore::default()
+        };
 
         let mut snapshots = HashMap::new();
 
@@ -98,6 +210,7 @@ impl Default for ImageClient {
             let overlay = OverLay {
                 data_dir: config.work_dir.join(SnapshotType::Overlay.to_string()),
                 index: AtomicUsize::new(*overlay_index),
+                mount_label: config.selinux_label.clone(),
             };
             snapshots.insert(
                 SnapshotType::Overlay,
@@ -123,50 +236,129 @@ impl Default for ImageClient {
             );
         }
 
-        ImageClient {
+        #[cfg(feature = "native_feature")]
+        {
+            let native = Native {
+                mount_label: config.selinux_label.clone(),
+            };
+            snapshots.insert(
+                SnapshotType::Native,
+                Box::new(native) as Box<dyn Snapshotter>,
+            );
+        }
+
+        Ok(ImageClient {
             config,
             meta_store: Arc::new(Mutex::new(meta_store)),
             snapshots,
-        }
+            image_flights: SingleFlight::default(),
+            layer_flights: SingleFlight::default(),
+            gc_lock: RwLock::new(()),
+        })
     }
-}
 
-impl ImageClient {
     /// pull_image pulls an image with optional auth info and decrypt config
     /// and store the pulled data under user defined work_dir/layers.
     /// It will return the image ID with prepeared bundle: a rootfs directory,
     /// and config.json will be ready in the bundle_dir passed by user.
     pub async fn pull_image(
-        &mut self,
+        &self,
         image_url: &str,
         bundle_dir: &Path,
         auth_info: &Option<&str>,
         decrypt_config: &Option<&str>,
     ) -> Result<String> {
-        let mut client =
-            PullClient::new(image_url, &self.config.work_dir.join("layers"), auth_info)?;
+        let mount_options = self.config.mount_options.clone();
+        self.pull_image_with_options(
+            image_url,
+            bundle_dir,
+            auth_info,
+            decrypt_config,
+            &mount_options,
+        )
+        .await
+    }
+
+    /// pull_image_with_options pulls an image like `pull_image`, and mounts
+    /// the rootfs of the bundle with the mount options instead of the ones
+    /// in the config.
+    pub async fn pull_image_with_options(
+        &self,
+        image_url: &str,
+        bundle_dir: &Path,
+        auth_info: &Option<&str>,
+        decrypt_config: &Option<&str>,
+        mount_options: &MountOptions,
+    ) -> Result<String> {
+        // The signatures are checked against registry references, which
+        // an image layout does not have.
+        if self.config.security_validate && image_url.starts_with(OCI_LAYOUT_TRANSPORT) {
+            return Err(Error::InvalidConfig(
+                "security validation is not supported for OCI image layouts".to_string(),
+            ));
+        }
+
+        // The registry credentials may come from a credential helper,
+        // which is run as a blocking command.
+        let image = image_url.to_string();
+        let config = self.config.clone();
+        let auth_info = auth_info.map(|auth| auth.to_string());
+        let client = tokio::task::spawn_blocking(move || {
+            PullClient::new(&image, &config, &auth_info.as_deref())
+        })
+        .await
+        .map_err(|e| anyhow!("failed to create pull client: {}", e))??;
+        self.populate_image(client, image_url, bundle_dir, decrypt_config, mount_options)
+            .await
+    }
+
+    /// import_archive imports the image in a `docker save` tarball or an
+    /// OCI archive at path, and creates the bundle of the image in
+    /// bundle_dir like `pull_image`. The layers are unpacked right out of
+    /// the archive, which has to hold only one image.
+    pub async fn import_archive(&self, path: &Path, bundle_dir: &Path) -> Result<String> {
+        // The signatures are checked against registry references, which
+        // an archive does not have.
+        if self.config.security_validate {
+            return Err(Error::InvalidConfig(
+                "security validation is not supported for image archives".to_string(),
+            ));
+        }
+
+        let source = ArchiveSource::new(path, &self.config)?;
+        let reference = source.reference();
+        let client = PullClient::with_source(Box::new(source), &self.config)?;
+        let mount_options = self.config.mount_options.clone();
+        self.populate_image(client, &reference, bundle_dir, &None, &mount_options)
+            .await
+    }
+
+    // Pull the image manifest, config and layers with client into the
+    // meta store unless the image is there, and create the bundle.
+    async fn populate_image(
+        &self,
+        mut client: PullClient,
+        image_url: &str,
+        bundle_dir: &Path,
+        decrypt_config: &Option<&str>,
+        mount_options: &MountOptions,
+    ) -> Result<String> {
+        let _gc_guard = self.gc_lock.read().await;
+
         let (image_manifest, image_digest, image_config) = client.pull_manifest().await?;
 
         let id = image_manifest.config.digest.clone();
 
-        let snapshot = match self.snapshots.get_mut(&self.config.default_snapshot) {
+        let snapshot = match self.snapshots.get(&self.config.default_snapshot) {
             Some(s) => s,
             _ => {
-                return Err(anyhow!(
+                return Err(Error::InvalidConfig(format!(
                     "default snapshot {} not found",
                     &self.config.default_snapshot
-                ));
+                )));
             }
         };
 
-        // If image has already been populated, just create the bundle.
-        {
-            let m = self.meta_store.lock().await;
-            if let Some(image_data) = &m.image_db.get(&id) {
-                return create_bundle(image_data, bundle_dir, snapshot);
-            }
-        }
-
         if self.config.security_validate {
             if let Some(wrapped_aa_kbc_params) = decrypt_config {
                 let wrapped_aa_kbc_params = wrapped_aa_kbc_params.to_string();
@@ -175,25 +367,88 @@ impl ImageClient {
 
                 signature::allows_image(image_url, &image_digest, aa_kbc_params)
                     .await
-                    .map_err(|e| anyhow!("Security validate failed: {:?}", e))?;
+                    .map_err(|source| Error::PolicyRejected {
+                        image: image_url.to_string(),
+                        source,
+                    })?;
+
+                emit(
+                    &self.config.event_sender,
+                    PullEvent::SignatureChecked {
+                        image: image_url.to_string(),
+                        digest: image_digest.clone(),
+                    },
+                );
             } else {
-                return Err(anyhow!("Security validation need aa_kbc_params."));
+                return Err(Error::InvalidConfig(
+                    "security validation needs aa_kbc_params".to_string(),
+                ));
             }
         }
 
+        let image_config = ImageConfiguration::from_reader(image_config.as_bytes())
+            .map_err(anyhow::Error::from)?;
+        let platform = Platform {
+            os: image_config.os().to_string(),
+            architecture: image_config.architecture().to_string(),
+            variant: image_config.variant().clone(),
+        };
+
+        // Reject the image before fetching any layer if the registry did
+        // not resolve a manifest of the requested platform.
+        if !self.config.platform.matches(
+            &platform.os,
+            &platform.architecture,
+            platform.variant.as_deref(),
+        ) {
+            return Err(anyhow!(
+                "image platform {} mismatches with the requested platform {}",
+                platform,
+                self.config.platform
+            )
+            .into());
+        }
+
+        // The concurrent pulls of the image wait for the first one, and
+        // then find the image populated.
+        let flight = self.image_flights.acquire(&id).await;
+
+        // If image has already been populated, just create the bundle, as
+        // the policy and the platform are checked above for every pull. An
+        // image unpacked for another snapshot or with other id mappings is
+        // populated again, with the layers of the current ones.
+        let whiteout = self.config.default_snapshot.whiteout_format();
+        let image_data = self
+            .meta_store
+            .lock()
+            .await
+            .image_db
+            .get(&id)
+            .filter(|image| image.unpacked_with(whiteout, &self.config.id_mappings))
+            .cloned();
+        if let Some(image_data) = image_data {
+            drop(flight);
+            let mount_point =
+                create_bundle(&image_data, bundle_dir, snapshot.as_ref(), mount_options)?;
+            self.add_bundle(bundle_dir, &image_data.id, mount_point)
+                .await?;
+            return Ok(image_data.id);
+        }
+
         let mut image_data = ImageMeta {
             id,
             digest: image_digest,
             reference: image_url.to_string(),
-            image_config: ImageConfiguration::from_reader(image_config.as_bytes())?,
+            image_config,
+            platform,
             ..Default::default()
         };
 
         let diff_ids = image_data.image_config.rootfs().diff_ids();
         if diff_ids.len() != image_manifest.layers.len() {
-            return Err(anyhow!(
-                "Pulled number of layers mismatch with image config diff_ids"
-            ));
+            return Err(
+                anyhow!("Pulled number of layers mismatch with image config diff_ids").into(),
+            );
         }
 
         let layer_metas = client
@@ -201,36 +456,325 @@ impl ImageClient {
                 image_manifest.layers.clone(),
                 diff_ids,
                 decrypt_config,
+                whiteout,
                 self.meta_store.clone(),
+                &self.layer_flights,
             )
-            .await?;
+            .await;
 
-        image_data.layer_metas = layer_metas;
-        let layer_db: HashMap<String, LayerMeta> = image_data
-            .layer_metas
-            .iter()
-            .map(|layer| (layer.compressed_digest.clone(), layer.clone()))
-            .collect();
-
-        self.meta_store.lock().await.layer_db.extend(layer_db);
+        // The layers finished before a failure are kept in the layer db,
+        // so that they are not pulled again by the next pull.
+        image_data.layer_metas = match layer_metas {
+            Ok(layer_metas) => layer_metas,
+            Err(e) => {
+                self.save_meta_store().await?;
+                return Err(e);
+            }
+        };
 
-        let image_id = create_bundle(&image_data, bundle_dir, snapshot)?;
+        let mount_point = create_bundle(&image_data, bundle_dir, snapshot.as_ref(), mount_options)?;
 
+        let image_id = image_data.id.clone();
         self.meta_store
             .lock()
             .await
             .image_db
-            .insert(image_data.id.clone(), image_data);
+            .insert(image_id.clone(), image_data);
+        drop(flight);
+
+        self.add_bundle(bundle_dir, &image_id, mount_point).await?;
 
         Ok(image_id)
     }
+
+    /// unmount_bundle unmounts the rootfs of a bundle prepared by
+    /// `pull_image`, removes the snapshot work dir of the rootfs and
+    /// forgets the bundle.
+    pub async fn unmount_bundle(&self, bundle_dir: &Path) -> Result<()> {
+        let key = bundle_dir.display().to_string();
+        let bundle = self
+            .meta_store
+            .lock()
+            .await
+            .bundle_db
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(format!("bundle {}", key)))?;
+
+        let snapshot = self
+            .snapshots
+            .get(&bundle.snapshot)
+            .ok_or_else(|| Error::NotFound(format!("snapshot {}", bundle.snapshot)))?;
+        snapshot.unmount(&bundle.mount_point)?;
+
+        self.meta_store.lock().await.bundle_db.remove(&key);
+        self.save_meta_store().await
+    }
+
+    // add_bundle records the mount point of a bundle rootfs, saves the
+    // metadata database and reports the bundle is created.
+    async fn add_bundle(
+        &self,
+        bundle_dir: &Path,
+        image_id: &str,
+        mount_point: MountPoint,
+    ) -> Result<()> {
+        let bundle = BundleMeta {
+            image_id: image_id.to_string(),
+            snapshot: self.config.default_snapshot,
+            mount_point,
+        };
+
+        self.meta_store
+            .lock()
+            .await
+            .bundle_db
+            .insert(bundle_dir.display().to_string(), bundle);
+
+        self.save_meta_store().await?;
+
+        emit(
+            &self.config.event_sender,
+            PullEvent::BundleCreated {
+                image_id: image_id.to_string(),
+                bundle_dir: bundle_dir.to_path_buf(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// remove_image unmounts the bundles of the image with the given image
+    /// ID, digest or reference, removes the image from the metadata
+    /// database, and garbage collects the image layers which are not used
+    /// any more.
+    pub async fn remove_image(&self, image: &str) -> Result<()> {
+        let _gc_guard = self.gc_lock.write().await;
+
+        let (id, bundles) = {
+            let meta_store = self.meta_store.lock().await;
+            let id = meta_store
+                .image_db
+                .values()
+                .find(|meta| meta.id == image || meta.digest == image || meta.reference == image)
+                .map(|meta| meta.id.clone())
+                .ok_or_else(|| Error::NotFound(format!("image {}", image)))?;
+
+            let bundles: Vec<String> = meta_store
+                .bundle_db
+                .iter()
+                .filter(|(_, bundle)| bundle.image_id == id)
+                .map(|(bundle_dir, _)| bundle_dir.clone())
+                .collect();
+
+            (id, bundles)
+        };
+
+        for bundle_dir in bundles.iter() {
+            self.unmount_bundle(Path::new(bundle_dir)).await?;
+        }
+
+        self.meta_store.lock().await.image_db.remove(&id);
+
+        self.collect_layers().await
+    }
+
+    /// cleanup removes the layers of the pulls interrupted by a crash: the
+    /// layer dirs which are neither recorded in the metadata database nor
+    /// used by a mounted bundle, and the staging dirs of the interrupted
+    /// unpacks. It waits for the pulls in progress, and is meant to be
+    /// called once on startup. The work_dir must not be shared with another
+    /// process, whose layers would be taken as stale.
+    pub async fn cleanup(&self) -> Result<()> {
+        let _gc_guard = self.gc_lock.write().await;
+
+        let mut in_use = HashSet::new();
+        for snapshot in self.snapshots.values() {
+            in_use.extend(snapshot.layers_in_use()?);
+        }
+
+        let meta_store = self.meta_store.lock().await;
+        remove_stale_layers(
+            &self.config.work_dir.join("layers"),
+            &meta_store.layer_db,
+            &in_use,
+        )
+    }
+
+    /// garbage_collect removes the image layers which are neither
+    /// referenced by any image in the metadata database nor used by a
+    /// mounted bundle, together with the stale snapshot work dirs.
+    /// It waits for the pulls in progress to finish.
+    pub async fn garbage_collect(&self) -> Result<()> {
+        let _gc_guard = self.gc_lock.write().await;
+
+        self.collect_layers().await
+    }
+
+    // The garbage collection of garbage_collect, with gc_lock held.
+    async fn collect_layers(&self) -> Result<()> {
+        {
+            let mut meta_store = self.meta_store.lock().await;
+
+            let mut in_use: HashSet<String> = meta_store
+                .image_db
+                .values()
+                .flat_map(|meta| meta.layer_metas.iter().map(|l| l.store_path.clone()))
+                .collect();
+            for snapshot in self.snapshots.values() {
+                in_use.extend(snapshot.layers_in_use()?);
+            }
+
+            meta_store
+                .layer_db
+                .retain(|_, layer| in_use.contains(&layer.store_path));
+
+            remove_stale_layers(
+                &self.config.work_dir.join("layers"),
+                &meta_store.layer_db,
+                &in_use,
+            )?;
+
+            for snapshot in self.snapshots.values() {
+                snapshot.prune()?;
+            }
+        }
+
+        self.save_meta_store().await
+    }
+
+    /// save_meta_store writes the metadata database under `work_dir`,
+    /// together with the current work dir index of each snapshot, so
+    /// that the pulled images and layers survive a restart.
+    async fn save_meta_store(&self) -> Result<()> {
+        let mut meta_store = self.meta_store.lock().await;
+
+        for (snapshot_type, snapshot) in self.snapshots.iter() {
+            meta_store
+                .snapshot_db
+                .insert(snapshot_type.to_string(), snapshot.index());
+        }
+
+        meta_store.write_to_file(&self.config.work_dir.join(METAFILE))?;
+
+        Ok(())
+    }
+}
+
+// Remove the dirs under layer_dir which are neither layers recorded in the
+// layer_db nor in use, like the layers and the staging dir of interrupted
+// pulls. No pull may be in progress in the work_dir.
+fn remove_stale_layers(
+    layer_dir: &Path,
+    layer_db: &HashMap<String, LayerMeta>,
+    in_use: &HashSet<String>,
+) -> Result<()> {
+    if !layer_dir.exists() {
+        return Ok(());
+    }
+
+    let layer_paths: HashSet<&str> = layer_db
+        .values()
+        .map(|layer| layer.store_path.as_str())
+        .collect();
+    for entry in fs::read_dir(layer_dir)? {
+        let path = entry?.path();
+        let path_str = path.to_string_lossy();
+        if layer_paths.contains(path_str.as_ref()) || in_use.contains(path_str.as_ref()) {
+            continue;
+        }
+
+        fs::remove_dir_all(&path)
+            .map_err(|e| anyhow!("failed to remove layer {:?}: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// The builder of `ImageClient`. The config is loaded from the config
+/// file if one is given, or else the default config is used. The
+/// `CC_IMAGE_*` environment variables override the config, and the
+/// values set on the builder override both.
+#[derive(Default)]
+pub struct ImageClientBuilder {
+    config_file: Option<PathBuf>,
+    config: Option<ImageConfig>,
+    work_dir: Option<PathBuf>,
+    default_snapshot: Option<SnapshotType>,
+    security_validate: Option<bool>,
+    event_sender: Option<PullEventSender>,
+}
+
+impl ImageClientBuilder {
+    /// Load the config from a config file, which may only have some of
+    /// the fields.
+    pub fn config_file(mut self, path: &Path) -> Self {
+        self.config_file = Some(path.to_path_buf());
+        self
+    }
+
+    /// Start from the given config instead of a config file.
+    pub fn config(mut self, config: ImageConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Set the work dir to store the images.
+    pub fn work_dir(mut self, work_dir: &Path) -> Self {
+        self.work_dir = Some(work_dir.to_path_buf());
+        self
+    }
+
+    /// Set the snapshot to mount the bundle rootfs with.
+    pub fn default_snapshot(mut self, snapshot: SnapshotType) -> Self {
+        self.default_snapshot = Some(snapshot);
+        self
+    }
+
+    /// Set whether the image signatures are verified.
+    pub fn security_validate(mut self, security_validate: bool) -> Self {
+        self.security_validate = Some(security_validate);
+        self
+    }
+
+    /// Set the sender to report the pull progress events to.
+    pub fn event_sender(mut self, event_sender: PullEventSender) -> Self {
+        self.event_sender = Some(event_sender);
+        self
+    }
+
+    /// Build the `ImageClient`, the config is validated here.
+    pub fn build(self) -> Result<ImageClient> {
+        let config = match (self.config, &self.config_file) {
+            (Some(config), _) => config,
+            (None, Some(path)) => ImageConfig::try_from(path.as_path())?,
+            (None, None) => ImageConfig::default(),
+        };
+        let mut config = config.with_env_overrides()?;
+
+        if let Some(work_dir) = self.work_dir {
+            config.work_dir = work_dir;
+        }
+        if let Some(snapshot) = self.default_snapshot {
+            config.default_snapshot = snapshot;
+        }
+        if let Some(security_validate) = self.security_validate {
+            config.security_validate = security_validate;
+        }
+        if self.event_sender.is_some() {
+            config.event_sender = self.event_sender;
+        }
+
+        ImageClient::new(config)
+    }
 }
 
 fn create_bundle(
     image_data: &ImageMeta,
     bundle_dir: &Path,
-    snapshot: &mut Box<dyn Snapshotter>,
-) -> Result<String> {
+    snapshot: &dyn Snapshotter,
+    mount_options: &MountOptions,
+) -> Result<MountPoint> {
     let layer_path = image_data
         .layer_metas
         .iter()
@@ -238,21 +782,451 @@ fn create_bundle(
         .map(|l| l.store_path.as_str())
         .collect::<Vec<&str>>();
 
-    snapshot.mount(&layer_path, &bundle_dir.join(BUNDLE_ROOTFS))?;
-
     let image_config = image_data.image_config.clone();
     if image_config.os() != &Os::Linux {
-        return Err(anyhow!("unsupport OS image {:?}", image_config.os()));
+        return Err(anyhow!("unsupport OS image {:?}", image_config.os()).into());
     }
 
+    let mount_point =
+        snapshot.mount(&layer_path, &bundle_dir.join(BUNDLE_ROOTFS), mount_options)?;
+
     create_runtime_config(&image_config, bundle_dir)?;
-    let image_id = image_data.id.clone();
-    Ok(image_id)
+    Ok(mount_point)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pull::STAGING_DIR;
+    use crate::unpack::IdMapping;
+    use sha2::Digest;
+    use std::os::unix::fs::MetadataExt;
+    use test_utils::layer::{append_file, layer_tarball};
+    use test_utils::registry::TestRegistry;
+
+    fn layer_meta(work_dir: &Path, digest: &str) -> LayerMeta {
+        let store_path = work_dir.join("layers").join(digest);
+        fs::create_dir_all(&store_path).unwrap();
+
+        LayerMeta {
+            compressed_digest: digest.to_string(),
+            uncompressed_digest: digest.to_string(),
+            store_path: store_path.display().to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_image_client_builder() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let config_file = work_dir.path().join("config.json");
+        fs::write(
+            &config_file,
+            r#"{"security_validate": true, "max_concurrent_unpacks": 4}"#,
+        )
+        .unwrap();
+
+        let image_client = ImageClient::builder()
+            .config_file(&config_file)
+            .work_dir(work_dir.path())
+            .security_validate(false)
+            .build()
+            .unwrap();
+        assert_eq!(image_client.config.work_dir, work_dir.path());
+        assert!(!image_client.config.security_validate);
+        assert_eq!(image_client.config.max_concurrent_unpacks, 4);
+        assert_eq!(
+            image_client.snapshots.contains_key(&SnapshotType::Overlay),
+            cfg!(feature = "overlay_feature")
+        );
+
+        let result = ImageClient::builder()
+            .work_dir(Path::new("image-rs"))
+            .build();
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+
+        let result = ImageClient::builder()
+            .config_file(&work_dir.path().join("none.json"))
+            .build();
+        assert!(result.is_err());
+
+        #[cfg(not(feature = "occlum_feature"))]
+        assert!(matches!(
+            ImageClient::new(ImageConfig {
+                work_dir: work_dir.path().to_path_buf(),
+                default_snapshot: SnapshotType::OcclumUnionfs,
+                ..Default::default()
+            }),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_layer_key() {
+        let digest = "sha256:0123";
+        let mapping = IdMapping {
+            container_id: 0,
+            host_id: 100000,
+            size: 65536,
+        };
+        let id_mappings = IdMappings {
+            uid_mappings: vec![mapping.clone()],
+            gid_mappings: vec![mapping],
+        };
+
+        let none = IdMappings::default();
+        let keys: HashSet<String> = vec![
+            layer_key(digest, WhiteoutFormat::Overlay, &none),
+            layer_key(digest, WhiteoutFormat::Oci, &none),
+            layer_key(digest, WhiteoutFormat::Overlay, &id_mappings),
+            layer_key(digest, WhiteoutFormat::Oci, &id_mappings),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(keys.len(), 4);
+        assert!(keys.contains(digest));
+
+        let image = ImageMeta {
+            layer_metas: vec![LayerMeta {
+                compressed_digest: digest.to_string(),
+                whiteout: WhiteoutFormat::Oci,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(image.layer_metas[0].key(), format!("{}@oci", digest));
+        assert!(image.unpacked_with(WhiteoutFormat::Oci, &none));
+        assert!(!image.unpacked_with(WhiteoutFormat::Overlay, &none));
+        assert!(!image.unpacked_with(WhiteoutFormat::Oci, &id_mappings));
+
+        // The layers stored before the whiteout format was recorded were
+        // unpacked for overlay.
+        let layer_meta: LayerMeta = serde_json::from_str(
+            r#"{"decoder": "Gzip", "encrypted": false, "compressed_digest": "sha256:0123",
+                "uncompressed_digest": "", "store_path": ""}"#,
+        )
+        .unwrap();
+        assert_eq!(layer_meta.whiteout, WhiteoutFormat::Overlay);
+        assert_eq!(layer_meta.key(), digest);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let kept = layer_meta(work_dir.path(), "sha256_kept");
+        let stale = layer_meta(work_dir.path(), "sha256_stale");
+        let staged = layer_meta(work_dir.path(), ".staging/sha256_staged");
+
+        let mut meta_store = MetaStore::default();
+        meta_store
+            .layer_db
+            .insert("sha256:kept".to_string(), kept.clone());
+        meta_store
+            .write_to_file(&work_dir.path().join(METAFILE))
+            .unwrap();
+
+        let config = ImageConfig {
+            work_dir: work_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        // Nothing is removed until the cleanup.
+        let image_client = ImageClient::new(config.clone()).unwrap();
+        assert!(Path::new(&stale.store_path).exists());
+
+        image_client.cleanup().await.unwrap();
+        assert!(Path::new(&kept.store_path).exists());
+        assert!(!Path::new(&stale.store_path).exists());
+        assert!(!Path::new(&staged.store_path).exists());
+        assert!(!work_dir.path().join("layers").join(STAGING_DIR).exists());
+        drop(image_client);
+
+        // The client is not constructed if the meta store can not be
+        // loaded, so that its layers are not removed.
+        fs::write(work_dir.path().join(METAFILE), "corrupted").unwrap();
+        assert!(ImageClient::new(config).is_err());
+        assert!(Path::new(&kept.store_path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_remove_image() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let image_client = ImageClient {
+            config: ImageConfig {
+                work_dir: work_dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            meta_store: Arc::new(Mutex::new(MetaStore::default())),
+            snapshots: HashMap::new(),
+            image_flights: SingleFlight::default(),
+            layer_flights: SingleFlight::default(),
+            gc_lock: RwLock::new(()),
+        };
+
+        let shared = layer_meta(work_dir.path(), "sha256:shared");
+        let foo = layer_meta(work_dir.path(), "sha256:foo");
+        let bar = layer_meta(work_dir.path(), "sha256:bar");
+        let orphan = layer_meta(work_dir.path(), "sha256:orphan");
+
+        {
+            let mut meta_store = image_client.meta_store.lock().await;
+            for (id, layers) in [
+                ("sha256:image_foo", vec![shared.clone(), foo.clone()]),
+                ("sha256:image_bar", vec![shared.clone(), bar.clone()]),
+            ] {
+                for layer in layers.iter() {
+                    meta_store
+                        .layer_db
+                        .insert(layer.compressed_digest.clone(), layer.clone());
+                }
+                meta_store.image_db.insert(
+                    id.to_string(),
+                    ImageMeta {
+                        id: id.to_string(),
+                        reference: format!("example.com/{}:latest", &id[7..]),
+                        layer_metas: layers,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+
+        assert!(matches!(
+            image_client.remove_image("example.com/none").await,
+            Err(Error::NotFound(_))
+        ));
+
+        image_client
+            .remove_image("example.com/image_foo:latest")
+            .await
+            .unwrap();
+        assert!(Path::new(&shared.store_path).exists());
+        assert!(Path::new(&bar.store_path).exists());
+        assert!(!Path::new(&foo.store_path).exists());
+        assert!(!Path::new(&orphan.store_path).exists());
+
+        image_client.remove_image("sha256:image_bar").await.unwrap();
+        assert!(!Path::new(&shared.store_path).exists());
+        assert!(!Path::new(&bar.store_path).exists());
+
+        let meta_store = MetaStore::try_from(work_dir.path().join(METAFILE).as_path()).unwrap();
+        assert!(meta_store.image_db.is_empty());
+        assert!(meta_store.layer_db.is_empty());
+    }
+
+    #[cfg(feature = "overlay_feature")]
+    #[tokio::test]
+    async fn test_unmount_bundle() {
+        test_utils::skip_if_not_root!();
+
+        let work_dir = tempfile::tempdir().unwrap();
+        let bundle_dir = tempfile::tempdir().unwrap();
+        let overlay = OverLay {
+            data_dir: work_dir.path().join("overlay"),
+            index: AtomicUsize::new(0),
+            mount_label: None,
+        };
+        let mut snapshots = HashMap::new();
+        snapshots.insert(
+            SnapshotType::Overlay,
+            Box::new(overlay) as Box<dyn Snapshotter>,
+        );
+        let image_client = ImageClient {
+            config: ImageConfig {
+                work_dir: work_dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            meta_store: Arc::new(Mutex::new(MetaStore::default())),
+            snapshots,
+            image_flights: SingleFlight::default(),
+            layer_flights: SingleFlight::default(),
+            gc_lock: RwLock::new(()),
+        };
+
+        let layer = layer_meta(work_dir.path(), "sha256:foo");
+        fs::write(Path::new(&layer.store_path).join("foo"), "foo").unwrap();
+        let snapshot = image_client.snapshots.get(&SnapshotType::Overlay).unwrap();
+        let mount_point = snapshot
+            .mount(
+                &[&layer.store_path],
+                &bundle_dir.path().join(BUNDLE_ROOTFS),
+                &MountOptions::default(),
+            )
+            .unwrap();
+        let work = mount_point.work_dir.clone();
+        assert!(bundle_dir.path().join(BUNDLE_ROOTFS).join("foo").exists());
+
+        image_client
+            .add_bundle(bundle_dir.path(), "sha256:image_foo", mount_point)
+            .await
+            .unwrap();
+
+        image_client
+            .unmount_bundle(bundle_dir.path())
+            .await
+            .unwrap();
+        assert!(!bundle_dir.path().join(BUNDLE_ROOTFS).join("foo").exists());
+        assert!(!work.exists());
+        assert!(image_client.meta_store.lock().await.bundle_db.is_empty());
+
+        assert!(matches!(
+            image_client.unmount_bundle(bundle_dir.path()).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[cfg(feature = "overlay_feature")]
+    #[tokio::test]
+    async fn test_security_validate_local_images() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let bundle_dir = tempfile::tempdir().unwrap();
+        let image_client = ImageClient::new(ImageConfig {
+            work_dir: work_dir.path().to_path_buf(),
+            security_validate: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        // The local images are rejected before they are read, as they have
+        // no registry reference to check the policy against.
+        let layout = format!("oci:{}:latest", work_dir.path().join("layout").display());
+        let result = image_client
+            .pull_image(
+                &layout,
+                bundle_dir.path(),
+                &None,
+                &Some("provider:attestation-agent:sample_kbc::null"),
+            )
+            .await;
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+
+        let result = image_client
+            .import_archive(&work_dir.path().join("busybox.tar"), bundle_dir.path())
+            .await;
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_import_archive() {
+        test_utils::skip_if_not_root!();
+
+        let work_dir = tempfile::tempdir().unwrap();
+        let archive_dir = tempfile::tempdir().unwrap();
+        let archive = archive_dir.path().join("busybox.tar");
+
+        let layer = layer_tarball(&[("foo", Some("foo"))]);
+
+        let config = format!(
+            r#"{{"architecture": "amd64", "os": "linux", "rootfs": {{"type": "layers", "diff_ids": ["sha256:{:x}"]}}}}"#,
+            sha2::Sha256::digest(&layer)
+        );
+        let manifest = r#"[{"Config": "config.json", "Layers": ["abc/layer.tar"]}]"#;
+
+        let mut ar = tar::Builder::new(fs::File::create(&archive).unwrap());
+        for (path, data) in [
+            ("manifest.json", manifest.as_bytes()),
+            ("config.json", config.as_bytes()),
+            ("abc/layer.tar", layer.as_slice()),
+        ]
+        .iter()
+        {
+            append_file(&mut ar, path, data);
+        }
+        ar.finish().unwrap();
+
+        std::env::set_var("CC_IMAGE_WORK_DIR", &work_dir.path());
+        let image_client = ImageClient::default();
+
+        let bundle_dir = tempfile::tempdir().unwrap();
+        let image_id = image_client
+            .import_archive(&archive, bundle_dir.path())
+            .await
+            .unwrap();
+        assert!(bundle_dir.path().join("config.json").exists());
+        assert!(bundle_dir.path().join(BUNDLE_ROOTFS).join("foo").exists());
+
+        let meta_store = image_client.meta_store.lock().await;
+        let image = meta_store.image_db.get(&image_id).unwrap();
+        assert_eq!(
+            image.reference,
+            format!("docker-archive:{}", archive.display())
+        );
+        assert_eq!(meta_store.layer_db.len(), 1);
+        drop(meta_store);
+
+        // The archive is read in place.
+        assert!(archive.exists());
+        image_client
+            .unmount_bundle(bundle_dir.path())
+            .await
+            .unwrap();
+    }
+
+    #[cfg(feature = "overlay_feature")]
+    #[tokio::test]
+    async fn test_pull_image_id_mappings() {
+        test_utils::skip_if_not_root!();
+
+        let registry = TestRegistry::start().await;
+        let layer = layer_tarball(&[("foo", Some("foo"))]);
+        registry.push_image("foo", "latest", &layer);
+
+        let work_dir = tempfile::tempdir().unwrap();
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": work_dir.path(),
+            "registries": [{
+                "prefix": "example.com",
+                "mirrors": [{"location": registry.host(), "insecure": true}]
+            }]
+        }))
+        .unwrap();
+        let mut image_client = ImageClient::new(config).unwrap();
+
+        let mut bundles = Vec::new();
+        for host_id in [100000, 200000] {
+            let mapping = IdMapping {
+                container_id: 0,
+                host_id,
+                size: 65536,
+            };
+            image_client.config.id_mappings = IdMappings {
+                uid_mappings: vec![mapping.clone()],
+                gid_mappings: vec![mapping],
+            };
+
+            let bundle_dir = tempfile::tempdir().unwrap();
+            image_client
+                .pull_image("example.com/foo:latest", bundle_dir.path(), &None, &None)
+                .await
+                .unwrap();
+
+            // The cached image of the other mappings is never reused.
+            let foo = fs::metadata(bundle_dir.path().join(BUNDLE_ROOTFS).join("foo")).unwrap();
+            assert_eq!((foo.uid(), foo.gid()), (host_id, host_id));
+            bundles.push(bundle_dir);
+        }
+
+        let meta_store = image_client.meta_store.lock().await;
+        assert_eq!(meta_store.image_db.len(), 1);
+        assert_eq!(meta_store.layer_db.len(), 2);
+        drop(meta_store);
+
+        // The cached image is still checked against the platform.
+        image_client.config.platform = "linux/s390x".parse().unwrap();
+        let bundle_dir = tempfile::tempdir().unwrap();
+        let result = image_client
+            .pull_image("example.com/foo:latest", bundle_dir.path(), &None, &None)
+            .await;
+        assert!(result.is_err());
+        assert!(!bundle_dir.path().join(BUNDLE_ROOTFS).join("foo").exists());
+
+        for bundle_dir in bundles.iter() {
+            image_client
+                .unmount_bundle(bundle_dir.path())
+                .await
+                .unwrap();
+        }
+    }
 
     #[tokio::test]
     async fn test_pull_image() {
@@ -276,7 +1250,7 @@ mod tests {
             // "releases-docker.jfrog.io/reg2/busybox:1.33.1"
         ];
 
-        let mut image_client = ImageClient::default();
+        let image_client = ImageClient::default();
         for image in oci_images.iter() {
             let bundle_dir = tempfile::tempdir().unwrap();
 
@@ -296,7 +1270,7 @@ mod tests {
 
         let image = "mcr.microsoft.com/hello-world";
 
-        let mut image_client = ImageClient::default();
+        let image_client = ImageClient::default();
 
         let bundle1_dir = tempfile::tempdir().unwrap();
         assert!(image_client
@@ -321,5 +1295,17 @@ mod tests {
 
         // Assert that image is pulled only once.
         assert_eq!(image_client.meta_store.lock().await.image_db.len(), 1);
+
+        // Assert that the metadata survives a restart of the client.
+        assert!(work_dir.path().join(METAFILE).exists());
+        let image_client = ImageClient::default();
+        assert_eq!(image_client.meta_store.lock().await.image_db.len(), 1);
+
+        let bundle3_dir = tempfile::tempdir().unwrap();
+        assert!(image_client
+            .pull_image(image, bundle3_dir.path(), &None, &None)
+            .await
+            .is_ok());
+        assert!(bundle3_dir.path().join("rootfs").join("hello").exists());
     }
 }
@@ -5,12 +5,22 @@
 /// Environment macro for `image-rs` work dir.
 pub const CC_IMAGE_WORK_DIR: &str = "CC_IMAGE_WORK_DIR";
 
+pub mod auth;
 pub mod bundle;
 pub mod config;
 pub mod decoder;
 pub mod decrypt;
+pub mod digest;
+pub mod error;
+pub mod event;
+pub mod fetch;
 pub mod image;
 pub mod meta_store;
 pub mod pull;
+pub mod singleflight;
 pub mod snapshots;
+pub mod source;
+pub mod stream;
 pub mod unpack;
+
+pub use error::{Error, Result};
@@ -1,16 +1,17 @@
 use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::Write;
 use std::path::Path;
 
-use crate::image::{ImageMeta, LayerMeta};
+use crate::image::{BundleMeta, ImageMeta, LayerMeta};
 
 pub const METAFILE: &str = "meta_store.json";
 
 /// `image-rs` container metadata storage database.
-#[derive(Clone, Default, Deserialize, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, Debug)]
 pub struct MetaStore {
     // image_db holds map of image ID with image data.
     pub image_db: HashMap<String, ImageMeta>,
@@ -20,6 +21,10 @@ pub struct MetaStore {
 
     // snapshot_db holds map of snapshot with work dir index.
     pub snapshot_db: HashMap<String, usize>,
+
+    // bundle_db holds map of bundle dir with the mounted bundle rootfs.
+    #[serde(default)]
+    pub bundle_db: HashMap<String, BundleMeta>,
 }
 
 impl TryFrom<&Path> for MetaStore {
@@ -32,3 +37,96 @@ impl TryFrom<&Path> for MetaStore {
             .map_err(|e| anyhow!("failed to parse metastore file {}", e.to_string()))
     }
 }
+
+impl MetaStore {
+    /// Write `MetaStore` to a local file. The data is written to a temporary
+    /// file next to `path` first and then renamed over it, so a crash never
+    /// leaves a truncated metastore file behind.
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        let parent = path
+            .parent()
+            .ok_or_else(|| anyhow!("invalid metastore file path {:?}", path))?;
+        fs::create_dir_all(parent)?;
+
+        let data = serde_json::to_vec(self)
+            .map_err(|e| anyhow!("failed to serialize metastore {}", e.to_string()))?;
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let mut file = File::create(&tmp_path)
+            .map_err(|e| anyhow!("failed to create metastore file {}", e.to_string()))?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, path)
+            .map_err(|e| anyhow!("failed to save metastore file {}", e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshots::{MountPoint, SnapshotType};
+    use std::path::PathBuf;
+    use tempfile;
+
+    #[test]
+    fn test_meta_store_write_and_load() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let meta_file = tempdir.path().join("sub").join(METAFILE);
+
+        let mut meta_store = MetaStore::default();
+        meta_store.layer_db.insert(
+            "sha256:foo".to_string(),
+            LayerMeta {
+                compressed_digest: "sha256:foo".to_string(),
+                uncompressed_digest: "sha256:bar".to_string(),
+                store_path: "/layers/sha256_foo".to_string(),
+                ..Default::default()
+            },
+        );
+        meta_store.image_db.insert(
+            "sha256:baz".to_string(),
+            ImageMeta {
+                id: "sha256:baz".to_string(),
+                reference: "docker.io/library/busybox:latest".to_string(),
+                ..Default::default()
+            },
+        );
+        meta_store.snapshot_db.insert("overlay".to_string(), 3);
+        meta_store.bundle_db.insert(
+            "/run/bundle".to_string(),
+            BundleMeta {
+                image_id: "sha256:baz".to_string(),
+                snapshot: SnapshotType::Overlay,
+                mount_point: MountPoint {
+                    r#type: "overlay".to_string(),
+                    mount_path: PathBuf::from("/run/bundle/rootfs"),
+                    work_dir: PathBuf::from("/overlay/2"),
+                },
+            },
+        );
+
+        meta_store.write_to_file(&meta_file).unwrap();
+        assert!(meta_file.exists());
+        assert!(!meta_file.with_extension("json.tmp").exists());
+
+        let loaded = MetaStore::try_from(meta_file.as_path()).unwrap();
+        assert_eq!(loaded.layer_db, meta_store.layer_db);
+        assert_eq!(loaded.snapshot_db, meta_store.snapshot_db);
+        assert_eq!(loaded.bundle_db, meta_store.bundle_db);
+        assert_eq!(
+            loaded.image_db["sha256:baz"].reference,
+            "docker.io/library/busybox:latest"
+        );
+
+        // Overwrite the existing file.
+        meta_store.snapshot_db.insert("overlay".to_string(), 4);
+        meta_store.write_to_file(&meta_file).unwrap();
+        let loaded = MetaStore::try_from(meta_file.as_path()).unwrap();
+        assert_eq!(loaded.snapshot_db["overlay"], 4);
+    }
+}
@@ -2,100 +2,231 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{anyhow, Result};
 use futures_util::future;
 use oci_distribution::manifest::{OciDescriptor, OciImageManifest};
-use oci_distribution::{secrets::RegistryAuth, Client, Reference};
-use sha2::Digest;
 use std::convert::TryFrom;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{self, Read};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 
+use crate::config::ImageConfig;
 use crate::decoder::Compression;
 use crate::decrypt::Decryptor;
-use crate::image::LayerMeta;
+use crate::digest::{Digest, DigestHasher};
+use crate::error::{Error, Result};
+use crate::event::{emit, PullEvent, PullEventSender};
+use crate::image::{layer_key, LayerMeta};
 use crate::meta_store::MetaStore;
-use crate::unpack::unpack;
+use crate::singleflight::SingleFlight;
+use crate::source::{new_source, ImageSource};
+use crate::stream::{EofReader, HashReader};
+use crate::unpack::{unpack, IdMappings, UnpackLimits, UnpackOptions, WhiteoutFormat};
 
-const DIGEST_SHA256: &str = "sha256";
-const DIGEST_SHA512: &str = "sha512";
+/// The dir under the layers dir where the layers are unpacked. A layer is
+/// renamed to its store path only after it is completely unpacked and
+/// verified, so a layer dir is never partially unpacked.
+pub const STAGING_DIR: &str = ".staging";
 
-const ERR_NO_DECRYPT_CFG: &str = "decrypt_config is None";
-const ERR_BAD_UNCOMPRESSED_DIGEST: &str = "unsupported uncompressed digest format";
-const ERR_BAD_COMPRESSED_DIGEST: &str = "unsupported compressed digest format";
+/// verify_blob hashes the whole blob read from reader with the algorithm
+/// of the layer digest, and checks the blob against the digest and size
+/// of the layer descriptor.
+pub fn verify_blob<R: Read>(reader: R, layer: &OciDescriptor) -> Result<()> {
+    let expected = Digest::try_from(layer.digest.as_str())?;
 
-/// The PullClient connects to remote OCI registry, pulls the container image,
-/// and save the image layers under data_dir and return the layer meta info.
-pub struct PullClient {
-    /// `oci-distribuion` client to talk with remote OCI registry.
-    pub client: Client,
+    let mut reader = HashReader::new(reader, DigestHasher::new(expected.algorithm()));
+    let size = io::copy(&mut reader, &mut io::sink())?;
+    if layer.size < 0 || size != layer.size as u64 {
+        return Err(Error::SizeMismatch {
+            digest: layer.digest.clone(),
+            expected: layer.size,
+            actual: size,
+        });
+    }
+
+    let digest = reader.digest();
+    if digest != expected {
+        return Err(Error::DigestMismatch {
+            expected: layer.digest.clone(),
+            actual: digest.to_string(),
+        });
+    }
+
+    Ok(())
+}
 
-    /// OCI registry auth info.
-    pub auth: RegistryAuth,
+/// The PullClient pulls the container image from an image source, like a
+/// remote OCI registry or a local OCI image layout, and save the image
+/// layers under data_dir and return the layer meta info.
+pub struct PullClient {
+    /// The image reference to pull, as it is given.
+    pub image: String,
 
-    /// OCI image reference.
-    pub reference: Reference,
+    /// The source to pull the image manifest, config and layers from.
+    pub source: Box<dyn ImageSource>,
 
     /// OCI image layer data store dir.
     pub data_dir: PathBuf,
+
+    /// The permits to download a layer blob. The permits are of this
+    /// client, so the concurrent pulls of other clients are not counted.
+    pub download_permits: Semaphore,
+
+    /// The permits to decrypt, decompress and unpack a layer, of this
+    /// client like the download permits.
+    pub unpack_permits: Semaphore,
+
+    /// The sender to report the pull progress to.
+    pub event_sender: Option<PullEventSender>,
+
+    /// The mappings to shift the file ownership of the layers with.
+    pub id_mappings: IdMappings,
+
+    /// The limits of the layer tarballs to unpack.
+    pub unpack_limits: UnpackLimits,
 }
 
 impl PullClient {
     /// Constructs a new PullClient struct with provided image info,
-    /// data store dir and optional remote registry auth info.
-    pub fn new(image: &str, data_dir: &Path, auth_info: &Option<&str>) -> Result<PullClient> {
-        let mut auth = RegistryAuth::Anonymous;
-        if let Some(auth_info) = auth_info {
-            if let Some((username, password)) = auth_info.split_once(':') {
-                auth = RegistryAuth::Basic(username.to_string(), password.to_string());
-            } else {
-                return Err(anyhow!("Invalid authentication info ({:?})", auth_info));
-            }
-        }
+    /// `image-rs` config and optional remote registry auth info.
+    /// An image reference with `oci:` transport is read from a local
+    /// OCI image layout, others are pulled from registries.
+    /// The layers are stored under the `layers` dir of config work_dir.
+    /// It may block on a credential helper of the auth file of config.
+    pub fn new(image: &str, config: &ImageConfig, auth_info: &Option<&str>) -> Result<PullClient> {
+        let mut client = PullClient::with_source(new_source(image, config, auth_info)?, config)?;
+        client.image = image.to_string();
 
-        let reference = Reference::try_from(image)?;
-        let client = Client::default();
+        Ok(client)
+    }
+
+    /// Constructs a new PullClient struct pulling from the given image
+    /// source, like an image archive.
+    pub fn with_source(source: Box<dyn ImageSource>, config: &ImageConfig) -> Result<PullClient> {
+        if config.max_concurrent_downloads == 0 || config.max_concurrent_unpacks == 0 {
+            return Err(Error::InvalidConfig(
+                "max_concurrent_downloads and max_concurrent_unpacks must be greater than 0"
+                    .to_string(),
+            ));
+        }
 
         Ok(PullClient {
-            client,
-            auth,
-            reference,
-            data_dir: data_dir.to_path_buf(),
+            image: source.reference(),
+            source,
+            data_dir: config.work_dir.join("layers"),
+            download_permits: Semaphore::new(config.max_concurrent_downloads),
+            unpack_permits: Semaphore::new(config.max_concurrent_unpacks),
+            event_sender: config.event_sender.clone(),
+            id_mappings: config.id_mappings.clone(),
+            unpack_limits: config.unpack_limits.clone(),
         })
     }
 
-    /// pull_manifest pulls an image manifest and config data.
+    /// pull_manifest pulls an image manifest and config data. The image
+    /// config is verified against the config digest, which is the image id.
     pub async fn pull_manifest(&mut self) -> Result<(OciImageManifest, String, String)> {
-        self.client
-            .pull_manifest_and_config(&self.reference, &self.auth)
-            .await
+        let manifest = self.source.pull_manifest().await?;
+
+        Digest::try_from(manifest.1.as_str())?;
+        Digest::try_from(manifest.0.config.digest.as_str())?.verify(manifest.2.as_bytes())?;
+
+        emit(
+            &self.event_sender,
+            PullEvent::ManifestResolved {
+                image: self.image.clone(),
+                endpoint: self.source.reference(),
+                digest: manifest.1.clone(),
+            },
+        );
+
+        Ok(manifest)
     }
 
     /// pull_layers pulls an image layers and do ondemand decrypt/decompress.
-    /// It returns the layer metadata for layer db to track.
+    /// The whiteouts in the layers are unpacked in the given whiteout format.
+    /// Each layer is recorded in the layer db of meta_store as soon as it
+    /// is unpacked, so the finished layers are kept if another layer fails.
+    /// The downloads and the unpacks are bounded by the concurrency limits
+    /// of the config, and the layer metadata is returned in the order of
+    /// layer_descs for layer db to track.
+    /// A registry blob is downloaded to the download dir of the config and
+    /// verified before it is decrypted, so the dir needs room for the
+    /// blobs being downloaded and unpacked, see `ImageConfig::download_dir`.
+    /// A layer shared with another pull is fetched and unpacked by only one
+    /// of the pulls, the others wait in flights and reuse its layer meta.
+    /// A layer unpacked in another whiteout format or with other id mappings
+    /// is never reused.
     pub async fn pull_layers(
         &self,
         layer_descs: Vec<OciDescriptor>,
         diff_ids: &[String],
         decrypt_config: &Option<&str>,
+        whiteout: WhiteoutFormat,
         meta_store: Arc<Mutex<MetaStore>>,
+        flights: &SingleFlight,
     ) -> Result<Vec<LayerMeta>> {
         let layer_metas = layer_descs.into_iter().enumerate().map(|(i, layer)| {
-            let client = &self.client;
-            let reference = &self.reference;
             let ms = meta_store.clone();
 
             async move {
-                let mut layer_data: Vec<u8> = Vec::new();
+                let key = layer_key(&layer.digest, whiteout, &self.id_mappings);
+                let _flight = flights.acquire(&key).await;
+                if let Some(layer_meta) = ms.lock().await.layer_db.get(&key) {
+                    return Ok(layer_meta.clone());
+                }
+
+                let progress = |downloaded: u64| {
+                    emit(
+                        &self.event_sender,
+                        PullEvent::LayerDownloading {
+                            digest: layer.digest.clone(),
+                            downloaded,
+                            total: layer.size.max(0) as u64,
+                        },
+                    )
+                };
+                let blob = {
+                    let _permit = self.download_permits.acquire().await?;
+                    self.source.fetch_blob(&layer, &progress).await?
+                };
 
-                client
-                    .pull_blob(reference, &layer.digest, &mut layer_data)
-                    .await?;
+                // The blob is verified as a whole before it is decrypted
+                // or decompressed.
+                let layer_meta = async {
+                    let _permit = self.unpack_permits.acquire().await?;
 
-                self.handle_layer(layer, diff_ids[i].clone(), decrypt_config, layer_data, ms)
+                    let blob_reader = blob.open()?;
+                    let descriptor = layer.clone();
+                    tokio::task::spawn_blocking(move || verify_blob(blob_reader, &descriptor))
+                        .await??;
+
+                    let layer_meta = self
+                        .handle_layer(
+                            layer,
+                            diff_ids[i].clone(),
+                            decrypt_config,
+                            whiteout,
+                            blob.open()?,
+                        )
+                        .await?;
+
+                    Ok::<_, anyhow::Error>(layer_meta)
+                }
+                .await;
+
+                // A blob with a bad digest has to be downloaded again anyway.
+                if blob.temporary {
+                    tokio::fs::remove_file(&blob.path).await?;
+                }
+                let layer_meta = layer_meta?;
+
+                ms.lock()
                     .await
+                    .layer_db
+                    .insert(layer_meta.key(), layer_meta.clone());
+
+                Ok::<_, anyhow::Error>(layer_meta)
             }
         });
 
@@ -104,103 +235,163 @@ impl PullClient {
         Ok(layer_metas)
     }
 
-    async fn handle_layer(
+    /// handle_layer streams the verified layer blob read from layer_reader
+    /// through decryption, decompression and unpack, while checking the
+    /// uncompressed digest (diff_id) of the layer.
+    async fn handle_layer<R>(
         &self,
         layer: OciDescriptor,
         diff_id: String,
         decrypt_config: &Option<&str>,
-        layer_data: Vec<u8>,
-        ms: Arc<Mutex<MetaStore>>,
-    ) -> Result<LayerMeta> {
-        let mut out: Vec<u8> = Vec::new();
-        let plaintext_layer: Vec<u8>;
-
+        whiteout: WhiteoutFormat,
+        mut layer_reader: R,
+    ) -> Result<LayerMeta>
+    where
+        R: Read + Send + 'static,
+    {
         let mut layer_meta = LayerMeta::default();
-        let mut media_type_str: &str = layer.media_type.as_str();
 
         let decryptor = Decryptor::from_media_type(&layer.media_type);
 
-        if decryptor.is_encrypted() {
-            if let Some(dc) = decrypt_config {
-                plaintext_layer = decryptor
-                    .get_plaintext_layer(&layer, layer_data, dc)
-                    .await?;
-                media_type_str = decryptor.media_type.as_str();
-                layer_meta.encrypted = true;
-            } else {
-                return Err(anyhow!(ERR_NO_DECRYPT_CFG));
+        let media_type_str = if decryptor.is_encrypted() {
+            if decrypt_config.is_none() {
+                return Err(Error::DecryptionKeyUnavailable(layer.digest.clone()));
             }
+            layer_meta.encrypted = true;
+            decryptor.media_type.as_str()
         } else {
-            plaintext_layer = layer_data;
-        }
+            layer.media_type.as_str()
+        };
+
+        layer_meta.decoder = Compression::try_from(media_type_str)?;
+
+        let expected_diff_id = Digest::try_from(diff_id.as_str())?;
+        let diff_hasher = DigestHasher::new(expected_diff_id.algorithm());
 
-        let layer_db = &ms.lock().await.layer_db;
+        let layer_name = layer_key(&layer.digest, whiteout, &self.id_mappings).replace(':', "_");
+        let destination = self.data_dir.join(&layer_name);
+        let staging = self.data_dir.join(STAGING_DIR).join(&layer_name);
 
-        if let Some(layer_meta) = layer_db.get(&layer.digest) {
-            return Ok(layer_meta.clone());
+        // The staging dir of an interrupted unpack of the same layer.
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
         }
 
-        layer_meta.decoder = Compression::try_from(media_type_str)?;
+        let layer_digest = layer.digest.clone();
+        let decrypt_config = decrypt_config.map(|dc| dc.to_string());
+        let decoder = layer_meta.decoder;
+        let encrypted = layer_meta.encrypted;
+        let unpack_destination = staging.clone();
+        let options = UnpackOptions {
+            whiteout,
+            id_mappings: self.id_mappings.clone(),
+            limits: self.unpack_limits.clone(),
+        };
 
-        if layer_meta.decoder == Compression::Uncompressed {
-            let digest = if diff_id.starts_with(DIGEST_SHA256) {
-                format!(
-                    "{}:{:x}",
-                    DIGEST_SHA256,
-                    sha2::Sha256::digest(plaintext_layer.as_slice())
-                )
-            } else if diff_id.starts_with(DIGEST_SHA512) {
-                format!(
-                    "{}:{:x}",
-                    DIGEST_SHA512,
-                    sha2::Sha512::digest(plaintext_layer.as_slice())
-                )
-            } else {
-                return Err(anyhow!("{}: {:?}", ERR_BAD_UNCOMPRESSED_DIGEST, diff_id));
+        let event_sender = self.event_sender.clone();
+
+        // Decryption, decompression and unpack are blocking operations, the
+        // whole pipeline runs on a blocking thread and streams the layer
+        // blob through all of them.
+        let handler = tokio::task::spawn_blocking(move || -> Result<Digest> {
+            // A plain layer is passed through, even with a decrypt config.
+            let plaintext_reader: Box<dyn Read + '_> = match &decrypt_config {
+                Some(dc) if encrypted => {
+                    decryptor.get_plaintext_layer(&layer, &mut layer_reader, dc)?
+                }
+                _ => Box::new(&mut layer_reader),
             };
 
-            layer_meta.uncompressed_digest = digest.clone();
-            layer_meta.compressed_digest = digest;
-        } else {
-            layer_meta.compressed_digest = layer.digest.clone();
-            layer_meta
-                .decoder
-                .decompress(plaintext_layer.as_slice(), &mut out)?;
-
-            if diff_id.starts_with(DIGEST_SHA256) {
-                layer_meta.uncompressed_digest =
-                    format!("{}:{:x}", DIGEST_SHA256, sha2::Sha256::digest(&out));
-            } else if diff_id.starts_with(DIGEST_SHA512) {
-                layer_meta.uncompressed_digest =
-                    format!("{}:{:x}", DIGEST_SHA512, sha2::Sha512::digest(&out));
-            } else {
-                return Err(anyhow!("{}: {:?}", ERR_BAD_COMPRESSED_DIGEST, diff_id));
-            }
-        }
+            // The layer is decrypted and decompressed once the plaintext and
+            // the tar are read to the end, while it is being unpacked.
+            let mut plaintext_reader = EofReader::new(plaintext_reader, || {
+                if encrypted {
+                    let event = PullEvent::LayerDecrypted {
+                        digest: layer.digest.clone(),
+                    };
+                    emit(&event_sender, event);
+                }
+            });
 
-        // uncompressed digest should equal to the diff_ids in image_config.
-        if layer_meta.uncompressed_digest != diff_id {
-            return Err(anyhow!(
-                "unequal uncompressed digest {:?} config diff_id {:?}",
-                layer_meta.uncompressed_digest,
-                diff_id
-            ));
-        }
+            let uncompressed_digest = {
+                let tar_reader = EofReader::new(decoder.decoder(&mut plaintext_reader)?, || {
+                    if decoder != Compression::Uncompressed {
+                        let event = PullEvent::LayerDecompressed {
+                            digest: layer.digest.clone(),
+                        };
+                        emit(&event_sender, event);
+                    }
+                });
+                let mut tar_reader = HashReader::new(tar_reader, diff_hasher);
+                unpack(&mut tar_reader, &unpack_destination, &options)?;
 
-        let store_path = format!(
-            "{}/{}",
-            self.data_dir.display(),
-            &layer.digest.to_string().replace(':', "_")
-        );
+                // The tar reader may stop before the end of the archive, drain
+                // the remaining data so that the digests cover the whole stream.
+                io::copy(&mut tar_reader, &mut io::sink())?;
+                tar_reader.digest()
+            };
 
-        let destination = Path::new(&store_path);
+            // The decompressor may stop at the end of the compressed data.
+            io::copy(&mut plaintext_reader, &mut io::sink())?;
 
-        if let Err(e) = unpack(out, destination) {
-            fs::remove_dir_all(destination)?;
-            return Err(e);
-        }
+            Ok(uncompressed_digest)
+        });
 
+        let result = handler
+            .await
+            .map_err(anyhow::Error::from)?
+            .and_then(|uncompressed_digest| {
+                // uncompressed digest should equal to the diff_ids in image_config.
+                if uncompressed_digest != expected_diff_id {
+                    return Err(Error::DigestMismatch {
+                        expected: diff_id,
+                        actual: uncompressed_digest.to_string(),
+                    });
+                }
+
+                Ok(uncompressed_digest.to_string())
+            })
+            .and_then(|uncompressed_digest| {
+                // A layer dir which is not recorded in the meta store, left
+                // behind by a crash after the rename, is replaced.
+                if destination.exists() {
+                    fs::remove_dir_all(&destination)?;
+                }
+                fs::rename(&staging, &destination)?;
+
+                Ok(uncompressed_digest)
+            });
+
+        let uncompressed_digest = match result {
+            Ok(digest) => digest,
+            Err(e) => {
+                if staging.exists() {
+                    fs::remove_dir_all(&staging)?;
+                }
+                return Err(e);
+            }
+        };
+
+        // The layer is unpacked along with the verification, but it is
+        // only usable once both digests are verified.
+        emit(
+            &self.event_sender,
+            PullEvent::LayerVerified {
+                digest: layer_digest.clone(),
+            },
+        );
+        emit(
+            &self.event_sender,
+            PullEvent::LayerUnpacked {
+                digest: layer_digest.clone(),
+            },
+        );
+
+        layer_meta.compressed_digest = layer_digest;
+        layer_meta.uncompressed_digest = uncompressed_digest;
         layer_meta.store_path = destination.display().to_string();
+        layer_meta.id_mappings = self.id_mappings.clone();
+        layer_meta.whiteout = whiteout;
 
         Ok(layer_meta)
     }
@@ -209,15 +400,20 @@ impl PullClient {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::decoder::ERR_BAD_MEDIA_TYPE;
     use flate2::write::GzEncoder;
     use oci_distribution::manifest::IMAGE_CONFIG_MEDIA_TYPE;
     use oci_spec::image::{ImageConfiguration, MediaType};
     use ocicrypt_rs::spec::MEDIA_TYPE_LAYER_ENC;
+    use sha2::Digest;
     use std::io::Write;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
     use tempfile;
 
     use test_utils::assert_result;
+    use test_utils::layer::layer_tarball;
+    use test_utils::registry::{Fault, TestRegistry, LAYER_MEDIA_TYPE};
 
     #[tokio::test]
     async fn test_pull_client() {
@@ -229,7 +425,11 @@ mod tests {
 
         for image in oci_images.iter() {
             let tempdir = tempfile::tempdir().unwrap();
-            let mut client = PullClient::new(image, tempdir.path(), &None).unwrap();
+            let config = ImageConfig {
+                work_dir: tempdir.path().to_path_buf(),
+                ..Default::default()
+            };
+            let mut client = PullClient::new(image, &config, &None).unwrap();
             let (image_manifest, _image_digest, image_config) =
                 client.pull_manifest().await.unwrap();
 
@@ -250,7 +450,9 @@ mod tests {
                     image_manifest.layers.clone(),
                     diff_ids,
                     &Some(decrypt_config.to_str().unwrap()),
-                    Arc::new(Mutex::new(MetaStore::default()))
+                    WhiteoutFormat::Oci,
+                    Arc::new(Mutex::new(MetaStore::default())),
+                    &SingleFlight::default(),
                 )
                 .await
                 .is_ok());
@@ -261,8 +463,6 @@ mod tests {
     async fn test_handle_layer() {
         let oci_image = "docker.io/arronwang/busybox_gzip";
 
-        let bad_media_err = format!("{}: {}", ERR_BAD_MEDIA_TYPE, IMAGE_CONFIG_MEDIA_TYPE);
-
         let empty_diff_id = "";
 
         let default_layer = OciDescriptor::default();
@@ -289,13 +489,14 @@ mod tests {
         };
 
         let tempdir = tempfile::tempdir().unwrap();
-        let mut client = PullClient::new(oci_image, tempdir.path(), &None).unwrap();
+        let config = ImageConfig {
+            work_dir: tempdir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let mut client = PullClient::new(oci_image, &config, &None).unwrap();
 
         let (_image_manifest, _image_digest, _image_config) = client.pull_manifest().await.unwrap();
 
-        let meta_store = MetaStore::default();
-        let ms = Arc::new(Mutex::new(meta_store));
-
         #[derive(Debug)]
         struct TestData<'a> {
             layer: OciDescriptor,
@@ -311,43 +512,39 @@ mod tests {
                 diff_id: empty_diff_id,
                 decrypt_config: None,
                 layer_data: Vec::<u8>::new(),
-                result: Err(anyhow!(bad_media_err.clone())),
+                result: Err(Error::UnsupportedMediaType(
+                    IMAGE_CONFIG_MEDIA_TYPE.to_string(),
+                )),
             },
             TestData {
                 layer: default_layer.clone(),
                 diff_id: "foo",
                 decrypt_config: None,
                 layer_data: Vec::<u8>::new(),
-                result: Err(anyhow!(bad_media_err.clone())),
+                result: Err(Error::UnsupportedMediaType(
+                    IMAGE_CONFIG_MEDIA_TYPE.to_string(),
+                )),
             },
             TestData {
-                layer: encrypted_layer,
+                layer: encrypted_layer.clone(),
                 diff_id: empty_diff_id,
                 decrypt_config: None,
                 layer_data: Vec::<u8>::new(),
-                result: Err(anyhow!(ERR_NO_DECRYPT_CFG)),
+                result: Err(Error::DecryptionKeyUnavailable(encrypted_layer.digest)),
             },
             TestData {
                 layer: uncompressed_layer,
                 diff_id: empty_diff_id,
                 decrypt_config: None,
                 layer_data: Vec::<u8>::new(),
-                result: Err(anyhow!(
-                    "{}: {:?}",
-                    ERR_BAD_UNCOMPRESSED_DIGEST,
-                    empty_diff_id
-                )),
+                result: Err(Error::UnsupportedDigest(empty_diff_id.to_string())),
             },
             TestData {
                 layer: compressed_layer,
                 diff_id: empty_diff_id,
                 decrypt_config: None,
                 layer_data: gzip_compressed_bytes,
-                result: Err(anyhow!(
-                    "{}: {:?}",
-                    ERR_BAD_COMPRESSED_DIGEST,
-                    empty_diff_id
-                )),
+                result: Err(Error::UnsupportedDigest(empty_diff_id.to_string())),
             },
         ];
 
@@ -359,8 +556,8 @@ mod tests {
                     d.layer.clone(),
                     d.diff_id.to_string(),
                     &d.decrypt_config,
-                    d.layer_data.clone(),
-                    ms.clone(),
+                    WhiteoutFormat::Oci,
+                    io::Cursor::new(d.layer_data.clone()),
                 )
                 .await;
 
@@ -369,4 +566,655 @@ mod tests {
             assert_result!(d.result, result, msg);
         }
     }
+
+    #[tokio::test]
+    async fn test_handle_layer_stream() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let data = "file data";
+        let tar_bytes = layer_tarball(&[("file.txt", Some(data))]);
+
+        let mut gzip_encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip_encoder.write_all(&tar_bytes).unwrap();
+        let gzip_bytes = gzip_encoder.finish().unwrap();
+
+        let diff_id = format!("sha256:{:x}", sha2::Sha256::digest(&tar_bytes));
+        let layer = OciDescriptor {
+            media_type: MediaType::ImageLayerGzip.to_string(),
+            digest: format!("sha256:{:x}", sha2::Sha256::digest(&gzip_bytes)),
+            size: gzip_bytes.len() as i64,
+            ..Default::default()
+        };
+
+        let config = ImageConfig {
+            work_dir: tempdir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let mut client = PullClient::new("busybox", &config, &None).unwrap();
+
+        // A mismatched diff_id fails the layer and cleans up the unpacked data.
+        let bad_diff_id = format!("sha256:{:x}", sha2::Sha256::digest(b"foo"));
+        let result = client
+            .handle_layer(
+                layer.clone(),
+                bad_diff_id.clone(),
+                &None,
+                WhiteoutFormat::Oci,
+                io::Cursor::new(gzip_bytes.clone()),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::DigestMismatch { expected, actual })
+                if expected == bad_diff_id && actual == diff_id
+        ));
+        let layer_name = layer.digest.replace(':', "_");
+        assert!(!client.data_dir.join(&layer_name).exists());
+        assert!(!client.data_dir.join(STAGING_DIR).join(&layer_name).exists());
+
+        // A plain layer is not decrypted even with a decrypt config.
+        let layer_meta = client
+            .handle_layer(
+                layer.clone(),
+                diff_id.clone(),
+                &Some("provider:attestation-agent:sample_kbc::null"),
+                WhiteoutFormat::Oci,
+                io::Cursor::new(gzip_bytes.clone()),
+            )
+            .await
+            .unwrap();
+        assert!(!layer_meta.encrypted);
+        assert_eq!(layer_meta.uncompressed_digest, diff_id);
+        let store_path = Path::new(&layer_meta.store_path);
+        assert_eq!(
+            fs::read_to_string(store_path.join("file.txt")).unwrap(),
+            data
+        );
+
+        // A partially unpacked layer of a crash is replaced.
+        let stale_file = client
+            .data_dir
+            .join(STAGING_DIR)
+            .join(&layer_name)
+            .join("stale");
+        fs::create_dir_all(stale_file.parent().unwrap()).unwrap();
+        fs::write(&stale_file, "stale").unwrap();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        client.event_sender = Some(sender);
+        let layer_meta = client
+            .handle_layer(
+                layer.clone(),
+                diff_id.clone(),
+                &Some("provider:attestation-agent:sample_kbc::null"),
+                WhiteoutFormat::Oci,
+                io::Cursor::new(gzip_bytes),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(layer_meta.decoder, Compression::Gzip);
+        assert_eq!(layer_meta.compressed_digest, layer.digest);
+        assert_eq!(layer_meta.uncompressed_digest, diff_id);
+
+        let store_path = Path::new(&layer_meta.store_path);
+        assert_eq!(store_path, client.data_dir.join(&layer_name));
+        assert_eq!(
+            fs::read_to_string(store_path.join("file.txt")).unwrap(),
+            data
+        );
+        assert!(!store_path.join("stale").exists());
+        assert!(!client.data_dir.join(STAGING_DIR).join(&layer_name).exists());
+
+        // The plain layer is decompressed before it is verified and unpacked,
+        // it is not reported as decrypted.
+        drop(client);
+        let mut events = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+        let digest = layer.digest;
+        assert_eq!(
+            events,
+            [
+                PullEvent::LayerDecompressed {
+                    digest: digest.clone()
+                },
+                PullEvent::LayerVerified {
+                    digest: digest.clone()
+                },
+                PullEvent::LayerUnpacked { digest },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_blob() {
+        let data = b"This is some text!".to_vec();
+        let layer = OciDescriptor {
+            media_type: MediaType::ImageLayerGzip.to_string(),
+            digest: format!("sha256:{:x}", sha2::Sha256::digest(&data)),
+            size: data.len() as i64,
+            ..Default::default()
+        };
+        assert!(verify_blob(data.as_slice(), &layer).is_ok());
+
+        let sha512_layer = OciDescriptor {
+            digest: format!("sha512:{:x}", sha2::Sha512::digest(&data)),
+            ..layer.clone()
+        };
+        assert!(verify_blob(data.as_slice(), &sha512_layer).is_ok());
+
+        let mut tampered = data.clone();
+        tampered[0] = b't';
+        let bad_digest = format!("sha256:{:x}", sha2::Sha256::digest(&tampered));
+
+        let tests = [
+            (
+                data[1..].to_vec(),
+                layer.clone(),
+                Error::SizeMismatch {
+                    digest: layer.digest.clone(),
+                    expected: layer.size,
+                    actual: data.len() as u64 - 1,
+                },
+            ),
+            (
+                tampered,
+                layer.clone(),
+                Error::DigestMismatch {
+                    expected: layer.digest.clone(),
+                    actual: bad_digest,
+                },
+            ),
+            (
+                data.clone(),
+                OciDescriptor {
+                    digest: "md5:foo".to_string(),
+                    ..layer.clone()
+                },
+                Error::UnsupportedDigest("md5:foo".to_string()),
+            ),
+        ];
+
+        for (data, layer, err) in tests.iter() {
+            let result = verify_blob(data.as_slice(), layer);
+            assert_eq!(format!("{:?}", result.unwrap_err()), format!("{:?}", err));
+        }
+    }
+
+    // Push a single layer image with a "file.txt" of the given data to the
+    // test registry, and return the layer descriptor.
+    fn push_test_image(
+        registry: &TestRegistry,
+        repository: &str,
+        tag: &str,
+        data: &str,
+    ) -> OciDescriptor {
+        let tar_bytes = layer_tarball(&[("file.txt", Some(data))]);
+
+        OciDescriptor {
+            media_type: LAYER_MEDIA_TYPE.to_string(),
+            digest: registry.push_image(repository, tag, &tar_bytes),
+            size: tar_bytes.len() as i64,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_from_mirror() {
+        let registry = TestRegistry::start().await;
+        let layer = push_test_image(&registry, "library/busybox", "latest", "file data");
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": tempdir.path(),
+            "default_snapshot": "overlay",
+            "security_validate": false,
+            "retry": {"max_retries": 1, "initial_backoff_ms": 10},
+            "registries": [{
+                "prefix": "docker.io/library",
+                "mirrors": [
+                    // Nothing listens on port 1, so it falls back to the next mirror.
+                    {"location": "127.0.0.1:1/library", "insecure": true},
+                    {"location": format!("{}/library", registry.host()), "insecure": true}
+                ]
+            }]
+        }))
+        .unwrap();
+
+        let mut client = PullClient::new("busybox", &config, &None).unwrap();
+        assert_eq!(
+            client.source.reference(),
+            "docker.io/library/busybox:latest"
+        );
+
+        let (image_manifest, _image_digest, image_config) = client.pull_manifest().await.unwrap();
+        assert_eq!(
+            client.source.reference(),
+            format!("{}/library/busybox:latest", registry.host())
+        );
+        assert_eq!(image_manifest.layers, vec![layer.clone()]);
+
+        let image_config = ImageConfiguration::from_reader(image_config.as_bytes()).unwrap();
+        let layer_metas = client
+            .pull_layers(
+                image_manifest.layers.clone(),
+                image_config.rootfs().diff_ids(),
+                &None,
+                WhiteoutFormat::Oci,
+                Arc::new(Mutex::new(MetaStore::default())),
+                &SingleFlight::default(),
+            )
+            .await
+            .unwrap();
+
+        let file = Path::new(&layer_metas[0].store_path).join("file.txt");
+        assert_eq!(fs::read(file).unwrap(), b"file data");
+
+        let requests = registry.requests();
+        assert!(requests.contains(&"GET /v2/library/busybox/manifests/latest".to_string()));
+        assert!(requests.contains(&format!("GET /v2/library/busybox/blobs/{}", layer.digest)));
+    }
+
+    #[tokio::test]
+    async fn test_pull_layers_retry() {
+        let registry = TestRegistry::start().await;
+        let data = "x".repeat(64 * 1024);
+        let layer = push_test_image(&registry, "foo", "latest", &data);
+
+        let manifest_path = "/v2/foo/manifests/latest";
+        let blob_path = format!("/v2/foo/blobs/{}", layer.digest);
+        registry.add_fault(manifest_path, Fault::Status(503));
+        registry.add_fault(&blob_path, Fault::Status(500));
+        registry.add_fault(&blob_path, Fault::Truncate(1024));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": tempdir.path(),
+            "default_snapshot": "overlay",
+            "security_validate": false,
+            "retry": {"max_retries": 3, "initial_backoff_ms": 10},
+            "registries": [{
+                "prefix": "example.com",
+                "mirrors": [{"location": registry.host(), "insecure": true}]
+            }]
+        }))
+        .unwrap();
+
+        let mut client = PullClient::new("example.com/foo:latest", &config, &None).unwrap();
+        let (image_manifest, _image_digest, image_config) = client.pull_manifest().await.unwrap();
+        let image_config = ImageConfiguration::from_reader(image_config.as_bytes()).unwrap();
+
+        let meta_store = Arc::new(Mutex::new(MetaStore::default()));
+        let layer_metas = client
+            .pull_layers(
+                image_manifest.layers.clone(),
+                image_config.rootfs().diff_ids(),
+                &None,
+                WhiteoutFormat::Oci,
+                meta_store.clone(),
+                &SingleFlight::default(),
+            )
+            .await
+            .unwrap();
+
+        let file = Path::new(&layer_metas[0].store_path).join("file.txt");
+        assert_eq!(fs::read_to_string(file).unwrap(), data);
+        let key = layer_key(&layer.digest, WhiteoutFormat::Oci, &IdMappings::default());
+        assert_eq!(key, format!("{}@oci", layer.digest));
+        assert!(meta_store.lock().await.layer_db.contains_key(&key));
+
+        // The truncated download is resumed from where it broke.
+        let requests = registry.requests();
+        let blob_requests: Vec<&String> =
+            requests.iter().filter(|r| r.contains(&blob_path)).collect();
+        assert_eq!(
+            blob_requests,
+            vec![
+                &format!("GET {}", blob_path),
+                &format!("GET {}", blob_path),
+                &format!("GET {} bytes=1024-", blob_path),
+            ]
+        );
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|r| r.contains(manifest_path))
+                .count(),
+            2
+        );
+
+        // The downloaded blob is removed after it is unpacked.
+        let download_dir = tempdir.path().join("downloads");
+        assert_eq!(fs::read_dir(download_dir).unwrap().count(), 0);
+
+        // A missing blob is not retried.
+        let missing = OciDescriptor {
+            digest: format!("sha256:{:x}", sha2::Sha256::digest(b"missing")),
+            ..layer.clone()
+        };
+        let result = client
+            .pull_layers(
+                vec![missing.clone()],
+                &[missing.digest.clone()],
+                &None,
+                WhiteoutFormat::Oci,
+                meta_store,
+                &SingleFlight::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+        assert_eq!(
+            registry
+                .requests()
+                .iter()
+                .filter(|r| r.contains(&missing.digest))
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pull_layers_concurrency() {
+        let registry = TestRegistry::start().await;
+        let data = |i: usize| i.to_string().repeat(1 << 20);
+        let layers: Vec<OciDescriptor> = (0..5)
+            .map(|i| push_test_image(&registry, "foo", &i.to_string(), &data(i)))
+            .collect();
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": tempdir.path(),
+            "default_snapshot": "overlay",
+            "security_validate": false,
+            "max_concurrent_downloads": 2,
+            "max_concurrent_unpacks": 1,
+            "registries": [{
+                "prefix": "example.com",
+                "mirrors": [{"location": registry.host(), "insecure": true}]
+            }]
+        }))
+        .unwrap();
+
+        let mut client = PullClient::new("example.com/foo:0", &config, &None).unwrap();
+        client.pull_manifest().await.unwrap();
+        assert_eq!(client.download_permits.available_permits(), 2);
+        assert_eq!(client.unpack_permits.available_permits(), 1);
+
+        // The blobs are served slowly, so that the downloads overlap as
+        // much as the permits let them.
+        for layer in layers.iter() {
+            registry.add_fault(
+                &format!("/v2/foo/blobs/{}", layer.digest),
+                Fault::Delay(Duration::from_millis(100)),
+            );
+        }
+
+        // The unpacks in progress are the entries of the staging dir.
+        let staging_dir = client.data_dir.join(STAGING_DIR);
+        let done = Arc::new(AtomicBool::new(false));
+        let sampler = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut peak = 0;
+                while !done.load(Ordering::SeqCst) {
+                    if let Ok(entries) = fs::read_dir(&staging_dir) {
+                        peak = peak.max(entries.count());
+                    }
+                }
+                peak
+            })
+        };
+
+        // The layer of each test image is the tar of its tag.
+        let diff_ids: Vec<String> = layers.iter().map(|l| l.digest.clone()).collect();
+        let layer_metas = client
+            .pull_layers(
+                layers.clone(),
+                &diff_ids,
+                &None,
+                WhiteoutFormat::Oci,
+                Arc::new(Mutex::new(MetaStore::default())),
+                &SingleFlight::default(),
+            )
+            .await
+            .unwrap();
+
+        for (i, layer_meta) in layer_metas.iter().enumerate() {
+            assert_eq!(layer_meta.compressed_digest, layers[i].digest);
+            let file = Path::new(&layer_meta.store_path).join("file.txt");
+            assert_eq!(fs::read_to_string(file).unwrap(), data(i));
+        }
+        done.store(true, Ordering::SeqCst);
+        assert_eq!(sampler.join().unwrap(), 1);
+        assert_eq!(registry.peak_requests(), 2);
+        assert_eq!(client.download_permits.available_permits(), 2);
+        assert_eq!(client.unpack_permits.available_permits(), 1);
+
+        let config = ImageConfig {
+            max_concurrent_unpacks: 0,
+            ..config
+        };
+        assert!(matches!(
+            PullClient::new("example.com/foo:0", &config, &None),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pull_layers_single_flight() {
+        let registry = TestRegistry::start().await;
+        let layer = push_test_image(&registry, "foo", "latest", "shared");
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": tempdir.path(),
+            "default_snapshot": "overlay",
+            "security_validate": false,
+            "registries": [{
+                "prefix": "example.com",
+                "mirrors": [{"location": registry.host(), "insecure": true}]
+            }]
+        }))
+        .unwrap();
+
+        let meta_store = Arc::new(Mutex::new(MetaStore::default()));
+        let flights = SingleFlight::default();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let mut client = PullClient::new("example.com/foo:latest", &config, &None).unwrap();
+            client.pull_manifest().await.unwrap();
+            clients.push(client);
+        }
+
+        let diff_ids = [layer.digest.clone()];
+        let pulls = clients.iter().map(|client| {
+            client.pull_layers(
+                vec![layer.clone()],
+                &diff_ids,
+                &None,
+                WhiteoutFormat::Oci,
+                meta_store.clone(),
+                &flights,
+            )
+        });
+        let results = future::try_join_all(pulls).await.unwrap();
+        assert_eq!(results[0], results[1]);
+
+        // The shared layer is downloaded only once.
+        assert_eq!(
+            registry
+                .requests()
+                .iter()
+                .filter(|r| r.contains("/blobs/") && r.contains(&layer.digest))
+                .count(),
+            1
+        );
+        assert!(flights.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pull_events() {
+        let registry = TestRegistry::start().await;
+        let data = "x".repeat(3 * 1024 * 1024);
+        let layer = push_test_image(&registry, "foo", "latest", &data);
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": tempdir.path(),
+            "default_snapshot": "overlay",
+            "security_validate": false,
+            "registries": [{
+                "prefix": "example.com",
+                "mirrors": [{"location": registry.host(), "insecure": true}]
+            }]
+        }))
+        .unwrap();
+        config.event_sender = Some(sender);
+
+        let mut client = PullClient::new("example.com/foo:latest", &config, &None).unwrap();
+        let (image_manifest, image_digest, _image_config) = client.pull_manifest().await.unwrap();
+        client
+            .pull_layers(
+                image_manifest.layers.clone(),
+                &[layer.digest.clone()],
+                &None,
+                WhiteoutFormat::Oci,
+                Arc::new(Mutex::new(MetaStore::default())),
+                &SingleFlight::default(),
+            )
+            .await
+            .unwrap();
+        drop(client);
+        drop(config);
+
+        let mut events = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+
+        assert_eq!(
+            events[0],
+            PullEvent::ManifestResolved {
+                image: "example.com/foo:latest".to_string(),
+                endpoint: format!("{}/foo:latest", registry.host()),
+                digest: image_digest,
+            }
+        );
+
+        let total = layer.size as u64;
+        let downloaded: Vec<u64> = events
+            .iter()
+            .filter_map(|e| match e {
+                PullEvent::LayerDownloading {
+                    digest,
+                    downloaded,
+                    total: t,
+                } if digest == &layer.digest && *t == total => Some(*downloaded),
+                _ => None,
+            })
+            .collect();
+        assert!(downloaded.len() >= 3);
+        assert!(downloaded.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(downloaded.last(), Some(&total));
+
+        let digest = layer.digest.clone();
+        assert_eq!(
+            events[events.len() - 2..],
+            [
+                PullEvent::LayerVerified {
+                    digest: digest.clone()
+                },
+                PullEvent::LayerUnpacked { digest },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pull_from_layout() {
+        let layout = tempfile::tempdir().unwrap();
+        let blobs_dir = layout.path().join("blobs").join("sha256");
+        fs::create_dir_all(&blobs_dir).unwrap();
+        let add_blob = |data: &[u8]| {
+            let digest = format!("{:x}", sha2::Sha256::digest(data));
+            fs::write(blobs_dir.join(&digest), data).unwrap();
+            format!("sha256:{}", digest)
+        };
+
+        let data = "file data";
+        let tar_bytes = layer_tarball(&[("file.txt", Some(data))]);
+        let layer_digest = add_blob(&tar_bytes);
+
+        let image_config = format!(
+            r#"{{"architecture": "amd64", "os": "linux", "rootfs": {{"type": "layers", "diff_ids": ["{}"]}}}}"#,
+            layer_digest
+        );
+        let manifest = OciImageManifest {
+            config: OciDescriptor {
+                media_type: MediaType::ImageConfig.to_string(),
+                digest: add_blob(image_config.as_bytes()),
+                size: image_config.len() as i64,
+                ..Default::default()
+            },
+            layers: vec![OciDescriptor {
+                media_type: MediaType::ImageLayer.to_string(),
+                digest: layer_digest.clone(),
+                size: tar_bytes.len() as i64,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": MediaType::ImageManifest.to_string(),
+                "digest": add_blob(&manifest),
+                "size": manifest.len(),
+                "annotations": {"org.opencontainers.image.ref.name": "v1"}
+            }]
+        });
+        fs::write(layout.path().join("index.json"), index.to_string()).unwrap();
+        fs::write(
+            layout.path().join("oci-layout"),
+            r#"{"imageLayoutVersion": "1.0.0"}"#,
+        )
+        .unwrap();
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config = ImageConfig {
+            work_dir: tempdir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        let image = format!("oci:{}:v1", layout.path().display());
+        let mut client = PullClient::new(&image, &config, &None).unwrap();
+        let (image_manifest, _image_digest, image_config) = client.pull_manifest().await.unwrap();
+        assert_eq!(client.source.reference(), image);
+
+        let image_config = ImageConfiguration::from_reader(image_config.as_bytes()).unwrap();
+        let layer_metas = client
+            .pull_layers(
+                image_manifest.layers.clone(),
+                image_config.rootfs().diff_ids(),
+                &None,
+                WhiteoutFormat::Oci,
+                Arc::new(Mutex::new(MetaStore::default())),
+                &SingleFlight::default(),
+            )
+            .await
+            .unwrap();
+
+        let file = Path::new(&layer_metas[0].store_path).join("file.txt");
+        assert_eq!(fs::read_to_string(file).unwrap(), data);
+
+        // The blobs of the layout are left as they are.
+        assert!(blobs_dir.join(layer_digest.replace("sha256:", "")).exists());
+
+        let image = format!("oci:{}:v2", layout.path().display());
+        let mut client = PullClient::new(&image, &config, &None).unwrap();
+        assert!(client.pull_manifest().await.is_err());
+    }
 }
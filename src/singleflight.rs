@@ -0,0 +1,99 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// SingleFlight lets only one task work on a key, like an image or a
+/// layer digest, at a time. The other tasks of the same key wait until
+/// the flight lands, and then find its result in the meta store instead
+/// of doing the same work again. If the flight failed, the next task
+/// takes the key over and tries again.
+#[derive(Debug, Default)]
+pub struct SingleFlight {
+    flights: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+/// The guard of a flight, the key is released when it is dropped.
+pub struct Flight<'a> {
+    key: String,
+    guard: Option<OwnedMutexGuard<()>>,
+    single_flight: &'a SingleFlight,
+}
+
+impl SingleFlight {
+    /// Wait until no other task is working on key, and take it.
+    pub async fn acquire(&self, key: &str) -> Flight<'_> {
+        let lock = self
+            .flights
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+
+        Flight {
+            key: key.to_string(),
+            guard: Some(lock.lock_owned().await),
+            single_flight: self,
+        }
+    }
+
+    /// Get the keys which are being worked on or waited for.
+    pub fn keys(&self) -> HashSet<String> {
+        self.flights.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        let mut flights = self.single_flight.flights.lock().unwrap();
+        self.guard.take();
+
+        // Nobody else waits for the key when the map holds the last
+        // reference of the lock.
+        if let Some(lock) = flights.get(&self.key) {
+            if Arc::strong_count(lock) == 1 {
+                flights.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let single_flight = Arc::new(SingleFlight::default());
+        let running = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let single_flight = single_flight.clone();
+                let running = running.clone();
+                tokio::spawn(async move {
+                    let _flight = single_flight.acquire("foo").await;
+                    assert_eq!(running.fetch_add(1, Ordering::SeqCst), 0);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        // Another key is not blocked by the flights of foo.
+        let bar = single_flight.acquire("bar").await;
+        assert!(single_flight.keys().contains("bar"));
+        drop(bar);
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(single_flight.keys().is_empty());
+    }
+}
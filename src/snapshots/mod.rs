@@ -2,21 +2,30 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::anyhow;
+use nix::mount::MsFlags;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
 use std::path::{Path, PathBuf};
 
+use crate::error::Result;
+use crate::unpack::WhiteoutFormat;
+
+#[cfg(feature = "native_feature")]
+pub mod native;
 #[cfg(feature = "occlum_feature")]
 pub mod occlum;
 #[cfg(feature = "overlay_feature")]
 pub mod overlay;
 
 /// Snapshot types.
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum SnapshotType {
     Overlay,
     OcclumUnionfs,
+    Native,
 }
 
 impl std::fmt::Display for SnapshotType {
@@ -24,14 +33,34 @@ impl std::fmt::Display for SnapshotType {
         let out = match self {
             Self::Overlay => "overlay",
             Self::OcclumUnionfs => "occlum_unionfs",
+            Self::Native => "native",
         };
 
         write!(f, "{}", out)
     }
 }
 
+impl SnapshotType {
+    /// Whether the snapshot is compiled in by its cargo feature.
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Self::Overlay => cfg!(feature = "overlay_feature"),
+            Self::OcclumUnionfs => cfg!(feature = "occlum_feature"),
+            Self::Native => cfg!(feature = "native_feature"),
+        }
+    }
+
+    /// The format of whiteouts the snapshot expects in the unpacked layers.
+    pub fn whiteout_format(&self) -> WhiteoutFormat {
+        match self {
+            Self::Overlay => WhiteoutFormat::Overlay,
+            Self::OcclumUnionfs | Self::Native => WhiteoutFormat::Oci,
+        }
+    }
+}
+
 /// A MountPoint contains the info to represents a mount point.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct MountPoint {
     /// The filesystem type of mount point.
     pub r#type: String,
@@ -43,10 +72,185 @@ pub struct MountPoint {
     pub work_dir: PathBuf,
 }
 
+/// The options of a rootfs mount. The overlay snapshot supports all of
+/// them, the other snapshots only the default options.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct MountOptions {
+    /// Mount the rootfs read only. The overlay mount has only the lower
+    /// dirs, without the upper dir and the overlay workdir.
+    pub read_only: bool,
+
+    /// Never sync the overlay upper dir (`volatile`), for the ephemeral
+    /// containers whose rootfs changes are dropped after a crash anyway.
+    pub volatile: bool,
+
+    /// The overlay `index` feature, the kernel default if not set.
+    pub index: Option<bool>,
+
+    /// The overlay `metacopy` feature, the kernel default if not set.
+    pub metacopy: Option<bool>,
+
+    /// Do not allow access to the device files of the rootfs (`nodev`).
+    pub nodev: bool,
+
+    /// Ignore the setuid and setgid bits of the rootfs files (`nosuid`).
+    pub nosuid: bool,
+}
+
+impl MountOptions {
+    /// Get the mount flags of the options.
+    pub fn flags(&self) -> MsFlags {
+        let mut flags = MsFlags::empty();
+        flags.set(MsFlags::MS_RDONLY, self.read_only);
+        flags.set(MsFlags::MS_NODEV, self.nodev);
+        flags.set(MsFlags::MS_NOSUID, self.nosuid);
+
+        flags
+    }
+
+    /// Check that the options are the default ones, for the snapshots
+    /// which support no mount option.
+    pub fn check_default(&self, snapshot: SnapshotType) -> Result<()> {
+        if self != &MountOptions::default() {
+            return Err(anyhow!(
+                "mount options {:?} are not supported by the {} snapshot",
+                self,
+                snapshot
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
 pub trait Snapshotter: Send + Sync {
-    // mount the OCI image layers to destination mount path.
-    fn mount(&mut self, layer_path: &[&str], mount_path: &Path) -> Result<MountPoint>;
+    // mount the OCI image layers to destination mount path with the options.
+    fn mount(
+        &self,
+        layer_path: &[&str],
+        mount_path: &Path,
+        options: &MountOptions,
+    ) -> Result<MountPoint>;
 
     // unmount the mount_point and cleanup snapshot work dir.
     fn unmount(&self, mount_point: &MountPoint) -> Result<()>;
+
+    // get the index of the next snapshot work dir, which is persisted in
+    // the `MetaStore` snapshot_db.
+    fn index(&self) -> usize;
+
+    // get the image layer paths which are still used by the mount points
+    // of the snapshot, these layers must not be garbage collected.
+    fn layers_in_use(&self) -> Result<HashSet<String>>;
+
+    // remove the snapshot work dirs which are not used by any mount point.
+    fn prune(&self) -> Result<()>;
+}
+
+/// Remove the work dir of a mount point, a missing work dir is ignored.
+pub fn remove_work_dir(work_dir: &Path) -> Result<()> {
+    match fs::remove_dir_all(work_dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(anyhow!(
+            "failed to remove work dir {:?}, with error: {}",
+            work_dir,
+            e
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Get the super block options of all the mounts with `fs_type`
+/// filesystem type from /proc/self/mountinfo.
+pub fn mount_options(fs_type: &str) -> Result<Vec<String>> {
+    let mountinfo = fs::read_to_string(MOUNTINFO)
+        .map_err(|e| anyhow!("failed to read {}: {}", MOUNTINFO, e))?;
+
+    Ok(parse_mount_options(&mountinfo, fs_type))
+}
+
+// Each line of mountinfo looks like:
+// 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+// the optional fields end with a single hyphen, followed by the filesystem
+// type, the mount source and the super block options.
+fn parse_mount_options(mountinfo: &str, fs_type: &str) -> Vec<String> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (_, fields) = line.split_once(" - ")?;
+            let mut fields = fields.split(' ');
+            if fields.next()? != fs_type {
+                return None;
+            }
+            fields.nth(1).map(unescape_mount_field)
+        })
+        .collect()
+}
+
+// The kernel escapes space, tab, newline and backslash in mountinfo
+// fields as three digits octal sequence like "\040".
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let code = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or_default();
+            if let Ok(c) = u8::from_str_radix(code, 8) {
+                out.push(c);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_options_flags() {
+        assert_eq!(MountOptions::default().flags(), MsFlags::empty());
+        assert!(MountOptions::default()
+            .check_default(SnapshotType::Native)
+            .is_ok());
+
+        let options: MountOptions =
+            serde_json::from_str(r#"{"read_only": true, "nosuid": true, "index": false}"#).unwrap();
+        assert_eq!(options.flags(), MsFlags::MS_RDONLY | MsFlags::MS_NOSUID);
+        assert_eq!(options.index, Some(false));
+        assert_eq!(options.metacopy, None);
+        assert!(options.check_default(SnapshotType::Native).is_err());
+    }
+
+    #[test]
+    fn test_parse_mount_options() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw,errors=remount-ro
+98 22 0:52 / /run/a rw,relatime shared:60 - overlay overlay rw,lowerdir=/l/a:/l/b,upperdir=/o/0/upperdir,workdir=/o/0/workdir
+99 22 0:53 / /run/b\\040c rw,relatime - overlay overlay rw,lowerdir=/l\\040c,upperdir=/o/1/upperdir,workdir=/o/1/workdir
+";
+
+        assert_eq!(
+            parse_mount_options(mountinfo, "overlay"),
+            vec![
+                "rw,lowerdir=/l/a:/l/b,upperdir=/o/0/upperdir,workdir=/o/0/workdir".to_string(),
+                "rw,lowerdir=/l c,upperdir=/o/1/upperdir,workdir=/o/1/workdir".to_string(),
+            ]
+        );
+        assert_eq!(
+            parse_mount_options(mountinfo, "ext4"),
+            vec!["rw,errors=remount-ro".to_string()]
+        );
+        assert!(parse_mount_options(mountinfo, "unionfs").is_empty());
+    }
 }
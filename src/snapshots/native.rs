@@ -0,0 +1,242 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::anyhow;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::error::Result;
+use crate::snapshots::{remove_work_dir, MountOptions, MountPoint, SnapshotType, Snapshotter};
+use crate::unpack::{apply_layer, set_xattr};
+
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// The Native snapshot materializes the rootfs by copying the layers in
+/// order into the mount path, which needs no mount privileges. The files
+/// are reflinked on the filesystems supporting it, and keep their owners,
+/// hard links and timestamps, see [`apply_layer`].
+#[derive(Debug)]
+pub struct Native {
+    /// The SELinux label set on every file of the rootfs.
+    pub mount_label: Option<String>,
+}
+
+impl Native {
+    // Copy the layers from the bottom one into the mount path, and label
+    // the result.
+    fn populate(&self, layer_path: &[&str], mount_path: &Path) -> Result<()> {
+        // The layer paths are from the top layer to the bottom one, and
+        // each layer is applied over the lower ones.
+        for layer in layer_path.iter().rev() {
+            apply_layer(Path::new(layer), mount_path)?;
+        }
+
+        if let Some(label) = &self.mount_label {
+            for entry in WalkDir::new(mount_path).follow_links(false) {
+                let entry = entry.map_err(std::io::Error::from)?;
+                set_xattr(entry.path(), SELINUX_XATTR, label.as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Snapshotter for Native {
+    // The rootfs is a plain directory, which supports no mount option.
+    fn mount(
+        &self,
+        layer_path: &[&str],
+        mount_path: &Path,
+        options: &MountOptions,
+    ) -> Result<MountPoint> {
+        options.check_default(SnapshotType::Native)?;
+
+        if fs::read_dir(mount_path).map_or(false, |mut dir| dir.next().is_some()) {
+            return Err(anyhow!("rootfs {:?} is not empty", mount_path).into());
+        }
+
+        if let Err(e) = self.populate(layer_path, mount_path) {
+            remove_work_dir(mount_path)?;
+            return Err(e);
+        }
+
+        // The rootfs itself is the work dir of the snapshot.
+        Ok(MountPoint {
+            r#type: SnapshotType::Native.to_string(),
+            mount_path: mount_path.to_path_buf(),
+            work_dir: mount_path.to_path_buf(),
+        })
+    }
+
+    fn unmount(&self, mount_point: &MountPoint) -> Result<()> {
+        remove_work_dir(&mount_point.work_dir)
+    }
+
+    // The rootfs is created in the bundle, there are no work dirs under
+    // the work_dir to index.
+    fn index(&self) -> usize {
+        0
+    }
+
+    // The layers are copied into the rootfs at mount time, so the mount
+    // points never reference the image layers.
+    fn layers_in_use(&self) -> Result<HashSet<String>> {
+        Ok(HashSet::new())
+    }
+
+    // The rootfs is only removed when the bundle is unmounted.
+    fn prune(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpack::{unpack, UnpackOptions, WhiteoutFormat};
+    use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+    use test_utils::layer::layer_tarball;
+
+    #[test]
+    fn test_native_mount() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let native = Native { mount_label: None };
+
+        let lower = tempdir.path().join("lower");
+        let upper = tempdir.path().join("upper");
+        let layers = [
+            (
+                &lower,
+                layer_tarball(&[
+                    ("etc", None),
+                    ("etc/foo", Some("foo")),
+                    ("etc/bar", Some("bar")),
+                    ("opt", None),
+                    ("opt/old", Some("old")),
+                ]),
+            ),
+            (
+                &upper,
+                layer_tarball(&[
+                    ("etc", None),
+                    ("etc/.wh.foo", Some("")),
+                    ("etc/bar", Some("new bar")),
+                    ("opt", None),
+                    ("opt/.wh..wh..opq", Some("")),
+                    ("opt/new", Some("new")),
+                ]),
+            ),
+        ];
+        for (path, data) in layers.iter() {
+            unpack(
+                data.as_slice(),
+                path,
+                &UnpackOptions::new(WhiteoutFormat::Oci),
+            )
+            .unwrap();
+        }
+
+        let rootfs = tempdir.path().join("bundle").join("rootfs");
+        let layer_path = [upper.to_str().unwrap(), lower.to_str().unwrap()];
+        let options = MountOptions::default();
+        let mount_point = native.mount(&layer_path, &rootfs, &options).unwrap();
+        assert_eq!(mount_point.r#type, "native");
+
+        assert!(!rootfs.join("etc").join("foo").exists());
+        assert_eq!(
+            fs::read_to_string(rootfs.join("etc").join("bar")).unwrap(),
+            "new bar"
+        );
+        assert!(!rootfs.join("opt").join("old").exists());
+        assert!(rootfs.join("opt").join("new").exists());
+        assert!(!rootfs.join("etc").join(".wh.foo").exists());
+
+        // The layers are left untouched.
+        assert!(lower.join("etc").join("foo").exists());
+
+        // A rootfs is never mounted over another one.
+        assert!(native.mount(&layer_path, &rootfs, &options).is_err());
+
+        let read_only = MountOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let other_rootfs = tempdir.path().join("other").join("rootfs");
+        assert!(native
+            .mount(&layer_path, &other_rootfs, &read_only)
+            .is_err());
+        assert!(!other_rootfs.exists());
+
+        native.unmount(&mount_point).unwrap();
+        assert!(!rootfs.exists());
+        native.unmount(&mount_point).unwrap();
+    }
+
+    #[test]
+    fn test_native_mount_metadata() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let native = Native { mount_label: None };
+
+        let layer = tempdir.path().join("layer");
+        fs::create_dir_all(layer.join("dir")).unwrap();
+        fs::write(layer.join("dir").join("foo"), "foo").unwrap();
+        fs::hard_link(layer.join("dir").join("foo"), layer.join("bar")).unwrap();
+        symlink("dir/foo", layer.join("link")).unwrap();
+
+        // Only root can give the files another owner.
+        let is_root = Uid::effective().is_root();
+        let (uid, gid) = if is_root {
+            (1000, 1000)
+        } else {
+            (Uid::effective().as_raw(), Gid::effective().as_raw())
+        };
+        for path in ["dir", "dir/foo", "link"].iter() {
+            fchownat(
+                None,
+                &layer.join(path),
+                Some(Uid::from_raw(uid)),
+                Some(Gid::from_raw(gid)),
+                FchownatFlags::NoFollowSymlink,
+            )
+            .unwrap();
+        }
+        // chown clears the setuid bit.
+        fs::set_permissions(layer.join("bar"), fs::Permissions::from_mode(0o4755)).unwrap();
+
+        let mtime = filetime::FileTime::from_unix_time(20_000, 0);
+        for path in ["dir/foo", "dir"].iter() {
+            filetime::set_file_mtime(layer.join(path), mtime).unwrap();
+        }
+        filetime::set_symlink_file_times(layer.join("link"), mtime, mtime).unwrap();
+
+        let rootfs = tempdir.path().join("bundle").join("rootfs");
+        let layer_path = [layer.to_str().unwrap()];
+        let mount_point = native
+            .mount(&layer_path, &rootfs, &MountOptions::default())
+            .unwrap();
+
+        let foo = fs::metadata(rootfs.join("dir").join("foo")).unwrap();
+        let bar = fs::metadata(rootfs.join("bar")).unwrap();
+        assert_eq!(foo.ino(), bar.ino());
+        assert_eq!(foo.nlink(), 2);
+        assert_eq!(foo.mode() & 0o7777, 0o4755);
+
+        for path in ["dir", "dir/foo", "link"].iter() {
+            let metadata = fs::symlink_metadata(rootfs.join(path)).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (uid, gid), "{}", path);
+            assert_eq!(
+                filetime::FileTime::from_last_modification_time(&metadata),
+                mtime,
+                "{}",
+                path
+            );
+        }
+
+        native.unmount(&mount_point).unwrap();
+    }
+}
@@ -4,17 +4,20 @@
 
 // This unionfs file is used for occlum only
 
+use std::collections::HashSet;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use anyhow::{anyhow, Result};
-use dircpy::CopyBuilder;
+use anyhow::anyhow;
 use fs_extra;
 use fs_extra::dir;
 use nix::mount::MsFlags;
 
-use crate::snapshots::{MountPoint, Snapshotter};
+use crate::error::{Error, Result};
+use crate::snapshots::{remove_work_dir, MountOptions, MountPoint, SnapshotType, Snapshotter};
+use crate::unpack::apply_layer;
 
 const LD_LIB: &str = "ld-linux-x86-64.so.2";
 
@@ -24,7 +27,7 @@ pub struct Unionfs {
     pub index: AtomicUsize,
 }
 
-fn clear_path(mount_path: &Path) -> Result<()> {
+fn clear_path(mount_path: &Path) -> anyhow::Result<()> {
     let mut from_paths = Vec::new();
     let paths = fs::read_dir(
         mount_path
@@ -39,7 +42,7 @@ fn clear_path(mount_path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn create_dir(create_path: &PathBuf) -> Result<()> {
+fn create_dir(create_path: &PathBuf) -> anyhow::Result<()> {
     if !create_path.exists() {
         fs::create_dir_all(create_path.as_path())?;
     }
@@ -47,7 +50,7 @@ fn create_dir(create_path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn create_environment(mount_path: &Path) -> Result<()> {
+fn create_environment(mount_path: &Path) -> anyhow::Result<()> {
     let mut from_paths = Vec::new();
     let mut copy_options = dir::CopyOptions::new();
     copy_options.overwrite = true;
@@ -109,7 +112,16 @@ fn create_environment(mount_path: &Path) -> Result<()> {
 }
 
 impl Snapshotter for Unionfs {
-    fn mount(&mut self, layer_path: &[&str], mount_path: &Path) -> Result<MountPoint> {
+    // The layers are copied into the sefs image, the unionfs mount only
+    // lives until then, so no mount option applies.
+    fn mount(
+        &self,
+        layer_path: &[&str],
+        mount_path: &Path,
+        options: &MountOptions,
+    ) -> Result<MountPoint> {
+        options.check_default(SnapshotType::OcclumUnionfs)?;
+
         // From the description of https://github.com/occlum/occlum/blob/master/docs/runtime_mount.md#1-mount-trusted-unionfs-consisting-of-sefss ,
         // the source type of runtime mount is "unionfs".
         let fs_type = String::from("unionfs");
@@ -149,43 +161,64 @@ impl Snapshotter for Unionfs {
             flags,
             Some(options.as_str()),
         )
-        .map_err(|e| {
-            anyhow!(
-                "failed to mount {:?} to {:?}, with error: {}",
-                source,
-                mount_path,
-                e
-            )
+        .map_err(|e| Error::Mount {
+            target: mount_path.to_path_buf(),
+            source: io::Error::from(e),
         })?;
 
         // clear the mount_path if there is something
         clear_path(mount_path)?;
 
-        // copy dirs to the specified mount directory
-        let mut layer_path_vec = layer_path.to_vec();
-        let len = layer_path_vec.len();
-        for _i in 0..len {
-            let layer = layer_path_vec
-                .pop()
-                .ok_or(anyhow!("Pop() failed from Vec"))?;
-            CopyBuilder::new(layer, &mount_path).overwrite(true).run()?;
+        // copy layers to the specified mount directory from the bottom one,
+        // and apply the whiteouts of each layer over the lower ones.
+        for layer in layer_path.iter().rev() {
+            apply_layer(Path::new(layer), mount_path)?;
         }
 
         // create environment for Occlum
         create_environment(mount_path)?;
 
-        nix::mount::umount(mount_path)?;
+        nix::mount::umount(mount_path).map_err(|e| Error::Unmount {
+            target: mount_path.to_path_buf(),
+            source: io::Error::from(e),
+        })?;
 
         Ok(MountPoint {
             r#type: fs_type,
             mount_path: mount_path.to_path_buf(),
-            work_dir: self.data_dir.to_path_buf(),
+            work_dir: sefs_base,
         })
     }
 
     fn unmount(&self, mount_point: &MountPoint) -> Result<()> {
-        nix::mount::umount(mount_point.mount_path.as_path())?;
+        // The unionfs is already unmounted after the layers are copied
+        // into the sefs image, so EINVAL is expected here.
+        match nix::mount::umount(mount_point.mount_path.as_path()) {
+            Ok(()) | Err(nix::errno::Errno::EINVAL) => {}
+            Err(e) => {
+                return Err(Error::Unmount {
+                    target: mount_point.mount_path.clone(),
+                    source: io::Error::from(e),
+                })
+            }
+        }
+
+        remove_work_dir(&mount_point.work_dir)
+    }
+
+    fn index(&self) -> usize {
+        self.index.load(Ordering::SeqCst)
+    }
+
+    // The layers are copied into the sefs image at mount time, so the
+    // mount points never reference the image layers.
+    fn layers_in_use(&self) -> Result<HashSet<String>> {
+        Ok(HashSet::new())
+    }
 
+    // The sefs work dirs are named by container id outside of the data
+    // dir, and are only removed when the bundle is unmounted.
+    fn prune(&self) -> Result<()> {
         Ok(())
     }
 }
@@ -196,6 +229,7 @@ mod tests {
     use std::fs::File;
     use std::io::Write;
     use tempfile;
+    use test_utils::skip_if_root;
 
     #[test]
     fn test_create_dir() {
@@ -221,26 +255,6 @@ mod tests {
         assert!(!file.exists());
     }
 
-    #[allow(unused_macros)]
-    macro_rules! skip_if_root {
-        () => {
-            if nix::unistd::Uid::effective().is_root() {
-                println!("INFO: skipping {} which needs non-root", module_path!());
-                return;
-            }
-        };
-    }
-
-    #[allow(unused_macros)]
-    macro_rules! skip_if_not_root {
-        () => {
-            if !nix::unistd::Uid::effective().is_root() {
-                println!("INFO: skipping {} which needs root", module_path!());
-                return;
-            }
-        };
-    }
-
     #[test]
     fn test_mount() {
         skip_if_root!();
@@ -249,7 +263,7 @@ mod tests {
         let work_dir = tempfile::tempdir().unwrap().path().to_path_buf();
 
         let unionfs_index = 0;
-        let mut occlum_unionfs = Unionfs {
+        let occlum_unionfs = Unionfs {
             data_dir: work_dir,
             index: AtomicUsize::new(unionfs_index),
         };
@@ -261,6 +275,9 @@ mod tests {
             path_2.path().to_str().unwrap(),
         ];
 
-        assert!(!occlum_unionfs.mount(layer_path, mnt_path.as_ref()).is_ok());
+        assert!(matches!(
+            occlum_unionfs.mount(layer_path, mnt_path.as_ref(), &MountOptions::default()),
+            Err(Error::Mount { .. })
+        ));
     }
 }
@@ -2,62 +2,240 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use nix::mount::MsFlags;
+use nix::sched::{unshare, CloneFlags};
+use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
-use crate::snapshots::{MountPoint, SnapshotType, Snapshotter};
+use crate::error::{Error, Result};
+use crate::snapshots::{
+    mount_options, remove_work_dir, MountOptions, MountPoint, SnapshotType, Snapshotter,
+};
+
+/// The max number of lower layers of an overlay mount, the
+/// OVL_MAX_STACK of the kernel.
+pub const MAX_LOWER_LAYERS: usize = 500;
+
+// The dir in a snapshot work dir holding the short aliases of the lower
+// layers, which are symlinks named by the layer index. The lowerdir option
+// is relative to it, so that the mount data of the deepest image still
+// fits in the one page limit of mount(2).
+const LOWER_ALIAS_DIR: &str = "l";
+
+// The empty dir in a snapshot work dir, which is the bottom lower dir of
+// the read only mounts. Overlay needs two lower dirs at least without an
+// upper dir, and its absolute path tells the work dir of the mount.
+const EMPTY_LOWER_DIR: &str = "empty";
 
 #[derive(Debug)]
 pub struct OverLay {
     pub data_dir: PathBuf,
     pub index: AtomicUsize,
+
+    /// The SELinux label of the mounted rootfs.
+    pub mount_label: Option<String>,
+}
+
+/// The lower dirs and work dir of an overlay mount.
+#[derive(Debug, Default, PartialEq)]
+struct OverlayMount {
+    lowerdirs: Vec<String>,
+    work_dir: PathBuf,
+}
+
+impl OverlayMount {
+    // The work dir is the parent of the upper dir, or of the empty bottom
+    // lower dir for the read only mounts. The lower dir aliases relative
+    // to the alias dir of the work dir are resolved to the layer paths.
+    fn from_options(options: &str) -> Self {
+        let mut mount = OverlayMount::default();
+        let mut upperdir = None;
+        for option in options.split(',') {
+            if let Some(lowerdir) = option.strip_prefix("lowerdir=") {
+                mount.lowerdirs = lowerdir.split(':').map(|l| l.to_string()).collect();
+            } else if let Some(dir) = option.strip_prefix("upperdir=") {
+                upperdir = Some(PathBuf::from(dir));
+            }
+        }
+
+        let work_dir = match upperdir {
+            Some(upperdir) => upperdir.parent().map(|p| p.to_path_buf()),
+            None => match mount.lowerdirs.last().map(Path::new) {
+                Some(empty)
+                    if empty.is_absolute()
+                        && empty.file_name() == Some(OsStr::new(EMPTY_LOWER_DIR)) =>
+                {
+                    let work_dir = empty.parent().map(|p| p.to_path_buf());
+                    mount.lowerdirs.pop();
+                    work_dir
+                }
+                _ => None,
+            },
+        };
+
+        if let Some(work_dir) = work_dir {
+            let alias_dir = work_dir.join(LOWER_ALIAS_DIR);
+            for lowerdir in mount.lowerdirs.iter_mut() {
+                if Path::new(lowerdir.as_str()).is_relative() {
+                    if let Ok(layer) = fs::read_link(alias_dir.join(lowerdir.as_str())) {
+                        *lowerdir = layer.display().to_string();
+                    }
+                }
+            }
+            mount.work_dir = work_dir;
+        }
+
+        mount
+    }
+}
+
+// Mount with the working dir at cwd, for the relative paths in the mount
+// data. The mount runs on a new thread, which stops sharing the working
+// dir with the other threads before changing it.
+fn mount_in(
+    cwd: &Path,
+    fs_type: &str,
+    target: &Path,
+    flags: MsFlags,
+    data: &str,
+) -> io::Result<()> {
+    let cwd = cwd.to_path_buf();
+    let fs_type = fs_type.to_string();
+    let target = std::env::current_dir()?.join(target);
+    let data = data.to_string();
+
+    thread::spawn(move || {
+        unshare(CloneFlags::CLONE_FS)?;
+        nix::unistd::chdir(&cwd)?;
+        nix::mount::mount(
+            Some(fs_type.as_str()),
+            &target,
+            Some(fs_type.as_str()),
+            flags,
+            Some(data.as_str()),
+        )
+    })
+    .join()
+    .map_err(|_| io::Error::new(io::ErrorKind::Other, "mount thread panicked"))?
+    .map_err(io::Error::from)
+}
+
+impl OverLay {
+    // get the overlay mounts whose work dir is in the data dir.
+    fn mounts(&self) -> Result<Vec<OverlayMount>> {
+        let fs_type = SnapshotType::Overlay.to_string();
+        let mounts = mount_options(&fs_type)?
+            .iter()
+            .map(|options| OverlayMount::from_options(options))
+            .filter(|mount| mount.work_dir.starts_with(&self.data_dir))
+            .collect();
+
+        Ok(mounts)
+    }
+
+    // get the overlay mount options of the lower dirs and the work dir.
+    // The read only mounts have the empty bottom lower dir and no upper
+    // dir, so `volatile` only applies to the writable ones.
+    fn options(&self, lowerdir: &str, work_dir: &Path, mount_options: &MountOptions) -> String {
+        let mut options = if mount_options.read_only {
+            format!(
+                "lowerdir={}:{}",
+                lowerdir,
+                work_dir.join(EMPTY_LOWER_DIR).display()
+            )
+        } else {
+            let mut options = format!(
+                "lowerdir={},upperdir={},workdir={}",
+                lowerdir,
+                work_dir.join("upperdir").display(),
+                work_dir.join("workdir").display()
+            );
+            if mount_options.volatile {
+                options.push_str(",volatile");
+            }
+            options
+        };
+
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        if let Some(index) = mount_options.index {
+            options.push_str(&format!(",index={}", on_off(index)));
+        }
+        if let Some(metacopy) = mount_options.metacopy {
+            options.push_str(&format!(",metacopy={}", on_off(metacopy)));
+        }
+
+        // The whole mount takes the label, the label of the layers is
+        // never changed.
+        if let Some(label) = &self.mount_label {
+            options.push_str(&format!(",context=\"{}\"", label));
+        }
+
+        options
+    }
 }
 
 impl Snapshotter for OverLay {
-    fn mount(&mut self, layer_path: &[&str], mount_path: &Path) -> Result<MountPoint> {
+    fn mount(
+        &self,
+        layer_path: &[&str],
+        mount_path: &Path,
+        mount_options: &MountOptions,
+    ) -> Result<MountPoint> {
+        // The empty lower dir of the read only mounts takes one layer.
+        let max_layers = if mount_options.read_only {
+            MAX_LOWER_LAYERS - 1
+        } else {
+            MAX_LOWER_LAYERS
+        };
+        if layer_path.len() > max_layers {
+            return Err(anyhow!(
+                "overlay mounts at most {} layers, but the image has {}",
+                max_layers,
+                layer_path.len()
+            )
+            .into());
+        }
+
         let fs_type = SnapshotType::Overlay.to_string();
-        let overlay_lowerdir = layer_path.join(":");
         let index = self.index.fetch_add(1, Ordering::SeqCst).to_string();
         let work_dir = self.data_dir.join(&index);
-        let overlay_upperdir = work_dir.join("upperdir");
-        let overlay_workdir = work_dir.join("workdir");
+        let alias_dir = work_dir.join(LOWER_ALIAS_DIR);
 
         if !self.data_dir.exists() {
             fs::create_dir_all(&self.data_dir)?;
         }
-        fs::create_dir_all(&overlay_upperdir)?;
-        fs::create_dir_all(&overlay_workdir)?;
+        if mount_options.read_only {
+            fs::create_dir_all(work_dir.join(EMPTY_LOWER_DIR))?;
+        } else {
+            fs::create_dir_all(work_dir.join("upperdir"))?;
+            fs::create_dir_all(work_dir.join("workdir"))?;
+        }
+        fs::create_dir_all(&alias_dir)?;
+
+        let mut aliases = Vec::with_capacity(layer_path.len());
+        for (i, layer) in layer_path.iter().enumerate() {
+            let alias = i.to_string();
+            symlink(layer, alias_dir.join(&alias))?;
+            aliases.push(alias);
+        }
 
         if !mount_path.exists() {
             fs::create_dir_all(mount_path)?;
         }
 
-        let source = Path::new(&fs_type);
-        let flags = MsFlags::empty();
-        let options = format!(
-            "lowerdir={},upperdir={},workdir={}",
-            overlay_lowerdir,
-            overlay_upperdir.display(),
-            overlay_workdir.display()
-        );
+        let flags = mount_options.flags();
+        let options = self.options(&aliases.join(":"), &work_dir, mount_options);
 
-        nix::mount::mount(
-            Some(source),
-            mount_path,
-            Some(fs_type.as_str()),
-            flags,
-            Some(options.as_str()),
-        )
-        .map_err(|e| {
-            anyhow!(
-                "failed to mount {:?} to {:?}, with error: {}",
-                source,
-                mount_path,
-                e
-            )
+        mount_in(&alias_dir, &fs_type, mount_path, flags, &options).map_err(|e| Error::Mount {
+            target: mount_path.to_path_buf(),
+            source: e,
         })?;
 
         Ok(MountPoint {
@@ -68,8 +246,280 @@ impl Snapshotter for OverLay {
     }
 
     fn unmount(&self, mount_point: &MountPoint) -> Result<()> {
-        nix::mount::umount(mount_point.mount_path.as_path())?;
+        // EINVAL means the mount path is not mounted any more, e.g. after
+        // a reboot, and the work dir still needs to be cleaned up.
+        match nix::mount::umount(mount_point.mount_path.as_path()) {
+            Ok(()) | Err(nix::errno::Errno::EINVAL) => {}
+            Err(e) => {
+                return Err(Error::Unmount {
+                    target: mount_point.mount_path.clone(),
+                    source: io::Error::from(e),
+                })
+            }
+        }
+
+        remove_work_dir(&mount_point.work_dir)
+    }
+
+    fn index(&self) -> usize {
+        self.index.load(Ordering::SeqCst)
+    }
+
+    fn layers_in_use(&self) -> Result<HashSet<String>> {
+        Ok(self
+            .mounts()?
+            .into_iter()
+            .flat_map(|mount| mount.lowerdirs)
+            .collect())
+    }
+
+    fn prune(&self) -> Result<()> {
+        if !self.data_dir.exists() {
+            return Ok(());
+        }
+
+        let work_dirs: HashSet<PathBuf> = self
+            .mounts()?
+            .iter()
+            .map(|mount| mount.work_dir.clone())
+            .collect();
+
+        for entry in fs::read_dir(&self.data_dir)? {
+            let path = entry?.path();
+            if !work_dirs.contains(&path) {
+                fs::remove_dir_all(&path)
+                    .map_err(|e| anyhow!("failed to remove work dir {:?}: {}", path, e))?;
+            }
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlay_mount_from_options() {
+        let mount = OverlayMount::from_options(
+            "rw,relatime,lowerdir=/l/a:/l/b,upperdir=/o/0/upperdir,workdir=/o/0/workdir",
+        );
+        assert_eq!(
+            mount,
+            OverlayMount {
+                lowerdirs: vec!["/l/a".to_string(), "/l/b".to_string()],
+                work_dir: PathBuf::from("/o/0"),
+            }
+        );
+
+        let mount = OverlayMount::from_options("ro,relatime,lowerdir=/l/a:/o/1/empty");
+        assert_eq!(
+            mount,
+            OverlayMount {
+                lowerdirs: vec!["/l/a".to_string()],
+                work_dir: PathBuf::from("/o/1"),
+            }
+        );
+
+        // Not a mount of any snapshot.
+        let mount = OverlayMount::from_options("ro,lowerdir=/l/a:/l/b");
+        assert_eq!(mount.work_dir, PathBuf::new());
+    }
+
+    #[test]
+    fn test_overlay_mount_aliases() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let alias_dir = data_dir.path().join("0").join(LOWER_ALIAS_DIR);
+        fs::create_dir_all(&alias_dir).unwrap();
+        symlink("/l/a", alias_dir.join("0")).unwrap();
+        symlink("/l/b", alias_dir.join("1")).unwrap();
+
+        let mount = OverlayMount::from_options(&format!(
+            "rw,lowerdir=0:1,upperdir={}/0/upperdir,workdir={}/0/workdir",
+            data_dir.path().display(),
+            data_dir.path().display()
+        ));
+        assert_eq!(
+            mount.lowerdirs,
+            vec!["/l/a".to_string(), "/l/b".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_mount_max_layers() {
+        test_utils::skip_if_not_root!();
+
+        // The absolute paths of the layers are far over one page.
+        let tempdir = tempfile::tempdir().unwrap();
+        let base = tempdir.path().join("a".repeat(200));
+        let layers: Vec<String> = (0..MAX_LOWER_LAYERS)
+            .map(|i| {
+                let layer = base.join(format!("sha256_{:064}", i));
+                fs::create_dir_all(&layer).unwrap();
+                fs::write(layer.join(format!("f{}", i)), "").unwrap();
+                layer.display().to_string()
+            })
+            .collect();
+        let layer_path: Vec<&str> = layers.iter().map(|l| l.as_str()).collect();
+
+        let overlay = OverLay {
+            data_dir: base.join("overlay"),
+            index: AtomicUsize::new(0),
+            mount_label: None,
+        };
+        let rootfs = tempdir.path().join("rootfs");
+        let options = MountOptions::default();
+        let mount_point = overlay.mount(&layer_path, &rootfs, &options).unwrap();
+
+        assert!(rootfs.join("f0").exists());
+        assert!(rootfs.join(format!("f{}", MAX_LOWER_LAYERS - 1)).exists());
+        let in_use = overlay.layers_in_use().unwrap();
+        assert!(layers.iter().all(|l| in_use.contains(l)));
+
+        overlay.unmount(&mount_point).unwrap();
+        assert!(Path::new(&layers[0]).exists());
+
+        let mut too_many = layer_path.clone();
+        too_many.push(layer_path[0]);
+        assert!(overlay.mount(&too_many, &rootfs, &options).is_err());
+
+        let read_only = MountOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        assert!(overlay.mount(&layer_path, &rootfs, &read_only).is_err());
+    }
+
+    #[test]
+    fn test_mount_read_only() {
+        test_utils::skip_if_not_root!();
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let layer = tempdir.path().join("layer");
+        fs::create_dir_all(&layer).unwrap();
+        fs::write(layer.join("foo"), "foo").unwrap();
+
+        let overlay = OverLay {
+            data_dir: tempdir.path().join("overlay"),
+            index: AtomicUsize::new(0),
+            mount_label: None,
+        };
+        let options = MountOptions {
+            read_only: true,
+            nodev: true,
+            nosuid: true,
+            ..Default::default()
+        };
+        let rootfs = tempdir.path().join("rootfs");
+        let layer_path = [layer.to_str().unwrap()];
+        let mount_point = overlay.mount(&layer_path, &rootfs, &options).unwrap();
+
+        assert_eq!(fs::read_to_string(rootfs.join("foo")).unwrap(), "foo");
+        assert!(fs::write(rootfs.join("bar"), "bar").is_err());
+        assert!(!mount_point.work_dir.join("upperdir").exists());
+
+        // The read only mount still keeps its layer and work dir.
+        let in_use = overlay.layers_in_use().unwrap();
+        assert!(in_use.contains(layer.to_str().unwrap()));
+        overlay.prune().unwrap();
+        assert!(mount_point.work_dir.exists());
+
+        overlay.unmount(&mount_point).unwrap();
+        assert!(!mount_point.work_dir.exists());
+    }
+
+    #[test]
+    fn test_overlay_options() {
+        let mut overlay = OverLay {
+            data_dir: PathBuf::from("/o"),
+            index: AtomicUsize::new(0),
+            mount_label: None,
+        };
+        let work_dir = Path::new("/o/0");
+        let mut options = MountOptions::default();
+        assert_eq!(
+            overlay.options("/l/a:/l/b", work_dir, &options),
+            "lowerdir=/l/a:/l/b,upperdir=/o/0/upperdir,workdir=/o/0/workdir"
+        );
+
+        options.volatile = true;
+        options.index = Some(false);
+        options.metacopy = Some(true);
+        assert_eq!(
+            overlay.options("/l/a", work_dir, &options),
+            "lowerdir=/l/a,upperdir=/o/0/upperdir,workdir=/o/0/workdir,\
+             volatile,index=off,metacopy=on"
+        );
+
+        options.read_only = true;
+        options.metacopy = None;
+        assert_eq!(
+            overlay.options("/l/a", work_dir, &options),
+            "lowerdir=/l/a:/o/0/empty,index=off"
+        );
+
+        overlay.mount_label = Some("system_u:object_r:container_file_t:s0:c1,c2".to_string());
+        assert_eq!(
+            overlay.options("/l/a", work_dir, &MountOptions::default()),
+            "lowerdir=/l/a,upperdir=/o/0/upperdir,workdir=/o/0/workdir,\
+             context=\"system_u:object_r:container_file_t:s0:c1,c2\""
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let overlay = OverLay {
+            data_dir: data_dir.path().to_path_buf(),
+            index: AtomicUsize::new(2),
+            mount_label: None,
+        };
+
+        for index in 0..2 {
+            fs::create_dir_all(data_dir.path().join(index.to_string()).join("upperdir")).unwrap();
+        }
+
+        // Nothing is mounted from the data dir, so all the work dirs are stale.
+        assert!(overlay.layers_in_use().unwrap().is_empty());
+        overlay.prune().unwrap();
+        assert_eq!(fs::read_dir(data_dir.path()).unwrap().count(), 0);
+        assert_eq!(overlay.index(), 2);
+    }
+
+    #[test]
+    fn test_unmount_not_mounted() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let mount_path = tempfile::tempdir().unwrap();
+        let overlay = OverLay {
+            data_dir: data_dir.path().to_path_buf(),
+            index: AtomicUsize::new(1),
+            mount_label: None,
+        };
+
+        let work_dir = data_dir.path().join("0");
+        fs::create_dir_all(work_dir.join("upperdir")).unwrap();
+        fs::create_dir_all(work_dir.join("workdir")).unwrap();
+
+        let mount_point = MountPoint {
+            r#type: SnapshotType::Overlay.to_string(),
+            mount_path: mount_path.path().to_path_buf(),
+            work_dir: work_dir.clone(),
+        };
+
+        // umount fails with EPERM for unprivileged users.
+        if nix::unistd::Uid::effective().is_root() {
+            overlay.unmount(&mount_point).unwrap();
+            assert!(!work_dir.exists());
+
+            // Unmount again is a no-op.
+            overlay.unmount(&mount_point).unwrap();
+        } else {
+            assert!(matches!(
+                overlay.unmount(&mount_point),
+                Err(Error::Unmount { .. })
+            ));
+            assert!(work_dir.exists());
+        }
+    }
+}
@@ -0,0 +1,466 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use oci_distribution::manifest::{OciDescriptor, OciImageManifest};
+use oci_spec::image::{ImageConfiguration, MediaType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::{ImageConfig, Platform};
+use crate::decoder::Compression;
+use crate::digest::{Algorithm, Digest};
+use crate::source::layout::{blob_path, read_image, OCI_LAYOUT_FILE};
+use crate::source::{ImageSource, LocalBlob};
+
+/// The transport prefix of the references to docker archives.
+pub const DOCKER_ARCHIVE_TRANSPORT: &str = "docker-archive:";
+
+/// The transport prefix of the references to OCI archives.
+pub const OCI_ARCHIVE_TRANSPORT: &str = "oci-archive:";
+
+/// The file of a docker archive listing its images.
+const DOCKER_MANIFEST_FILE: &str = "manifest.json";
+
+/// The most symlinks followed to find an entry of an archive.
+const MAX_LINK_DEPTH: usize = 8;
+
+/// The format of an image archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A tarball created by `docker save`.
+    Docker,
+
+    /// An OCI image layout packed into a tarball.
+    Oci,
+}
+
+/// An image in the manifest.json of a docker archive.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Vec<String>,
+    layers: Vec<String>,
+}
+
+/// The ArchiveSource reads an image from a `docker save` tarball or an
+/// OCI archive in place, the layers are streamed out of the archive
+/// without extracting the archive to disk.
+pub struct ArchiveSource {
+    /// The path of the archive.
+    pub path: PathBuf,
+
+    /// The format of the archive, which is detected from its content.
+    pub format: ArchiveFormat,
+
+    /// The platform to pick from a multi-platform image index.
+    pub platform: Platform,
+
+    // The offset and size of the regular files in the archive.
+    files: HashMap<PathBuf, (u64, u64)>,
+
+    // The targets of the symlinks in the archive.
+    links: HashMap<PathBuf, PathBuf>,
+
+    // The files of the docker archive layers by the layer digests.
+    layers: HashMap<String, PathBuf>,
+}
+
+impl ArchiveSource {
+    /// Constructs a new ArchiveSource by indexing the entries of the
+    /// archive. The archive has to be an uncompressed tarball, so that
+    /// its files can be read in place.
+    pub fn new(path: &Path, config: &ImageConfig) -> Result<ArchiveSource> {
+        let mut file =
+            fs::File::open(path).map_err(|e| anyhow!("failed to open {:?}: {}", path, e))?;
+
+        let mut header = [0u8; 4];
+        let n = file.read(&mut header)?;
+        if Compression::from_magic(&header[..n]) != Compression::Uncompressed {
+            return Err(anyhow!(
+                "compressed image archive {:?} is not supported",
+                path
+            ));
+        }
+
+        let mut files = HashMap::new();
+        let mut links = HashMap::new();
+        let mut archive = tar::Archive::new(fs::File::open(path)?);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = normalize(&entry.path()?);
+            let entry_type = entry.header().entry_type();
+
+            if entry_type.is_file() {
+                files.insert(name, (entry.raw_file_position(), entry.size()));
+            } else if entry_type.is_symlink() {
+                if let Some(target) = entry.link_name()? {
+                    let parent = name.parent().unwrap_or_else(|| Path::new(""));
+                    links.insert(name.clone(), normalize(&parent.join(target)));
+                }
+            } else if entry_type.is_hard_link() {
+                if let Some(target) = entry.link_name()? {
+                    links.insert(name, normalize(&target));
+                }
+            }
+        }
+
+        let format = if files.contains_key(Path::new(OCI_LAYOUT_FILE)) {
+            ArchiveFormat::Oci
+        } else if files.contains_key(Path::new(DOCKER_MANIFEST_FILE)) {
+            ArchiveFormat::Docker
+        } else {
+            return Err(anyhow!("{:?} is not a docker or oci archive", path));
+        };
+
+        Ok(ArchiveSource {
+            path: path.to_path_buf(),
+            format,
+            platform: config.platform.clone(),
+            files,
+            links,
+            layers: HashMap::new(),
+        })
+    }
+
+    // Get the blob of a file in the archive, following the symlinks.
+    fn blob(&self, path: &Path) -> Result<LocalBlob> {
+        let mut name = normalize(path);
+        for _ in 0..MAX_LINK_DEPTH {
+            if let Some((offset, size)) = self.files.get(&name) {
+                return Ok(LocalBlob {
+                    path: self.path.clone(),
+                    offset: *offset,
+                    size: Some(*size),
+                    temporary: false,
+                });
+            }
+
+            match self.links.get(&name) {
+                Some(target) => name = target.clone(),
+                None => break,
+            }
+        }
+
+        Err(anyhow!("{:?} not found in archive {:?}", path, self.path))
+    }
+
+    // Read a file in the archive.
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.blob(path)?.open()?.read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+
+    // Build an image manifest for the only image of a docker archive.
+    // The layers are not compressed usually, their digests are the
+    // diff_ids then, otherwise the digests are calculated.
+    fn read_docker_image(&mut self) -> Result<(OciImageManifest, String, String)> {
+        let manifests: Vec<DockerManifest> =
+            serde_json::from_slice(&self.read_file(Path::new(DOCKER_MANIFEST_FILE))?)
+                .map_err(|e| anyhow!("failed to parse {}: {}", DOCKER_MANIFEST_FILE, e))?;
+        let manifest = match manifests.as_slice() {
+            [manifest] => manifest,
+            _ => {
+                return Err(anyhow!(
+                    "one image is expected, but {} images found",
+                    manifests.len()
+                ))
+            }
+        };
+        log::info!(
+            "import image {:?} from docker archive {:?}",
+            manifest.repo_tags,
+            self.path
+        );
+
+        let config = self.read_file(Path::new(&manifest.config))?;
+        let image_config = ImageConfiguration::from_reader(config.as_slice())?;
+        let diff_ids = image_config.rootfs().diff_ids();
+        if diff_ids.len() != manifest.layers.len() {
+            return Err(anyhow!(
+                "{} layers found in archive, mismatching with {} diff_ids in image config",
+                manifest.layers.len(),
+                diff_ids.len()
+            ));
+        }
+
+        let mut layers = Vec::new();
+        for (layer_path, diff_id) in manifest.layers.iter().zip(diff_ids) {
+            let blob = self.blob(Path::new(layer_path))?;
+
+            let mut header = Vec::new();
+            blob.open()?.take(4).read_to_end(&mut header)?;
+            let compression = Compression::from_magic(&header);
+
+            let digest = match compression {
+                Compression::Uncompressed => diff_id.clone(),
+                _ => Digest::from_reader(Algorithm::Sha256, blob.open()?)?.to_string(),
+            };
+
+            layers.push(OciDescriptor {
+                media_type: compression.layer_media_type().to_string(),
+                digest: digest.clone(),
+                size: blob.size.unwrap_or_default() as i64,
+                ..Default::default()
+            });
+            self.layers.insert(digest, PathBuf::from(layer_path));
+        }
+
+        let image_manifest = OciImageManifest {
+            config: OciDescriptor {
+                media_type: MediaType::ImageConfig.to_string(),
+                digest: Digest::from_bytes(Algorithm::Sha256, &config).to_string(),
+                size: config.len() as i64,
+                ..Default::default()
+            },
+            layers,
+            ..Default::default()
+        };
+
+        // A docker archive has no image manifest, the digest of the built
+        // manifest stands for the image digest.
+        let digest = Digest::from_bytes(Algorithm::Sha256, &serde_json::to_vec(&image_manifest)?);
+        let config = String::from_utf8(config)
+            .map_err(|e| anyhow!("invalid image config {}: {}", manifest.config, e))?;
+
+        Ok((image_manifest, digest.to_string(), config))
+    }
+}
+
+// Normalize a path in an archive lexically, so that the entries and the
+// references to them are compared in the same form.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+
+    normalized
+}
+
+#[async_trait]
+impl ImageSource for ArchiveSource {
+    fn reference(&self) -> String {
+        let transport = match self.format {
+            ArchiveFormat::Docker => DOCKER_ARCHIVE_TRANSPORT,
+            ArchiveFormat::Oci => OCI_ARCHIVE_TRANSPORT,
+        };
+
+        format!("{}{}", transport, self.path.display())
+    }
+
+    async fn pull_manifest(&mut self) -> Result<(OciImageManifest, String, String)> {
+        let result = match self.format {
+            ArchiveFormat::Docker => self.read_docker_image(),
+            ArchiveFormat::Oci => read_image(|path| self.read_file(path), &None, &self.platform),
+        };
+
+        result.map_err(|e| anyhow!("failed to read image from {:?}: {}", self.path, e))
+    }
+
+    /// The layer blob is a section of the archive.
+    async fn fetch_blob(
+        &self,
+        layer: &OciDescriptor,
+        progress: &(dyn Fn(u64) + Sync),
+    ) -> Result<LocalBlob> {
+        let path = match self.format {
+            ArchiveFormat::Docker => self
+                .layers
+                .get(&layer.digest)
+                .cloned()
+                .ok_or_else(|| anyhow!("layer {} not found", layer.digest))?,
+            ArchiveFormat::Oci => blob_path(&layer.digest)?,
+        };
+
+        let blob = self.blob(&path)?;
+        progress(blob.size.unwrap_or_default());
+
+        Ok(blob)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use sha2::Digest;
+    use std::io::Write;
+    use test_utils::layer::{append_file, layer_tarball};
+
+    fn digest(data: &[u8]) -> String {
+        format!("sha256:{:x}", sha2::Sha256::digest(data))
+    }
+
+    fn read_blob(blob: &LocalBlob) -> Vec<u8> {
+        let mut data = Vec::new();
+        blob.open().unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn test_docker_archive() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("busybox.tar");
+
+        let layer1 = layer_tarball(&[("file.txt", Some("foo"))]);
+        let layer2 = layer_tarball(&[("file.txt", Some("bar"))]);
+        let mut gzip_encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip_encoder.write_all(&layer2).unwrap();
+        let gzip_layer2 = gzip_encoder.finish().unwrap();
+
+        let config = format!(
+            r#"{{"architecture": "amd64", "os": "linux", "rootfs": {{"type": "layers", "diff_ids": ["{}", "{}"]}}}}"#,
+            digest(&layer1),
+            digest(&layer2)
+        );
+        let manifest = r#"[{
+            "Config": "config.json",
+            "RepoTags": ["busybox:latest"],
+            "Layers": ["abc/layer.tar", "def/layer.tar"]
+        }]"#;
+
+        let mut ar = tar::Builder::new(fs::File::create(&path).unwrap());
+        append_file(&mut ar, "manifest.json", manifest.as_bytes());
+        append_file(&mut ar, "config.json", config.as_bytes());
+        append_file(&mut ar, "xyz/layer.tar", &layer1);
+        append_file(&mut ar, "def/layer.tar", &gzip_layer2);
+        // docker save links the layers shared with other images.
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_link_name("../xyz/layer.tar").unwrap();
+        header.set_cksum();
+        ar.append_data(&mut header, "abc/layer.tar", std::io::empty())
+            .unwrap();
+        ar.finish().unwrap();
+
+        let mut source = ArchiveSource::new(&path, &ImageConfig::default()).unwrap();
+        assert_eq!(source.format, ArchiveFormat::Docker);
+        assert_eq!(
+            source.reference(),
+            format!("docker-archive:{}", path.display())
+        );
+
+        let (manifest, _digest, image_config) = source.pull_manifest().await.unwrap();
+        assert_eq!(image_config, config);
+        assert_eq!(manifest.config.digest, digest(config.as_bytes()));
+
+        let tests = [
+            (layer1.clone(), digest(&layer1), MediaType::ImageLayer),
+            (
+                gzip_layer2.clone(),
+                digest(&gzip_layer2),
+                MediaType::ImageLayerGzip,
+            ),
+        ];
+        for (layer, (data, digest, media_type)) in manifest.layers.iter().zip(tests.iter()) {
+            assert_eq!(&layer.digest, digest);
+            assert_eq!(layer.media_type, media_type.to_string());
+            assert_eq!(layer.size, data.len() as i64);
+
+            let blob = source.fetch_blob(layer, &|_| {}).await.unwrap();
+            assert_eq!(blob.path, path);
+            assert!(!blob.temporary);
+            assert_eq!(&read_blob(&blob), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oci_archive() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("busybox.tar");
+
+        let layer = layer_tarball(&[("file.txt", Some("foo"))]);
+        let config = format!(
+            r#"{{"architecture": "amd64", "os": "linux", "rootfs": {{"type": "layers", "diff_ids": ["{}"]}}}}"#,
+            digest(&layer)
+        );
+        let manifest = OciImageManifest {
+            config: OciDescriptor {
+                media_type: MediaType::ImageConfig.to_string(),
+                digest: digest(config.as_bytes()),
+                size: config.len() as i64,
+                ..Default::default()
+            },
+            layers: vec![OciDescriptor {
+                media_type: MediaType::ImageLayer.to_string(),
+                digest: digest(&layer),
+                size: layer.len() as i64,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": MediaType::ImageManifest.to_string(),
+                "digest": digest(&manifest),
+                "size": manifest.len()
+            }]
+        });
+
+        let mut ar = tar::Builder::new(fs::File::create(&path).unwrap());
+        append_file(
+            &mut ar,
+            "./oci-layout",
+            br#"{"imageLayoutVersion": "1.0.0"}"#,
+        );
+        append_file(&mut ar, "./index.json", index.to_string().as_bytes());
+        for data in [manifest.as_slice(), config.as_bytes(), layer.as_slice()].iter() {
+            let name = digest(data).replace("sha256:", "blobs/sha256/");
+            append_file(&mut ar, &name, data);
+        }
+        ar.finish().unwrap();
+
+        let mut source = ArchiveSource::new(&path, &ImageConfig::default()).unwrap();
+        assert_eq!(source.format, ArchiveFormat::Oci);
+
+        let (image_manifest, image_digest, image_config) = source.pull_manifest().await.unwrap();
+        assert_eq!(image_digest, digest(&manifest));
+        assert_eq!(image_config, config);
+
+        let blob = source
+            .fetch_blob(&image_manifest.layers[0], &|_| {})
+            .await
+            .unwrap();
+        assert_eq!(read_blob(&blob), layer);
+
+        // A compressed archive can not be read in place.
+        let gzip_path = tempdir.path().join("busybox.tar.gz");
+        let mut gzip_encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip_encoder.write_all(&fs::read(&path).unwrap()).unwrap();
+        fs::write(&gzip_path, gzip_encoder.finish().unwrap()).unwrap();
+        assert!(ArchiveSource::new(&gzip_path, &ImageConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let tests = [
+            ("./manifest.json", "manifest.json"),
+            ("abc/layer.tar", "abc/layer.tar"),
+            ("abc/../def/layer.tar", "def/layer.tar"),
+            ("/blobs/sha256/../../../etc", "etc"),
+        ];
+
+        for (path, normalized) in tests.iter() {
+            assert_eq!(normalize(Path::new(path)), PathBuf::from(normalized));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use oci_distribution::manifest::{OciDescriptor, OciImageIndex, OciImageManifest};
+use oci_spec::image::MediaType;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{ImageConfig, Platform};
+use crate::digest::Digest;
+use crate::source::{resolve_platform, ImageSource, LocalBlob, OCI_LAYOUT_TRANSPORT};
+
+/// The file marking the root of an OCI image layout.
+pub const OCI_LAYOUT_FILE: &str = "oci-layout";
+
+/// The entry point of an OCI image layout.
+const INDEX_FILE: &str = "index.json";
+
+/// The annotation of an index entry holding the tag of the image.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// The media type of Docker manifest lists.
+const DOCKER_MANIFEST_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// The LayoutSource reads images from a local OCI image layout directory,
+/// which is selected by a reference like `oci:/path/to/layout:tag`.
+pub struct LayoutSource {
+    /// The root dir of the image layout.
+    pub root: PathBuf,
+
+    /// The tag of the image in the `org.opencontainers.image.ref.name`
+    /// annotation. It can be omitted if the layout has only one image.
+    pub tag: Option<String>,
+
+    /// The platform to pick from a multi-platform image index.
+    pub platform: Platform,
+}
+
+impl LayoutSource {
+    /// Constructs a new LayoutSource with an `oci:` image reference.
+    pub fn new(image: &str, config: &ImageConfig) -> Result<LayoutSource> {
+        let layout = image
+            .strip_prefix(OCI_LAYOUT_TRANSPORT)
+            .ok_or_else(|| anyhow!("invalid oci layout reference {}", image))?;
+
+        // The tag follows the last colon, unless the colon is in the path.
+        let (root, tag) = match layout.rsplit_once(':') {
+            Some((root, tag)) if !tag.contains('/') => (root, Some(tag.to_string())),
+            _ => (layout, None),
+        };
+
+        if root.is_empty() {
+            return Err(anyhow!("invalid oci layout reference {}", image));
+        }
+
+        Ok(LayoutSource {
+            root: PathBuf::from(root),
+            tag,
+            platform: config.platform.clone(),
+        })
+    }
+
+    // Read a file of the layout by its path relative to the root.
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let path = self.root.join(path);
+        fs::read(&path).map_err(|e| anyhow!("failed to read {:?}: {}", path, e))
+    }
+}
+
+/// Get the path of a blob relative to the root of an image layout. The
+/// digest is validated so that the path never escapes the blobs dir.
+pub(crate) fn blob_path(digest: &str) -> Result<PathBuf> {
+    let digest = Digest::try_from(digest)?;
+
+    Ok(Path::new("blobs")
+        .join(digest.algorithm().to_string())
+        .join(digest.value()))
+}
+
+/// Read the image manifest, manifest digest and image config of the image
+/// with the tag from an image layout, whose files are read by read_file
+/// with their paths relative to the layout root. The tag can be omitted
+/// if the layout has only one image.
+pub(crate) fn read_image<F>(
+    read_file: F,
+    tag: &Option<String>,
+    platform: &Platform,
+) -> Result<(OciImageManifest, String, String)>
+where
+    F: Fn(&Path) -> Result<Vec<u8>>,
+{
+    read_file(Path::new(OCI_LAYOUT_FILE)).map_err(|e| anyhow!("not an oci image layout: {}", e))?;
+
+    // Read a manifest or config blob, and verify its digest.
+    let read_blob = |digest: &str| -> Result<Vec<u8>> {
+        let data = read_file(&blob_path(digest)?)?;
+        Digest::try_from(digest)?.verify(&data)?;
+
+        Ok(data)
+    };
+
+    let read_index = |data: &[u8]| -> Result<OciImageIndex> {
+        serde_json::from_slice(data).map_err(|e| anyhow!("failed to parse image index: {}", e))
+    };
+
+    let index = read_index(&read_file(Path::new(INDEX_FILE))?)?;
+    let entry = match tag {
+        Some(tag) => index.manifests.iter().find(|m| {
+            m.annotations
+                .as_ref()
+                .and_then(|a| a.get(REF_NAME_ANNOTATION))
+                .map_or(false, |name| name == tag)
+        }),
+        None if index.manifests.len() == 1 => index.manifests.first(),
+        None => {
+            return Err(anyhow!(
+                "a tag is needed to pick one of the {} images",
+                index.manifests.len()
+            ))
+        }
+    }
+    .ok_or_else(|| anyhow!("image {:?} not found", tag))?;
+
+    // Go through the nested image index of a multi-platform image.
+    let image_index = MediaType::ImageIndex.to_string();
+    let digest =
+        if entry.media_type == image_index || entry.media_type == DOCKER_MANIFEST_LIST_MEDIA_TYPE {
+            let nested = read_index(&read_blob(&entry.digest)?)?;
+            resolve_platform(platform, &nested.manifests)
+                .ok_or_else(|| anyhow!("no image of platform {} found", platform))?
+        } else {
+            entry.digest.clone()
+        };
+
+    let manifest: OciImageManifest = serde_json::from_slice(&read_blob(&digest)?)
+        .map_err(|e| anyhow!("failed to parse image manifest {}: {}", digest, e))?;
+
+    let config = String::from_utf8(read_blob(&manifest.config.digest)?)
+        .map_err(|e| anyhow!("invalid image config {}: {}", manifest.config.digest, e))?;
+
+    Ok((manifest, digest, config))
+}
+
+#[async_trait]
+impl ImageSource for LayoutSource {
+    fn reference(&self) -> String {
+        match &self.tag {
+            Some(tag) => format!("{}{}:{}", OCI_LAYOUT_TRANSPORT, self.root.display(), tag),
+            None => format!("{}{}", OCI_LAYOUT_TRANSPORT, self.root.display()),
+        }
+    }
+
+    async fn pull_manifest(&mut self) -> Result<(OciImageManifest, String, String)> {
+        read_image(|path| self.read_file(path), &self.tag, &self.platform)
+            .map_err(|e| anyhow!("failed to read image from {:?}: {}", self.root, e))
+    }
+
+    /// The layer blob is read from the layout directly, and is verified
+    /// by the pull client before it is unpacked.
+    async fn fetch_blob(
+        &self,
+        layer: &OciDescriptor,
+        progress: &(dyn Fn(u64) + Sync),
+    ) -> Result<LocalBlob> {
+        let path = self.root.join(blob_path(&layer.digest)?);
+        let metadata =
+            fs::metadata(&path).map_err(|e| anyhow!("failed to find blob {:?}: {}", path, e))?;
+        progress(metadata.len());
+
+        Ok(LocalBlob {
+            path,
+            offset: 0,
+            size: None,
+            temporary: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_source_new() {
+        let config = ImageConfig::default();
+
+        let tests = [
+            ("oci:/images/busybox:v1", "/images/busybox", Some("v1")),
+            ("oci:/images/busybox", "/images/busybox", None),
+            ("oci:images:latest", "images", Some("latest")),
+            ("oci:/images/a:b/busybox", "/images/a:b/busybox", None),
+        ];
+
+        for (image, root, tag) in tests.iter() {
+            let source = LayoutSource::new(image, &config).unwrap();
+            assert_eq!(source.root, PathBuf::from(root));
+            assert_eq!(source.tag.as_deref(), *tag);
+            assert_eq!(source.reference(), *image);
+        }
+
+        assert!(LayoutSource::new("oci:", &config).is_err());
+        assert!(LayoutSource::new("docker.io/busybox", &config).is_err());
+    }
+
+    #[test]
+    fn test_blob_path() {
+        let digest = "sha384:69704ef328d05a9f806b6b8502915e6a0a4faa4d72018dc42343f511490daf8a69704ef328d05a9f806b6b8502915e6a";
+        assert_eq!(
+            blob_path(digest).unwrap(),
+            PathBuf::from(digest.replace("sha384:", "blobs/sha384/"))
+        );
+        assert!(blob_path("sha256:../../etc/passwd").is_err());
+        assert!(blob_path("../sha256:0123").is_err());
+        assert!(blob_path("sha256:0123abcd").is_err());
+        assert!(blob_path("sha256").is_err());
+        assert!(blob_path("sha256:").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use oci_distribution::manifest::{ImageIndexEntry, OciDescriptor, OciImageManifest};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use crate::config::{ImageConfig, Platform};
+
+pub mod archive;
+pub mod layout;
+pub mod registry;
+
+use layout::LayoutSource;
+use registry::RegistrySource;
+
+/// The transport prefix of the references to OCI image layouts.
+pub const OCI_LAYOUT_TRANSPORT: &str = "oci:";
+
+/// A layer blob stored in a local file, or in a section of a local file
+/// like an image archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalBlob {
+    /// The path of the blob file.
+    pub path: PathBuf,
+
+    /// The offset of the blob in the file.
+    pub offset: u64,
+
+    /// The size of the blob, or None if the blob ends with the file.
+    pub size: Option<u64>,
+
+    /// Whether the blob file is owned by `image-rs` and is removed once
+    /// the layer is unpacked.
+    pub temporary: bool,
+}
+
+impl LocalBlob {
+    /// Open the blob for reading.
+    pub fn open(&self) -> Result<io::Take<fs::File>> {
+        let mut file = fs::File::open(&self.path)
+            .map_err(|e| anyhow!("failed to open blob {:?}: {}", self.path, e))?;
+        file.seek(SeekFrom::Start(self.offset))?;
+
+        Ok(file.take(self.size.unwrap_or(u64::MAX)))
+    }
+}
+
+/// An ImageSource provides the manifest, config and layer blobs of an
+/// image, like a remote registry or a local image layout.
+#[async_trait]
+pub trait ImageSource: Send + Sync {
+    // get the reference of the image on the source.
+    fn reference(&self) -> String;
+
+    // pull the image manifest of the configured platform, and return it
+    // with the manifest digest and the image config.
+    async fn pull_manifest(&mut self) -> Result<(OciImageManifest, String, String)>;
+
+    // fetch a layer blob into a local file, reporting the fetched bytes
+    // to progress.
+    async fn fetch_blob(
+        &self,
+        layer: &OciDescriptor,
+        progress: &(dyn Fn(u64) + Sync),
+    ) -> Result<LocalBlob>;
+}
+
+/// Get the image source of the image reference, an `oci:` reference is
+/// read from a local image layout, others are pulled from registries.
+pub fn new_source(
+    image: &str,
+    config: &ImageConfig,
+    auth_info: &Option<&str>,
+) -> Result<Box<dyn ImageSource>> {
+    if image.starts_with(OCI_LAYOUT_TRANSPORT) {
+        return Ok(Box::new(LayoutSource::new(image, config)?));
+    }
+
+    Ok(Box::new(RegistrySource::new(image, config, auth_info)?))
+}
+
+// Return the digest of the first manifest matching platform in an image index.
+pub(crate) fn resolve_platform(platform: &Platform, entries: &[ImageIndexEntry]) -> Option<String> {
+    entries
+        .iter()
+        .find(|entry| match &entry.platform {
+            Some(p) => platform.matches(&p.os, &p.architecture, p.variant.as_deref()),
+            None => false,
+        })
+        .map(|entry| entry.digest.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_platform() {
+        let entries: Vec<ImageIndexEntry> = serde_json::from_str(
+            r#"[
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 1,
+                    "digest": "sha256:amd64"
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 1,
+                    "digest": "sha256:amd64",
+                    "platform": {"architecture": "amd64", "os": "linux"}
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 1,
+                    "digest": "sha256:armv6",
+                    "platform": {"architecture": "arm", "os": "linux", "variant": "v6"}
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 1,
+                    "digest": "sha256:armv7",
+                    "platform": {"architecture": "arm", "os": "linux", "variant": "v7"}
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 1,
+                    "digest": "sha256:arm64",
+                    "platform": {"architecture": "arm64", "os": "linux"}
+                }
+            ]"#,
+        )
+        .unwrap();
+
+        let platform = |architecture: &str, variant: Option<&str>| Platform {
+            os: "linux".to_string(),
+            architecture: architecture.to_string(),
+            variant: variant.map(|v| v.to_string()),
+        };
+
+        let tests = [
+            (platform("amd64", None), Some("sha256:amd64")),
+            (platform("arm", Some("v7")), Some("sha256:armv7")),
+            (platform("arm", None), Some("sha256:armv6")),
+            (platform("arm64", Some("v8")), Some("sha256:arm64")),
+            (platform("s390x", None), None),
+        ];
+
+        for (platform, digest) in tests.iter() {
+            assert_eq!(
+                resolve_platform(platform, &entries).as_deref(),
+                *digest,
+                "{}",
+                platform
+            );
+        }
+    }
+}
@@ -0,0 +1,329 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use oci_distribution::client::{ClientConfig, ClientProtocol};
+use oci_distribution::manifest::{ImageIndexEntry, OciDescriptor, OciImageManifest};
+use oci_distribution::{secrets::RegistryAuth, Client, Reference};
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use crate::auth::DockerConfig;
+use crate::config::{ImageConfig, RetryConfig};
+use crate::error::Error;
+use crate::fetch::{Backoff, BlobFetcher};
+use crate::source::{resolve_platform, ImageSource, LocalBlob};
+
+/// The RegistrySource pulls images from remote OCI registries, through
+/// the configured mirrors first.
+pub struct RegistrySource {
+    /// `oci-distribuion` client to talk with remote OCI registry.
+    pub client: Client,
+
+    /// OCI registry auth info.
+    pub auth: RegistryAuth,
+
+    /// OCI image reference. It is the reference on the endpoint which
+    /// serves the image manifest once the manifest is pulled.
+    pub reference: Reference,
+
+    /// The image references on the configured mirrors followed by the
+    /// upstream registry, with their registry auth info.
+    pub endpoints: Vec<(Reference, RegistryAuth)>,
+
+    /// The dir to download the layer blobs to before they are unpacked.
+    pub download_dir: PathBuf,
+
+    /// The fetcher to download the layer blobs with.
+    pub fetcher: BlobFetcher,
+
+    /// The retry policy of the manifest fetches.
+    pub retry: RetryConfig,
+}
+
+impl RegistrySource {
+    /// Constructs a new RegistrySource with provided image reference,
+    /// `image-rs` config and optional remote registry auth info.
+    /// The auth info is only for the upstream registry. Without it, and
+    /// always for the mirrors, the registry credential is looked up in
+    /// the auth file of the config.
+    pub fn new(
+        image: &str,
+        config: &ImageConfig,
+        auth_info: &Option<&str>,
+    ) -> Result<RegistrySource> {
+        let reference = Reference::try_from(image)?;
+
+        // The upstream registry is always the last endpoint.
+        let config_endpoints = config.endpoints(&reference)?;
+        let upstream = config_endpoints.len() - 1;
+
+        // The mirrors always take their own credentials from the auth file.
+        let docker_config = match &config.auth_file {
+            Some(auth_file) if auth_info.is_none() || upstream > 0 => {
+                Some(DockerConfig::try_from(auth_file.as_path())?)
+            }
+            _ => None,
+        };
+
+        let mut endpoints = Vec::new();
+        let mut insecure_registries = Vec::new();
+        for (i, endpoint) in config_endpoints.into_iter().enumerate() {
+            let registry = endpoint.reference.registry().to_string();
+            let auth = match (auth_info, &docker_config) {
+                // The explicit auth info is for the upstream registry, and
+                // never sent to a mirror, which may be a third party host
+                // or even plain HTTP.
+                (Some(auth_info), _) if i == upstream => match auth_info.split_once(':') {
+                    Some((username, password)) => {
+                        RegistryAuth::Basic(username.to_string(), password.to_string())
+                    }
+                    None => {
+                        return Err(Error::Auth {
+                            registry,
+                            source: anyhow!("Invalid authentication info ({:?})", auth_info),
+                        }
+                        .into())
+                    }
+                },
+                (_, Some(docker_config)) => docker_config
+                    .credential(&endpoint.reference)
+                    .map_err(|source| Error::Auth { registry, source })?,
+                (_, None) => RegistryAuth::Anonymous,
+            };
+
+            if endpoint.insecure {
+                insecure_registries.push(endpoint.reference.registry().to_string());
+            }
+            endpoints.push((endpoint.reference, auth));
+        }
+
+        // Pick the manifest of the configured platform from an image index
+        // or a manifest list, before anything else is fetched.
+        let platform = config.platform.clone();
+        let client_config = ClientConfig {
+            protocol: ClientProtocol::HttpsExcept(insecure_registries.clone()),
+            platform_resolver: Some(Box::new(move |entries: &[ImageIndexEntry]| {
+                resolve_platform(&platform, entries)
+            })),
+            ..Default::default()
+        };
+        let client = Client::new(client_config);
+
+        let (reference, auth) = endpoints
+            .last()
+            .cloned()
+            .ok_or_else(|| anyhow!("no endpoint for image {}", image))?;
+
+        let fetcher = BlobFetcher::new(insecure_registries, config.retry.clone())?;
+
+        Ok(RegistrySource {
+            client,
+            auth,
+            reference,
+            endpoints,
+            download_dir: config.download_dir(),
+            fetcher,
+            retry: config.retry.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl ImageSource for RegistrySource {
+    fn reference(&self) -> String {
+        self.reference.whole()
+    }
+
+    /// The endpoints are tried in order, each with the configured retries,
+    /// and the layers are pulled from the first endpoint which serves
+    /// the manifest.
+    async fn pull_manifest(&mut self) -> Result<(OciImageManifest, String, String)> {
+        let mut last_err = anyhow!("no endpoint for image {}", self.reference);
+        for (reference, auth) in self.endpoints.iter() {
+            let what = format!("pull manifest of {}", reference);
+            let mut backoff = Backoff::new(&self.retry);
+            let result = loop {
+                match self.client.pull_manifest_and_config(reference, auth).await {
+                    Err(e) => match backoff.next_delay(&what, &e) {
+                        Some(delay) => tokio::time::sleep(delay).await,
+                        None => break Err(e),
+                    },
+                    result => break result,
+                }
+            };
+
+            match result {
+                Ok(manifest) => {
+                    self.reference = reference.clone();
+                    self.auth = auth.clone();
+                    return Ok(manifest);
+                }
+                Err(e) => {
+                    log::warn!("failed to pull manifest from {}: {}", reference, e);
+                    last_err = e;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// The blob is downloaded to a file first, so that a broken download
+    /// is resumed rather than started over.
+    async fn fetch_blob(
+        &self,
+        layer: &OciDescriptor,
+        progress: &(dyn Fn(u64) + Sync),
+    ) -> Result<LocalBlob> {
+        let path = self.download_dir.join(layer.digest.replace(':', "_"));
+        self.fetcher
+            .fetch_blob(
+                &self.reference,
+                &self.auth,
+                &layer.digest,
+                layer.size,
+                &path,
+                progress,
+            )
+            .await?;
+
+        Ok(LocalBlob {
+            path,
+            offset: 0,
+            size: None,
+            temporary: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::registry::TestRegistry;
+
+    #[test]
+    fn test_registry_source_auth() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let auth_file = tempdir.path().join("config.json");
+        std::fs::write(
+            &auth_file,
+            r#"{"auths": {"https://index.docker.io/v1/": {"auth": "Zm9vOmJhcg=="}}}"#,
+        )
+        .unwrap();
+
+        let config = ImageConfig {
+            work_dir: tempdir.path().to_path_buf(),
+            auth_file: Some(auth_file),
+            ..Default::default()
+        };
+
+        let source = RegistrySource::new("busybox", &config, &None).unwrap();
+        assert!(matches!(source.auth, RegistryAuth::Basic(u, p) if u == "foo" && p == "bar"));
+
+        // The explicit auth info takes precedence over the auth file.
+        let source = RegistrySource::new("busybox", &config, &Some("baz:qux")).unwrap();
+        assert!(matches!(source.auth, RegistryAuth::Basic(u, p) if u == "baz" && p == "qux"));
+
+        let source = RegistrySource::new("quay.io/foo/bar", &config, &None).unwrap();
+        assert!(matches!(source.auth, RegistryAuth::Anonymous));
+
+        let err = RegistrySource::new("busybox", &config, &Some("baz"))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Auth { registry, .. }) if registry == "docker.io"
+        ));
+    }
+
+    #[test]
+    fn test_registry_source_endpoints() {
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": "/var/lib/image-rs/",
+            "default_snapshot": "overlay",
+            "security_validate": false,
+            "registries": [{
+                "prefix": "docker.io/library",
+                "mirrors": [{"location": "localhost:5000/library", "insecure": true}]
+            }]
+        }))
+        .unwrap();
+
+        let source = RegistrySource::new("busybox", &config, &None).unwrap();
+        assert_eq!(source.endpoints.len(), 2);
+        assert_eq!(source.endpoints[0].0.registry(), "localhost:5000");
+
+        // The upstream registry is the reference before the manifest is pulled.
+        assert_eq!(source.reference(), "docker.io/library/busybox:latest");
+    }
+
+    #[tokio::test]
+    async fn test_registry_source_mirror_auth() {
+        let registry = TestRegistry::start().await;
+        registry.push_image("foo", "latest", b"layer");
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": tempdir.path(),
+            "registries": [{
+                "prefix": "example.com",
+                "mirrors": [{"location": registry.host(), "insecure": true}]
+            }]
+        }))
+        .unwrap();
+
+        let mut source =
+            RegistrySource::new("example.com/foo:latest", &config, &Some("user:secret")).unwrap();
+        assert!(matches!(source.endpoints[0].1, RegistryAuth::Anonymous));
+        assert!(matches!(&source.endpoints[1].1, RegistryAuth::Basic(u, _) if u == "user"));
+
+        source.pull_manifest().await.unwrap();
+        assert_eq!(
+            source.reference(),
+            format!("{}/foo:latest", registry.host())
+        );
+
+        // The upstream credentials never reach the plain HTTP mirror.
+        assert!(!registry.requests().is_empty());
+        assert!(registry.authorized_requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_registry_source_mirror_not_found() {
+        let mirror = TestRegistry::start().await;
+        let registry = TestRegistry::start().await;
+        registry.push_image("foo", "latest", b"layer");
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config: ImageConfig = serde_json::from_value(serde_json::json!({
+            "work_dir": tempdir.path(),
+            "retry": {"max_retries": 3, "initial_backoff_ms": 10},
+            "registries": [{
+                "prefix": "example.com",
+                "mirrors": [
+                    {"location": mirror.host(), "insecure": true},
+                    {"location": registry.host(), "insecure": true}
+                ]
+            }]
+        }))
+        .unwrap();
+
+        let mut source = RegistrySource::new("example.com/foo:latest", &config, &None).unwrap();
+        source.pull_manifest().await.unwrap();
+        assert_eq!(
+            source.reference(),
+            format!("{}/foo:latest", registry.host())
+        );
+
+        // The missing manifest is not retried on the first mirror.
+        let manifest_requests = mirror
+            .requests()
+            .into_iter()
+            .filter(|r| r.contains("/manifests/"))
+            .count();
+        assert_eq!(manifest_requests, 1);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, Read};
+
+use crate::digest::{Digest, DigestHasher};
+
+/// A `Read` adaptor which hashes all the data read through it, so that
+/// layer digests can be verified while the layer is being streamed.
+pub struct HashReader<R> {
+    inner: R,
+    hasher: DigestHasher,
+}
+
+impl<R> HashReader<R> {
+    pub fn new(inner: R, hasher: DigestHasher) -> Self {
+        HashReader { inner, hasher }
+    }
+
+    /// Return the digest of the data read so far.
+    pub fn digest(self) -> Digest {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A `Read` adaptor which calls `on_eof` once the end of the data is
+/// read, like to report that a layer is decrypted as soon as it is.
+pub struct EofReader<R, F: FnOnce()> {
+    inner: R,
+    on_eof: Option<F>,
+}
+
+impl<R, F: FnOnce()> EofReader<R, F> {
+    pub fn new(inner: R, on_eof: F) -> Self {
+        EofReader {
+            inner,
+            on_eof: Some(on_eof),
+        }
+    }
+}
+
+impl<R: Read, F: FnOnce()> Read for EofReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            if let Some(on_eof) = self.on_eof.take() {
+                on_eof();
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::Algorithm;
+    use sha2::Digest as _;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_hash_reader() {
+        let data = b"This is some text!".to_vec();
+
+        let tests = [
+            (
+                Algorithm::Sha256,
+                format!("sha256:{:x}", sha2::Sha256::digest(&data)),
+            ),
+            (
+                Algorithm::Sha384,
+                format!("sha384:{:x}", sha2::Sha384::digest(&data)),
+            ),
+            (
+                Algorithm::Sha512,
+                format!("sha512:{:x}", sha2::Sha512::digest(&data)),
+            ),
+        ];
+
+        for (algorithm, digest) in tests.iter() {
+            let hasher = DigestHasher::new(*algorithm);
+            let mut reader = HashReader::new(data.as_slice(), hasher);
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, data);
+            assert_eq!(reader.digest().to_string(), *digest);
+        }
+    }
+
+    #[test]
+    fn test_eof_reader() {
+        let eofs = Cell::new(0);
+        let mut reader = EofReader::new(&b"data"[..], || eofs.set(eofs.get() + 1));
+
+        let mut buf = [0u8; 2];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(reader.read(&mut []).unwrap(), 0);
+        assert_eq!(eofs.get(), 0);
+
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"ta");
+        assert_eq!(eofs.get(), 1);
+
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(eofs.get(), 1);
+    }
+}
@@ -2,32 +2,327 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use libc::timeval;
+use nix::sys::stat::{fchmodat, makedev, mknod, FchmodatFlags, Mode, SFlag};
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::ffi::CString;
+use std::ffi::{CString, OsStr, OsString};
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
 use tar::Archive;
+use walkdir::WalkDir;
 
-/// Unpack the contents of tarball to the destination path
-pub fn unpack(input: Vec<u8>, destination: &Path) -> Result<()> {
-    let mut archive = Archive::new(input.as_slice());
+use crate::error::Result;
+
+/// The name prefix of an OCI whiteout file, which marks the file named by
+/// the rest of its name as removed from the lower layers.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// The name of an OCI opaque whiteout file, which marks its parent directory
+/// as opaque, hiding all the directory content from the lower layers.
+pub const WHITEOUT_OPAQUE_DIR: &str = ".wh..wh..opq";
+
+const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+/// The ioctl to share the extents of a file with another file, _IOW(0x94, 9, int).
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+/// The extended attributes restored from the layers, an entry ending with
+/// `.` allows a whole namespace. The other xattrs, like `trusted.*` and
+/// `security.selinux`, only make sense on the host building the image and
+/// are dropped.
+pub const XATTR_ALLOWLIST: &[&str] = &[
+    "user.",
+    CAPABILITY_XATTR,
+    ACL_ACCESS_XATTR,
+    ACL_DEFAULT_XATTR,
+];
+
+const CAPABILITY_XATTR: &str = "security.capability";
+const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+// The PAX records carrying the xattrs and the star style POSIX ACLs.
+const PAX_SCHILY_XATTR: &str = "SCHILY.xattr.";
+const PAX_SCHILY_ACL_ACCESS: &str = "SCHILY.acl.access";
+const PAX_SCHILY_ACL_DEFAULT: &str = "SCHILY.acl.default";
+
+// The layout of the POSIX ACL xattrs, see include/uapi/linux/posix_acl_xattr.h.
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+// The revision of the file capabilities carrying the root uid of the
+// user namespace, see include/uapi/linux/capability.h.
+const VFS_CAP_REVISION_MASK: u32 = 0xFF00_0000;
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+
+/// The max number of symlinks followed to resolve a path, like the
+/// MAXSYMLINKS of the kernel.
+const MAX_SYMLINKS: usize = 40;
+
+const DEFAULT_MAX_ENTRIES: u64 = 1 << 20;
+const DEFAULT_MAX_SIZE: u64 = 64 << 30;
+const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// The format that the OCI whiteout entries of a layer are stored in
+/// after unpack, which depends on the snapshot consuming the layer.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WhiteoutFormat {
+    /// overlayfs whiteouts: a 0/0 character device for a removed entry and
+    /// the `trusted.overlay.opaque` xattr for an opaque directory.
+    Overlay,
+
+    /// The OCI `.wh.` files are kept as is, and turned into real deletions
+    /// when the layers are copied into one rootfs with [`apply_layer`].
+    Oci,
+}
+
+impl Default for WhiteoutFormat {
+    fn default() -> WhiteoutFormat {
+        WhiteoutFormat::Overlay
+    }
+}
+
+/// A range of ids in a user namespace mapped to the host, like an entry
+/// of the OCI runtime spec `linux.uidMappings`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct IdMapping {
+    /// The first id of the range in the container.
+    #[serde(rename = "containerID")]
+    pub container_id: u32,
+
+    /// The first id of the range on the host.
+    #[serde(rename = "hostID")]
+    pub host_id: u32,
+
+    /// The number of ids in the range.
+    pub size: u32,
+}
+
+/// The uid and gid mappings to shift the file ownership of the layers
+/// with. The layers are unpacked with the ids in the tarballs as is if
+/// there is no mapping.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IdMappings {
+    pub uid_mappings: Vec<IdMapping>,
+    pub gid_mappings: Vec<IdMapping>,
+}
+
+impl IdMappings {
+    /// Whether the ids are kept as is.
+    pub fn is_empty(&self) -> bool {
+        self.uid_mappings.is_empty() && self.gid_mappings.is_empty()
+    }
+
+    /// Check that the ranges neither overflow nor overlap in the
+    /// container or on the host.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for mappings in [&self.uid_mappings, &self.gid_mappings].iter() {
+            for (i, m) in mappings.iter().enumerate() {
+                if m.size == 0
+                    || m.container_id.checked_add(m.size - 1).is_none()
+                    || m.host_id.checked_add(m.size - 1).is_none()
+                {
+                    return Err(anyhow!("invalid id mapping {:?}", m));
+                }
+
+                let overlaps = |a: u32, b: u32, size_a: u32, size_b: u32| {
+                    (a as u64) < b as u64 + size_b as u64 && (b as u64) < a as u64 + size_a as u64
+                };
+                for other in mappings[..i].iter() {
+                    if overlaps(m.container_id, other.container_id, m.size, other.size)
+                        || overlaps(m.host_id, other.host_id, m.size, other.size)
+                    {
+                        return Err(anyhow!("overlapped id mappings {:?} {:?}", m, other));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Map the uid in the container to the host.
+    pub fn map_uid(&self, uid: u32) -> anyhow::Result<u32> {
+        map_id(&self.uid_mappings, uid).ok_or_else(|| anyhow!("uid {} is not mapped", uid))
+    }
+
+    /// Map the gid in the container to the host.
+    pub fn map_gid(&self, gid: u32) -> anyhow::Result<u32> {
+        map_id(&self.gid_mappings, gid).ok_or_else(|| anyhow!("gid {} is not mapped", gid))
+    }
+
+    /// Get a short id of the mappings to tell apart the layers unpacked
+    /// with different mappings, it is empty if there is no mapping.
+    pub fn id(&self) -> String {
+        if self.is_empty() {
+            return String::new();
+        }
+
+        let data = serde_json::to_vec(self).unwrap_or_default();
+        format!("{:x}", Sha256::digest(&data))[..12].to_string()
+    }
+}
+
+// An id without any mapping is kept as is.
+fn map_id(mappings: &[IdMapping], id: u32) -> Option<u32> {
+    if mappings.is_empty() {
+        return Some(id);
+    }
+
+    mappings
+        .iter()
+        .find(|m| id >= m.container_id && id - m.container_id < m.size)
+        .map(|m| m.host_id + (id - m.container_id))
+}
+
+/// The limits of a layer tarball to unpack, against the layers crafted
+/// to exhaust the disk space or the inodes of the host.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct UnpackLimits {
+    /// The max number of entries in a layer.
+    pub max_entries: u64,
+
+    /// The max total size in bytes of the files in a layer.
+    pub max_size: u64,
+
+    /// The max number of components of an entry path.
+    pub max_depth: usize,
+}
+
+impl Default for UnpackLimits {
+    fn default() -> Self {
+        UnpackLimits {
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_size: DEFAULT_MAX_SIZE,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl UnpackLimits {
+    /// Check that the limits are all greater than 0.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_entries == 0 || self.max_size == 0 || self.max_depth == 0 {
+            return Err(anyhow!("invalid unpack limits {:?}", self));
+        }
+
+        Ok(())
+    }
+}
+
+/// The options to unpack a layer with.
+#[derive(Clone, Debug)]
+pub struct UnpackOptions {
+    /// The format to store the whiteouts in.
+    pub whiteout: WhiteoutFormat,
+
+    /// The mappings to shift the file ownership with.
+    pub id_mappings: IdMappings,
+
+    /// The limits of the layer tarball.
+    pub limits: UnpackLimits,
+}
+
+impl UnpackOptions {
+    /// Construct the UnpackOptions keeping the file ownership as is,
+    /// with the default limits.
+    pub fn new(whiteout: WhiteoutFormat) -> Self {
+        UnpackOptions {
+            whiteout,
+            id_mappings: IdMappings::default(),
+            limits: UnpackLimits::default(),
+        }
+    }
+}
+
+/// Unpack the contents of tarball to the destination path. The owner of
+/// each file is shifted with the id mappings of the options.
+///
+/// The layer is untrusted: an entry path or a hard link target with `..`
+/// is rejected, and the symlinks unpacked earlier are resolved in the
+/// destination as the root, so nothing is ever written out of it. The
+/// device nodes are skipped without the privilege to create them.
+pub fn unpack<R: io::Read>(input: R, destination: &Path, options: &UnpackOptions) -> Result<()> {
+    let mut archive = Archive::new(input);
 
     if destination.exists() {
-        return Err(anyhow!(
-            "unpack destination {:?} already exists",
-            destination
-        ));
+        return Err(anyhow!("unpack destination {:?} already exists", destination).into());
     }
 
     fs::create_dir_all(destination)?;
 
-    let mut dirs: HashMap<CString, [timeval; 2]> = HashMap::default();
+    let limits = &options.limits;
+    let mut entries = 0;
+    let mut size = 0;
+    let mut dirs: HashMap<PathBuf, [timeval; 2]> = HashMap::default();
     for file in archive.entries()? {
         let mut file = file?;
-        file.unpack_in(destination)?;
+        let xattrs = entry_xattrs(&mut file)?;
+
+        entries += 1;
+        size += file.size();
+        if entries > limits.max_entries {
+            return Err(anyhow!("layer has more than {} entries", limits.max_entries).into());
+        }
+        if size > limits.max_size {
+            return Err(anyhow!("layer is larger than {} bytes", limits.max_size).into());
+        }
+
+        let path = entry_path(&file.path()?, limits.max_depth)?;
+
+        // The root dir itself.
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        // A whiteout removing anything out of its dir is rejected in any
+        // format, so that it never reaches apply_layer.
+        let whiteout = parse_whiteout(name)?;
+        if options.whiteout == WhiteoutFormat::Overlay {
+            if let Some(whiteout) = whiteout {
+                convert_whiteout(&path, &whiteout, destination)?;
+                continue;
+            }
+        }
+
+        let target = entry_in_root(destination, &path)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if !unpack_entry(&mut file, &target, destination)? {
+            continue;
+        }
+
+        if !options.id_mappings.is_empty() {
+            chown_entry(file.header(), &target, &options.id_mappings)?;
+        }
+
+        // The xattrs are set after chown, which clears the file capabilities.
+        if !xattrs.is_empty() && !file.header().entry_type().is_hard_link() {
+            for (name, value) in xattrs.iter() {
+                let value = map_xattr(name, value, &options.id_mappings)?;
+                set_xattr(&target, name, &value)?;
+            }
+        }
 
         // tar-rs crate only preserve timestamps of files,
         // symlink file and directory are not covered.
@@ -39,39 +334,726 @@ pub fn unpack(input: Vec<u8>, destination: &Path) -> Result<()> {
                 tv_sec: mtime,
                 tv_usec: 0,
             };
-            let path = CString::new(format!(
-                "{}/{}",
-                destination.display(),
-                file.path()?.display()
-            ))?;
 
             let times = [atime, atime];
 
             if file.header().entry_type().is_dir() {
                 dirs.insert(path, times);
             } else {
-                let ret = unsafe { libc::lutimes(path.as_ptr(), times.as_ptr()) };
-                if ret != 0 {
-                    return Err(anyhow!(
-                        "change symlink file: {:?} utime error: {:?}",
-                        path,
-                        io::Error::last_os_error()
-                    ));
+                lutimes(&target, &times)?;
+            }
+        }
+    }
+
+    // Directory timestamps need update after all files are extracted. Any
+    // component of a directory path may be replaced by a symlink since, so
+    // the path is resolved in the destination again, and the directories
+    // replaced since are skipped.
+    for (path, times) in dirs.iter() {
+        let dir = entry_in_root(destination, path)?;
+        if fs::symlink_metadata(&dir).map_or(false, |m| m.is_dir()) {
+            lutimes(&dir, times)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Set the access and modification times of path, without following it if
+// it is a symlink.
+fn lutimes(path: &Path, times: &[timeval; 2]) -> anyhow::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    let ret = unsafe { libc::lutimes(c_path.as_ptr(), times.as_ptr()) };
+    if ret != 0 {
+        return Err(anyhow!(
+            "change {:?} utime error: {:?}",
+            path,
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+// Check the path of an entry, and get its normal components. A path out
+// of the destination or deeper than max_depth is rejected.
+fn entry_path(path: &Path, max_depth: usize) -> anyhow::Result<PathBuf> {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normal.push(name),
+            // The absolute paths are relative to the destination, like tar does.
+            Component::RootDir | Component::CurDir => {}
+            _ => return Err(anyhow!("invalid entry path {:?}", path)),
+        }
+    }
+
+    if normal.components().count() > max_depth {
+        return Err(anyhow!(
+            "entry path {:?} is deeper than {}",
+            path,
+            max_depth
+        ));
+    }
+
+    Ok(normal)
+}
+
+/// Resolve path in root like openat2 with `RESOLVE_IN_ROOT`: the symlinks
+/// are followed with root as `/`, and `..` never goes above root. So the
+/// resolved path always stays in root, whatever the symlinks unpacked from
+/// a layer point to. The missing components are kept as is.
+pub fn resolve_in_root(root: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    // The components to resolve, in reverse order, `..` is kept as is.
+    fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+        for component in path.components().rev() {
+            match component {
+                Component::Normal(name) => pending.push(name.to_os_string()),
+                Component::ParentDir => pending.push(OsString::from("..")),
+                _ => {}
+            }
+        }
+    }
+
+    let mut pending = Vec::new();
+    push_components(&mut pending, path);
+
+    let mut resolved = PathBuf::new();
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let current = root.join(&resolved).join(&name);
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(anyhow!("too many levels of symlinks in {:?}", path));
                 }
+
+                let target = fs::read_link(&current)?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                push_components(&mut pending, &target);
+            }
+            _ => resolved.push(name),
+        }
+    }
+
+    Ok(root.join(resolved))
+}
+
+// Resolve the path of an entry in root: its parent dir is resolved with
+// resolve_in_root, and the entry itself is never followed.
+fn entry_in_root(root: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    match path.file_name() {
+        Some(name) => {
+            let parent = resolve_in_root(root, path.parent().unwrap_or_else(|| Path::new("")))?;
+            Ok(parent.join(name))
+        }
+        None => Ok(root.to_path_buf()),
+    }
+}
+
+// Unpack the entry to target, the parent dir of which is resolved in the
+// destination already. Returns false if the entry is skipped.
+fn unpack_entry<R: io::Read>(
+    file: &mut tar::Entry<'_, R>,
+    target: &Path,
+    destination: &Path,
+) -> anyhow::Result<bool> {
+    let entry_type = file.header().entry_type();
+
+    // An existing symlink is replaced instead of followed, and so is
+    // anything else but a directory over a directory.
+    if let Ok(metadata) = fs::symlink_metadata(target) {
+        if !(metadata.is_dir() && entry_type.is_dir()) {
+            remove_path(target)?;
+        }
+    }
+
+    // tar-rs links to the target relative to the working dir, so the hard
+    // links are created here with the target in the destination.
+    if entry_type.is_hard_link() {
+        let link_name = file
+            .link_name()?
+            .ok_or_else(|| anyhow!("hard link {:?} has no target", target))?;
+        if !link_name
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(anyhow!("invalid hard link target {:?}", link_name));
+        }
+
+        // The link target itself is not followed, like link(2).
+        let parent = link_name.parent().unwrap_or_else(|| Path::new(""));
+        let source = match link_name.file_name() {
+            Some(name) => resolve_in_root(destination, parent)?.join(name),
+            None => return Err(anyhow!("invalid hard link target {:?}", link_name)),
+        };
+        fs::hard_link(&source, target)
+            .map_err(|e| anyhow!("hard link {:?} to {:?} error: {}", target, link_name, e))?;
+
+        return Ok(true);
+    }
+
+    match file.unpack(target) {
+        Ok(_) => Ok(true),
+        Err(e)
+            if e.kind() == io::ErrorKind::PermissionDenied
+                && (entry_type.is_character_special() || entry_type.is_block_special()) =>
+        {
+            log::warn!(
+                "skip device {:?} without the privilege to create it",
+                target
+            );
+            Ok(false)
+        }
+        Err(e) => Err(anyhow!("unpack {:?} error: {}", target, e)),
+    }
+}
+
+// Change the owner of the unpacked entry to its mapped ids, without
+// following symlinks. The mode is set again, as chown clears the setuid
+// and setgid bits.
+fn chown_entry(header: &tar::Header, path: &Path, id_mappings: &IdMappings) -> anyhow::Result<()> {
+    // The target of a hard link is changed with the target entry.
+    if header.entry_type().is_hard_link() {
+        return Ok(());
+    }
+    let uid = id_mappings.map_uid(header.uid()? as u32)?;
+    let gid = id_mappings.map_gid(header.gid()? as u32)?;
+
+    fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(uid)),
+        Some(Gid::from_raw(gid)),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .map_err(|e| anyhow!("chown {:?} to {}:{} error: {}", path, uid, gid, e))?;
+
+    let entry_type = header.entry_type();
+    if entry_type.is_file() || entry_type.is_dir() {
+        let mode = Mode::from_bits_truncate(header.mode()?);
+        fchmodat(None, path, mode, FchmodatFlags::FollowSymlink)
+            .map_err(|e| anyhow!("chmod {:?} error: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Whether the xattr name is allowed by [`XATTR_ALLOWLIST`].
+pub fn is_xattr_allowed(name: &str) -> bool {
+    XATTR_ALLOWLIST.iter().any(|allowed| {
+        if allowed.ends_with('.') {
+            name.starts_with(allowed)
+        } else {
+            name == *allowed
+        }
+    })
+}
+
+// Get the allowed xattrs of an entry from its PAX records, the raw xattrs
+// in `SCHILY.xattr.<name>` and the text ACLs in `SCHILY.acl.access` and
+// `SCHILY.acl.default`.
+fn entry_xattrs<R: io::Read>(
+    file: &mut tar::Entry<'_, R>,
+) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    let extensions = match file.pax_extensions()? {
+        Some(extensions) => extensions,
+        None => return Ok(xattrs),
+    };
+
+    for extension in extensions {
+        let extension = extension?;
+        let key = extension
+            .key()
+            .map_err(|e| anyhow!("invalid PAX record key: {}", e))?;
+        let (name, value) = if let Some(name) = key.strip_prefix(PAX_SCHILY_XATTR) {
+            (name, extension.value_bytes().to_vec())
+        } else if key == PAX_SCHILY_ACL_ACCESS || key == PAX_SCHILY_ACL_DEFAULT {
+            let text = extension
+                .value()
+                .map_err(|e| anyhow!("invalid PAX record {}: {}", key, e))?;
+            let name = if key == PAX_SCHILY_ACL_ACCESS {
+                ACL_ACCESS_XATTR
+            } else {
+                ACL_DEFAULT_XATTR
+            };
+            (name, acl_from_text(text)?)
+        } else {
+            continue;
+        };
+
+        if is_xattr_allowed(name) {
+            xattrs.push((name.to_string(), value));
+        } else {
+            log::debug!("skip xattr {} not in the allowlist", name);
+        }
+    }
+
+    Ok(xattrs)
+}
+
+// Convert a text ACL like `user::rwx,user:1000:r-x,group::r-x,mask::r-x,other::r--`
+// into the POSIX ACL xattr value. The named entries take the numeric id
+// from the optional fourth field or the qualifier, since the names of
+// the image may not exist on the host.
+fn acl_from_text(text: &str) -> anyhow::Result<Vec<u8>> {
+    let mut entries: Vec<(u16, u16, u32)> = Vec::new();
+
+    for entry in text.split(|c| c == ',' || c == '\n') {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = entry.split(':').collect();
+        let (tag, qualifier, perms, id) = match fields[..] {
+            [tag, qualifier, perms] => (tag, qualifier, perms, qualifier),
+            [tag, qualifier, perms, id] => (tag, qualifier, perms, id),
+            _ => return Err(anyhow!("invalid ACL entry {:?}", entry)),
+        };
+
+        let named = !qualifier.is_empty();
+        let tag = match (tag, named) {
+            ("user" | "u", false) => ACL_USER_OBJ,
+            ("user" | "u", true) => ACL_USER,
+            ("group" | "g", false) => ACL_GROUP_OBJ,
+            ("group" | "g", true) => ACL_GROUP,
+            ("mask" | "m", false) => ACL_MASK,
+            ("other" | "o", false) => ACL_OTHER,
+            _ => return Err(anyhow!("invalid ACL entry {:?}", entry)),
+        };
+        let id = if named {
+            id.parse::<u32>()
+                .map_err(|_| anyhow!("no numeric id in ACL entry {:?}", entry))?
+        } else {
+            ACL_UNDEFINED_ID
+        };
+
+        let mut perm = 0;
+        if perms.len() != 3 {
+            return Err(anyhow!("invalid ACL entry {:?}", entry));
+        }
+        for (i, (c, bit)) in perms.chars().zip(['r', 'w', 'x'].iter()).enumerate() {
+            match c {
+                '-' => {}
+                c if c == *bit => perm |= 4 >> i,
+                _ => return Err(anyhow!("invalid ACL entry {:?}", entry)),
             }
         }
+
+        entries.push((tag, perm, id));
     }
 
-    // Directory timestamps need update after all files are extracted.
-    for (k, v) in dirs.iter() {
-        let ret = unsafe { libc::utimes(k.as_ptr(), v.as_ptr()) };
-        if ret != 0 {
-            return Err(anyhow!(
-                "change directory: {:?} utime error: {:?}",
-                k,
-                io::Error::last_os_error()
-            ));
+    // The kernel expects the entries sorted by tag and id.
+    entries.sort_by_key(|(tag, _, id)| (*tag, *id));
+
+    let mut acl = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries.iter() {
+        acl.extend_from_slice(&tag.to_le_bytes());
+        acl.extend_from_slice(&perm.to_le_bytes());
+        acl.extend_from_slice(&id.to_le_bytes());
+    }
+
+    Ok(acl)
+}
+
+// Shift the ids in the value of an xattr with the id mappings: the named
+// users and groups of the POSIX ACLs, and the root uid of the v3 file
+// capabilities.
+fn map_xattr(name: &str, value: &[u8], id_mappings: &IdMappings) -> anyhow::Result<Vec<u8>> {
+    let mut value = value.to_vec();
+    if id_mappings.is_empty() {
+        return Ok(value);
+    }
+
+    let read_u32 = |data: &[u8]| u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+
+    if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+        if value.len() < 4 || (value.len() - 4) % 8 != 0 {
+            return Err(anyhow!("invalid xattr {} of {} bytes", name, value.len()));
         }
+
+        for entry in value[4..].chunks_mut(8) {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let id = read_u32(&entry[4..]);
+            let id = match tag {
+                ACL_USER => id_mappings.map_uid(id)?,
+                ACL_GROUP => id_mappings.map_gid(id)?,
+                _ => continue,
+            };
+            entry[4..].copy_from_slice(&id.to_le_bytes());
+        }
+    } else if name == CAPABILITY_XATTR
+        && value.len() == 24
+        && read_u32(&value) & VFS_CAP_REVISION_MASK == VFS_CAP_REVISION_3
+    {
+        let root_id = id_mappings.map_uid(read_u32(&value[20..]))?;
+        value[20..].copy_from_slice(&root_id.to_le_bytes());
+    }
+
+    Ok(value)
+}
+
+// An OCI whiteout entry.
+enum Whiteout<'a> {
+    // The parent dir of the whiteout is opaque.
+    Opaque,
+
+    // The entry of the name is removed from the parent dir of the whiteout.
+    Removed(&'a OsStr),
+}
+
+// Parse the file name of an entry as a whiteout, None if it is not one.
+// The removed name has to be a normal file name, or `.wh..` and `.wh...`
+// would remove the parent dir of the whiteout or the one above.
+fn parse_whiteout(name: &OsStr) -> anyhow::Result<Option<Whiteout<'_>>> {
+    let bytes = name.as_bytes();
+    if bytes == WHITEOUT_OPAQUE_DIR.as_bytes() {
+        return Ok(Some(Whiteout::Opaque));
+    }
+
+    let removed = match bytes.strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+        Some(removed) => removed,
+        None => return Ok(None),
+    };
+    if removed.is_empty() || removed == b"." || removed == b".." || removed.contains(&b'/') {
+        return Err(anyhow!("invalid whiteout {:?}", name));
+    }
+
+    Ok(Some(Whiteout::Removed(OsStr::from_bytes(removed))))
+}
+
+// Convert the OCI whiteout entry at path into the overlayfs format under
+// destination.
+fn convert_whiteout(path: &Path, whiteout: &Whiteout, destination: &Path) -> anyhow::Result<()> {
+    let parent = resolve_in_root(destination, path.parent().unwrap_or_else(|| Path::new("")))?;
+    fs::create_dir_all(&parent)?;
+
+    match whiteout {
+        Whiteout::Opaque => set_xattr(&parent, OVERLAY_OPAQUE_XATTR, b"y"),
+        Whiteout::Removed(removed) => {
+            let target = parent.join(removed);
+            mknod(&target, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))
+                .map_err(|e| anyhow!("create whiteout {:?} error: {}", target, e))
+        }
+    }
+}
+
+// Set the extended attribute name of path, without following symlinks.
+pub(crate) fn set_xattr(path: &Path, name: &str, value: &[u8]) -> anyhow::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+
+    let ret = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "set xattr {} of {:?} error: {:?}",
+            name,
+            path,
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+// Get the value of the extended attribute name of path, without following
+// symlinks. Returns None if path has no such xattr.
+fn get_xattr(path: &Path, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+
+    let size =
+        unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENODATA) {
+            return Ok(None);
+        }
+        return Err(anyhow!("get xattr {} of {:?} error: {:?}", name, path, err));
+    }
+
+    let mut value = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::lgetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if size < 0 {
+        return Err(anyhow!(
+            "get xattr {} of {:?} error: {:?}",
+            name,
+            path,
+            io::Error::last_os_error()
+        ));
+    }
+    value.truncate(size as usize);
+
+    Ok(Some(value))
+}
+
+// List the names of the extended attributes of path, without following
+// symlinks.
+fn list_xattrs(path: &Path) -> anyhow::Result<Vec<String>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let list_error = || {
+        anyhow!(
+            "list xattrs of {:?} error: {:?}",
+            path,
+            io::Error::last_os_error()
+        )
+    };
+
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        return Err(list_error());
+    }
+
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::llistxattr(
+            c_path.as_ptr(),
+            names.as_mut_ptr() as *mut libc::c_char,
+            names.len(),
+        )
+    };
+    if size < 0 {
+        return Err(list_error());
+    }
+    names.truncate(size as usize);
+
+    Ok(names
+        .split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect())
+}
+
+// Copy the allowed xattrs of src to dst.
+fn copy_xattrs(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    for name in list_xattrs(src)? {
+        if !is_xattr_allowed(&name) {
+            continue;
+        }
+        if let Some(value) = get_xattr(src, &name)? {
+            set_xattr(dst, &name, &value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy a layer unpacked in [`WhiteoutFormat::Oci`] onto target, in which
+/// the lower layers have already been applied. The whiteouts of the layer
+/// are applied as real deletions of the content from the lower layers.
+///
+/// The copies keep the timestamps and the hard links of the layer, and its
+/// owners if the caller is privileged to chown. The paths are resolved in
+/// target like [`unpack`] does, whatever symlinks the lower layers have.
+pub fn apply_layer(layer: &Path, target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+
+    let privileged = Uid::effective().is_root();
+
+    // Directory permissions and timestamps are applied after all the
+    // content is copied, in case a directory is read only.
+    let mut dirs: Vec<(PathBuf, fs::Metadata)> = Vec::new();
+
+    // The first copy of each file with hard links in the layer, by inode.
+    let mut links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+
+    for entry in WalkDir::new(layer).follow_links(false) {
+        let entry = entry.map_err(io::Error::from)?;
+        let path = entry.path().strip_prefix(layer).map_err(|e| anyhow!(e))?;
+        let dest = entry_in_root(target, path)?;
+        let file_type = entry.file_type();
+        let metadata = entry.metadata().map_err(io::Error::from)?;
+
+        if file_type.is_dir() {
+            if fs::symlink_metadata(&dest).map_or(false, |m| !m.is_dir()) {
+                fs::remove_file(&dest)?;
+            }
+            fs::create_dir_all(&dest)?;
+
+            // An opaque directory hides everything from the lower layers, and
+            // is always visited before its own content.
+            if fs::symlink_metadata(entry.path().join(WHITEOUT_OPAQUE_DIR)).is_ok() {
+                for child in fs::read_dir(&dest)? {
+                    remove_path(&child?.path())?;
+                }
+            }
+
+            copy_owner(&metadata, &dest, privileged)?;
+            copy_xattrs(entry.path(), &dest)?;
+            dirs.push((path.to_path_buf(), metadata));
+            continue;
+        }
+
+        match parse_whiteout(entry.file_name())? {
+            Some(Whiteout::Opaque) => continue,
+            Some(Whiteout::Removed(removed)) => {
+                remove_path(&dest.with_file_name(removed))?;
+                continue;
+            }
+            None => {}
+        }
+
+        remove_path(&dest)?;
+
+        if file_type.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &dest)?;
+        } else if file_type.is_file() {
+            let inode = (metadata.dev(), metadata.ino());
+            if metadata.nlink() > 1 {
+                if let Some(first) = links.get(&inode) {
+                    fs::hard_link(first, &dest)?;
+                    continue;
+                }
+                links.insert(inode, dest.clone());
+            }
+            copy_file(entry.path(), &dest)?;
+        } else {
+            log::warn!("skip copying special file {:?}", entry.path());
+            continue;
+        }
+
+        // The xattrs are set after chown, which clears the file capabilities.
+        copy_owner(&metadata, &dest, privileged)?;
+        if file_type.is_file() {
+            copy_xattrs(entry.path(), &dest)?;
+        }
+        copy_times(&metadata, &dest)?;
+    }
+
+    // The dirs are resolved again, and the ones replaced since are skipped.
+    for (path, metadata) in dirs.iter().rev() {
+        let dir = entry_in_root(target, path)?;
+        if fs::symlink_metadata(&dir).map_or(false, |m| m.is_dir()) {
+            fs::set_permissions(&dir, metadata.permissions())?;
+            copy_times(metadata, &dir)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Give dst the owner of the source with metadata if privileged. Otherwise
+// dst is owned by the caller, and a regular file loses the setuid and
+// setgid bits of any other owner, which the caller must not gain. The
+// mode is set again, as chown clears the setuid and setgid bits.
+fn copy_owner(metadata: &fs::Metadata, dst: &Path, privileged: bool) -> anyhow::Result<()> {
+    if privileged {
+        fchownat(
+            None,
+            dst,
+            Some(Uid::from_raw(metadata.uid())),
+            Some(Gid::from_raw(metadata.gid())),
+            FchownatFlags::NoFollowSymlink,
+        )
+        .map_err(|e| anyhow!("chown {:?} error: {}", dst, e))?;
+        if metadata.is_file() {
+            fs::set_permissions(dst, metadata.permissions())?;
+        }
+        return Ok(());
+    }
+
+    if metadata.is_file() {
+        let mut mode = metadata.mode() & 0o7777;
+        if metadata.uid() != Uid::effective().as_raw() {
+            mode &= !libc::S_ISUID;
+        }
+        if metadata.gid() != Gid::effective().as_raw() {
+            mode &= !libc::S_ISGID;
+        }
+        fs::set_permissions(dst, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(())
+}
+
+// Set the access and modification times of dst to the ones of the source
+// with metadata, without following symlinks.
+fn copy_times(metadata: &fs::Metadata, dst: &Path) -> anyhow::Result<()> {
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as libc::time_t,
+            tv_nsec: metadata.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as libc::time_t,
+            tv_nsec: metadata.mtime_nsec() as _,
+        },
+    ];
+    let c_path = CString::new(dst.as_os_str().as_bytes())?;
+
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "change {:?} utime error: {:?}",
+            dst,
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+// Copy the regular file src to a new file dst together with its
+// permissions. The data is reflinked with FICLONE if the filesystem
+// supports it, and copied otherwise.
+fn copy_file(src: &Path, dst: &Path) -> io::Result<()> {
+    let mut source = fs::File::open(src)?;
+    let permissions = source.metadata()?.permissions();
+    let mut dest = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(permissions.mode())
+        .open(dst)?;
+
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, source.as_raw_fd()) };
+    if ret != 0 {
+        io::copy(&mut source, &mut dest)?;
+    }
+
+    // The mode of a new file is masked by the umask.
+    dest.set_permissions(permissions)
+}
+
+// Remove path whatever its file type is, a missing path is not an error.
+fn remove_path(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
     }
 
     Ok(())
@@ -83,7 +1065,35 @@ mod tests {
     use filetime;
     use std::fs::File;
     use std::io::prelude::*;
+    use std::os::unix::fs::FileTypeExt;
     use tempfile;
+    use test_utils::layer::{layer_tarball, layer_tarball_with_owner};
+    use test_utils::skip_if_not_root;
+
+    fn lower_layer() -> Vec<u8> {
+        layer_tarball(&[
+            ("a", None),
+            ("a/f1", Some("f1")),
+            ("a/f2", Some("f2")),
+            ("b", Some("b")),
+            ("c", None),
+            ("c/d", None),
+            ("c/d/e", Some("e")),
+            ("g", None),
+            ("g/h", Some("h")),
+        ])
+    }
+
+    fn upper_layer() -> Vec<u8> {
+        layer_tarball(&[
+            ("a/.wh.f1", Some("")),
+            (".wh.b", Some("")),
+            ("c", None),
+            ("c/.wh..wh..opq", Some("")),
+            ("c/new", Some("new")),
+            ("g", Some("g is a file now")),
+        ])
+    }
 
     #[test]
     fn test_unpack() {
@@ -116,7 +1126,12 @@ mod tests {
             fs::remove_dir_all(destination).unwrap();
         }
 
-        assert!(unpack(data.clone(), destination).is_ok());
+        assert!(unpack(
+            data.as_slice(),
+            destination,
+            &UnpackOptions::new(WhiteoutFormat::Oci)
+        )
+        .is_ok());
 
         let path = destination.join("file.txt");
         let metadata = fs::metadata(&path).unwrap();
@@ -129,6 +1144,670 @@ mod tests {
         assert_eq!(mtime, new_mtime);
 
         // destination already exists
-        assert!(unpack(data, destination).is_err());
+        assert!(unpack(
+            data.as_slice(),
+            destination,
+            &UnpackOptions::new(WhiteoutFormat::Oci)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_unpack_whiteout_apply_layers() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let lower = tempdir.path().join("lower");
+        let upper = tempdir.path().join("upper");
+        let rootfs = tempdir.path().join("rootfs");
+
+        unpack(
+            lower_layer().as_slice(),
+            &lower,
+            &UnpackOptions::new(WhiteoutFormat::Oci),
+        )
+        .unwrap();
+        unpack(
+            upper_layer().as_slice(),
+            &upper,
+            &UnpackOptions::new(WhiteoutFormat::Oci),
+        )
+        .unwrap();
+
+        // The OCI whiteout files are kept in the unpacked layer.
+        assert!(upper.join("a").join(".wh.f1").exists());
+        assert!(upper.join("c").join(WHITEOUT_OPAQUE_DIR).exists());
+
+        apply_layer(&lower, &rootfs).unwrap();
+        assert!(rootfs.join("a").join("f1").exists());
+        assert!(rootfs.join("c").join("d").join("e").exists());
+
+        apply_layer(&upper, &rootfs).unwrap();
+
+        // Removed files.
+        assert!(!rootfs.join("a").join("f1").exists());
+        assert!(!rootfs.join("b").exists());
+        assert_eq!(
+            fs::read_to_string(rootfs.join("a").join("f2")).unwrap(),
+            "f2"
+        );
+
+        // Opaque directory.
+        assert!(!rootfs.join("c").join("d").exists());
+        assert_eq!(
+            fs::read_to_string(rootfs.join("c").join("new")).unwrap(),
+            "new"
+        );
+
+        // Directory replaced by a file.
+        assert_eq!(
+            fs::read_to_string(rootfs.join("g")).unwrap(),
+            "g is a file now"
+        );
+
+        // No whiteout files leak into the rootfs.
+        for entry in WalkDir::new(&rootfs) {
+            let entry = entry.unwrap();
+            let name = entry.file_name().to_string_lossy();
+            assert!(!name.starts_with(WHITEOUT_PREFIX), "{:?}", entry.path());
+        }
+    }
+
+    #[test]
+    fn test_copy_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let src = tempdir.path().join("src");
+        let dst = tempdir.path().join("dst");
+
+        fs::write(&src, "data").unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o4755)).unwrap();
+
+        copy_file(&src, &dst).unwrap();
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "data");
+        assert_eq!(fs::metadata(&dst).unwrap().mode() & 0o7777, 0o4755);
+
+        // The destination is never overwritten.
+        assert!(copy_file(&src, &dst).is_err());
+    }
+
+    #[test]
+    fn test_unpack_whiteout_overlay() {
+        skip_if_not_root!();
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let upper = tempdir.path().join("upper");
+
+        unpack(
+            upper_layer().as_slice(),
+            &upper,
+            &UnpackOptions::new(WhiteoutFormat::Overlay),
+        )
+        .unwrap();
+
+        for path in [upper.join("a").join("f1"), upper.join("b")].iter() {
+            let metadata = fs::symlink_metadata(path).unwrap();
+            assert!(metadata.file_type().is_char_device(), "{:?}", path);
+            assert_eq!(metadata.rdev(), 0, "{:?}", path);
+        }
+
+        assert!(!upper.join("a").join(".wh.f1").exists());
+        assert!(!upper.join(".wh.b").exists());
+        assert!(!upper.join("c").join(WHITEOUT_OPAQUE_DIR).exists());
+        assert!(upper.join("c").join("new").exists());
+
+        let c_path = CString::new(upper.join("c").as_os_str().as_bytes()).unwrap();
+        let c_name = CString::new(OVERLAY_OPAQUE_XATTR).unwrap();
+        let mut value = [0u8; 8];
+        let len = unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        assert_eq!(len, 1);
+        assert_eq!(&value[..1], b"y");
+    }
+
+    #[test]
+    fn test_id_mappings() {
+        let mapping = |container_id, host_id, size| IdMapping {
+            container_id,
+            host_id,
+            size,
+        };
+        let id_mappings = IdMappings {
+            uid_mappings: vec![mapping(0, 100000, 1000), mapping(1000, 1000, 1)],
+            gid_mappings: vec![mapping(0, 200000, 65536)],
+        };
+        assert!(id_mappings.validate().is_ok());
+        assert_eq!(id_mappings.map_uid(0).unwrap(), 100000);
+        assert_eq!(id_mappings.map_uid(999).unwrap(), 100999);
+        assert_eq!(id_mappings.map_uid(1000).unwrap(), 1000);
+        assert!(id_mappings.map_uid(1001).is_err());
+        assert_eq!(id_mappings.map_gid(1001).unwrap(), 201001);
+        assert_eq!(id_mappings.id().len(), 12);
+
+        assert!(IdMappings::default().is_empty());
+        assert_eq!(IdMappings::default().map_uid(1001).unwrap(), 1001);
+        assert_eq!(IdMappings::default().id(), "");
+
+        let other = IdMappings {
+            gid_mappings: vec![mapping(0, 300000, 65536)],
+            ..id_mappings.clone()
+        };
+        assert_ne!(other.id(), id_mappings.id());
+
+        let invalid = [
+            vec![mapping(0, 100000, 0)],
+            vec![mapping(1, 100000, u32::MAX)],
+            vec![mapping(0, 100000, 1000), mapping(999, 200000, 1)],
+            vec![mapping(0, 100000, 1000), mapping(1000, 100999, 1)],
+        ];
+        for uid_mappings in invalid.iter() {
+            let id_mappings = IdMappings {
+                uid_mappings: uid_mappings.clone(),
+                ..Default::default()
+            };
+            assert!(id_mappings.validate().is_err(), "{:?}", uid_mappings);
+        }
+
+        let data = r#"{"uidMappings": [{"containerID": 0, "hostID": 100000, "size": 1000}]}"#;
+        let id_mappings: IdMappings = serde_json::from_str(data).unwrap();
+        assert_eq!(id_mappings.uid_mappings, vec![mapping(0, 100000, 1000)]);
+        assert!(id_mappings.gid_mappings.is_empty());
+    }
+
+    #[test]
+    fn test_unpack_id_mappings() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+
+        // Map the root of the container to the current user, which needs
+        // no privilege to chown to.
+        let mapping = |host_id| IdMapping {
+            container_id: 0,
+            host_id,
+            size: 1,
+        };
+        let options = UnpackOptions {
+            whiteout: WhiteoutFormat::Oci,
+            id_mappings: IdMappings {
+                uid_mappings: vec![mapping(uid)],
+                gid_mappings: vec![mapping(gid)],
+            },
+            limits: UnpackLimits::default(),
+        };
+
+        let layer = |owner: u64| {
+            layer_tarball_with_owner(
+                &[("dir", None, 0o750), ("dir/file", Some(""), 0o4755)],
+                owner,
+                owner,
+            )
+        };
+
+        let destination = tempdir.path().join("mapped");
+        unpack(layer(0).as_slice(), &destination, &options).unwrap();
+        for (path, mode) in [("dir", 0o750), ("dir/file", 0o4755)].iter() {
+            let metadata = fs::symlink_metadata(destination.join(path)).unwrap();
+            assert_eq!(metadata.uid(), uid, "{}", path);
+            assert_eq!(metadata.gid(), gid, "{}", path);
+            assert_eq!(metadata.mode() & 0o7777, *mode, "{}", path);
+        }
+
+        // The ids out of the mappings are rejected.
+        let destination = tempdir.path().join("unmapped");
+        assert!(unpack(layer(1000).as_slice(), &destination, &options).is_err());
+    }
+
+    // Build a tarball of one regular file with the PAX records.
+    fn pax_tarball(path: &str, records: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut pax = Vec::new();
+        for (key, value) in records.iter() {
+            // The length of a record counts its own digits.
+            let rest = key.len() + value.len() + 3;
+            let mut len = rest + 1;
+            while len != rest + len.to_string().len() {
+                len = rest + len.to_string().len();
+            }
+            pax.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+            pax.extend_from_slice(value);
+            pax.push(b'\n');
+        }
+
+        let mut ar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XHeader);
+        header.set_size(pax.len() as u64);
+        ar.append_data(&mut header, "PaxHeaders/file", pax.as_slice())
+            .unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o755);
+        header.set_size(4);
+        ar.append_data(&mut header, path, "data".as_bytes())
+            .unwrap();
+
+        ar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_acl_from_text() {
+        let acl = acl_from_text("user::rwx,group::r-x,other::r--,user:1000:r-x,mask::rw-").unwrap();
+        let entries = [
+            (ACL_USER_OBJ, 7u16, ACL_UNDEFINED_ID),
+            (ACL_USER, 5, 1000),
+            (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+            (ACL_MASK, 6, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 4, ACL_UNDEFINED_ID),
+        ];
+        let mut expected = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries.iter() {
+            expected.extend_from_slice(&tag.to_le_bytes());
+            expected.extend_from_slice(&perm.to_le_bytes());
+            expected.extend_from_slice(&id.to_le_bytes());
+        }
+        assert_eq!(acl, expected);
+
+        // The numeric id of a named entry is taken from the fourth field.
+        assert_eq!(
+            acl_from_text("u::rwx,u:alice:r-x:1000,g::r-x,m::rw-,o::r--").unwrap(),
+            expected
+        );
+
+        for invalid in [
+            "user:alice:r-x",
+            "user::rwxr",
+            "user::abc",
+            "nobody::rwx",
+            "mask:1:rwx",
+        ]
+        .iter()
+        {
+            assert!(acl_from_text(invalid).is_err(), "{}", invalid);
+        }
+
+        // The named users and the v3 capability root uid are shifted.
+        let id_mappings = IdMappings {
+            uid_mappings: vec![IdMapping {
+                container_id: 0,
+                host_id: 100000,
+                size: 65536,
+            }],
+            gid_mappings: Vec::new(),
+        };
+        let mapped = map_xattr(ACL_ACCESS_XATTR, &acl, &id_mappings).unwrap();
+        assert_eq!(&mapped[16..20], &101000u32.to_le_bytes());
+        assert_eq!(
+            map_xattr("user.foo", b"1000", &id_mappings).unwrap(),
+            b"1000"
+        );
+
+        let mut capability = VFS_CAP_REVISION_3.to_le_bytes().to_vec();
+        capability.resize(24, 0);
+        let mapped = map_xattr(CAPABILITY_XATTR, &capability, &id_mappings).unwrap();
+        assert_eq!(&mapped[20..], &100000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_unpack_xattrs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let acl = "user::rwx,user:1000:r-x,group::r-x,mask::r-x,other::r--";
+
+        // The v2 file capability of cap_net_raw, which needs root to set.
+        let mut capability = 0x0200_0001u32.to_le_bytes().to_vec();
+        capability.extend_from_slice(&(1u32 << 13).to_le_bytes());
+        capability.resize(20, 0);
+
+        let mut records: Vec<(&str, &[u8])> = vec![
+            ("SCHILY.xattr.user.foo", &b"bar"[..]),
+            ("SCHILY.xattr.trusted.foo", &b"bar"[..]),
+            ("SCHILY.acl.access", acl.as_bytes()),
+        ];
+        let is_root = Uid::effective().is_root();
+        if is_root {
+            records.push(("SCHILY.xattr.security.capability", capability.as_slice()));
+        }
+
+        let data = pax_tarball("file", &records);
+        let layer = tempdir.path().join("layer");
+        unpack(
+            data.as_slice(),
+            &layer,
+            &UnpackOptions::new(WhiteoutFormat::Oci),
+        )
+        .unwrap();
+
+        // The xattrs are kept by apply_layer too.
+        let rootfs = tempdir.path().join("rootfs");
+        apply_layer(&layer, &rootfs).unwrap();
+
+        for file in [layer.join("file"), rootfs.join("file")].iter() {
+            assert_eq!(fs::read_to_string(file).unwrap(), "data");
+            assert_eq!(get_xattr(file, "user.foo").unwrap(), Some(b"bar".to_vec()));
+            assert_eq!(
+                get_xattr(file, ACL_ACCESS_XATTR).unwrap(),
+                Some(acl_from_text(acl).unwrap())
+            );
+            assert_eq!(get_xattr(file, "trusted.foo").unwrap(), None);
+
+            if is_root {
+                assert_eq!(
+                    get_xattr(file, CAPABILITY_XATTR).unwrap(),
+                    Some(capability.clone())
+                );
+            }
+        }
+    }
+
+    // Build a tarball of raw entries of (path, type, data or link target),
+    // without the path checks of tar::Builder.
+    fn crafted_tarball(entries: &[(&str, tar::EntryType, &str)]) -> Vec<u8> {
+        let mut ar = tar::Builder::new(Vec::new());
+
+        for (path, entry_type, data) in entries.iter() {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+            header.set_mtime(0);
+            header.set_uid(0);
+            header.set_gid(0);
+
+            let data = if entry_type.is_symlink() || entry_type.is_hard_link() {
+                header.as_old_mut().linkname[..data.len()].copy_from_slice(data.as_bytes());
+                ""
+            } else {
+                data
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            ar.append(&header, data.as_bytes()).unwrap();
+        }
+
+        ar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_resolve_in_root() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+
+        fs::create_dir_all(root.join("usr").join("lib")).unwrap();
+        symlink("usr/lib", root.join("lib")).unwrap();
+        symlink("/usr", root.join("abs")).unwrap();
+        symlink("../../../..", root.join("usr").join("up")).unwrap();
+        symlink("loop2", root.join("loop1")).unwrap();
+        symlink("loop1", root.join("loop2")).unwrap();
+
+        let tests = [
+            ("lib/foo", root.join("usr/lib/foo")),
+            ("abs/lib", root.join("usr/lib")),
+            ("usr/up/etc", root.join("etc")),
+            ("usr/up/abs/../lib", root.join("usr/lib")),
+            ("/../missing/../usr", root.join("usr")),
+            ("", root.to_path_buf()),
+        ];
+        for (path, expected) in tests.iter() {
+            assert_eq!(
+                &resolve_in_root(root, Path::new(path)).unwrap(),
+                expected,
+                "{}",
+                path
+            );
+        }
+
+        assert!(resolve_in_root(root, Path::new("loop1/foo")).is_err());
+    }
+
+    #[test]
+    fn test_unpack_malicious() {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let outside = tempdir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), "secret").unwrap();
+        fs::set_permissions(&outside, fs::Permissions::from_mode(0o700)).unwrap();
+        let outside_str = outside.to_str().unwrap();
+        let secret = format!("{}/secret", outside_str);
+        let outside_mtime = fs::metadata(&outside).unwrap().mtime();
+
+        // (name, entries, whether the unpack succeeds)
+        let tests: Vec<(&str, Vec<(&str, tar::EntryType, &str)>, bool)> = vec![
+            (
+                "parent dir path",
+                vec![("../pwned", Regular, "pwned")],
+                false,
+            ),
+            (
+                "nested parent dir path",
+                vec![("a/../../pwned", Regular, "pwned")],
+                false,
+            ),
+            (
+                "absolute hard link",
+                vec![("link", Link, secret.as_str())],
+                false,
+            ),
+            (
+                "parent dir hard link",
+                vec![("link", Link, "../../outside/secret")],
+                false,
+            ),
+            (
+                "hard link through symlink",
+                vec![("s", Symlink, outside_str), ("link", Link, "s/secret")],
+                false,
+            ),
+            (
+                "symlink loop",
+                vec![
+                    ("a", Symlink, "b"),
+                    ("b", Symlink, "a"),
+                    ("a/pwned", Regular, ""),
+                ],
+                false,
+            ),
+            (
+                "absolute symlink",
+                vec![("s", Symlink, outside_str), ("s/pwned", Regular, "pwned")],
+                true,
+            ),
+            (
+                "relative symlink",
+                vec![
+                    ("s", Symlink, "../../../../outside"),
+                    ("s/pwned", Regular, "pwned"),
+                ],
+                true,
+            ),
+            (
+                "symlink replaced by dir",
+                vec![
+                    ("s", Symlink, outside_str),
+                    ("s", Directory, ""),
+                    ("s/pwned", Regular, ""),
+                ],
+                true,
+            ),
+            (
+                "symlink replaced by file",
+                vec![("s", Symlink, secret.as_str()), ("s", Regular, "pwned")],
+                true,
+            ),
+            (
+                "dir replaced by symlink",
+                vec![
+                    ("a", Directory, ""),
+                    ("a/outside", Directory, ""),
+                    ("a", Symlink, tempdir.path().to_str().unwrap()),
+                ],
+                true,
+            ),
+            (
+                "parent dir whiteout",
+                vec![("a", Directory, ""), ("a/.wh...", Regular, "")],
+                false,
+            ),
+            (
+                "current dir whiteout",
+                vec![("a", Directory, ""), ("a/.wh..", Regular, "")],
+                false,
+            ),
+        ];
+
+        for (i, (name, entries, ok)) in tests.iter().enumerate() {
+            let destination = tempdir.path().join(i.to_string());
+            let result = unpack(
+                crafted_tarball(entries).as_slice(),
+                &destination,
+                &UnpackOptions::new(WhiteoutFormat::Oci),
+            );
+            assert_eq!(result.is_ok(), *ok, "{}: {:?}", name, result);
+
+            // Nothing out of the destination is changed.
+            assert_eq!(fs::read_dir(&outside).unwrap().count(), 1, "{}", name);
+            assert_eq!(
+                fs::read_to_string(outside.join("secret")).unwrap(),
+                "secret",
+                "{}",
+                name
+            );
+            assert_eq!(
+                fs::metadata(&outside).unwrap().mode() & 0o777,
+                0o700,
+                "{}",
+                name
+            );
+            assert_eq!(
+                fs::metadata(&outside).unwrap().mtime(),
+                outside_mtime,
+                "{}",
+                name
+            );
+        }
+
+        // The symlinks are resolved in the destination.
+        let destination = tempdir.path().join("6");
+        let relative = outside.strip_prefix("/").unwrap();
+        assert_eq!(
+            fs::read_to_string(destination.join(relative).join("pwned")).unwrap(),
+            "pwned"
+        );
+        let destination = tempdir.path().join("7");
+        assert!(destination.join("outside").join("pwned").exists());
+        let destination = tempdir.path().join("8");
+        assert!(fs::symlink_metadata(destination.join("s"))
+            .unwrap()
+            .is_dir());
+        let destination = tempdir.path().join("9");
+        assert_eq!(fs::read_to_string(destination.join("s")).unwrap(), "pwned");
+    }
+
+    #[test]
+    fn test_unpack_limits() {
+        use tar::EntryType::{Directory, Regular};
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let limits = UnpackLimits {
+            max_entries: 3,
+            max_size: 8,
+            max_depth: 2,
+        };
+
+        let tests = [
+            (
+                vec![("a", Directory, ""), ("a/b", Regular, "12345678")],
+                true,
+            ),
+            (
+                vec![("a", Directory, ""), ("a/b", Regular, "123456789")],
+                false,
+            ),
+            (vec![("a/b/c", Regular, "")], false),
+            (vec![("./a/b", Regular, "")], true),
+            (
+                vec![
+                    ("a", Regular, ""),
+                    ("b", Regular, ""),
+                    ("c", Regular, ""),
+                    ("d", Regular, ""),
+                ],
+                false,
+            ),
+        ];
+
+        for (i, (entries, ok)) in tests.iter().enumerate() {
+            let options = UnpackOptions {
+                limits: limits.clone(),
+                ..UnpackOptions::new(WhiteoutFormat::Oci)
+            };
+            let destination = tempdir.path().join(i.to_string());
+            let result = unpack(crafted_tarball(entries).as_slice(), &destination, &options);
+            assert_eq!(result.is_ok(), *ok, "{:?}: {:?}", entries, result);
+        }
+
+        assert!(UnpackLimits::default().validate().is_ok());
+        assert!(UnpackLimits {
+            max_size: 0,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_unpack_device() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let mut ar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Char);
+        header.set_mode(0o666);
+        header.set_device_major(1).unwrap();
+        header.set_device_minor(3).unwrap();
+        header.set_size(0);
+        ar.append_data(&mut header, "null", io::empty()).unwrap();
+        let data = ar.into_inner().unwrap();
+
+        let destination = tempdir.path().join("layer");
+        unpack(
+            data.as_slice(),
+            &destination,
+            &UnpackOptions::new(WhiteoutFormat::Oci),
+        )
+        .unwrap();
+
+        // The device is skipped without the privilege to create it.
+        let created = fs::symlink_metadata(destination.join("null")).is_ok();
+        assert_eq!(created, Uid::effective().is_root());
+    }
+
+    #[test]
+    fn test_unpack_whiteout_invalid() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let data = layer_tarball(&[(".wh.", Some(""))]);
+        let destination = tempdir.path().join("layer");
+        assert!(unpack(
+            data.as_slice(),
+            &destination,
+            &UnpackOptions::new(WhiteoutFormat::Overlay)
+        )
+        .is_err());
+
+        // A whiteout of the parent dir left in a layer is never applied.
+        let layer = tempdir.path().join("crafted");
+        fs::create_dir_all(layer.join("a")).unwrap();
+        fs::write(layer.join("a").join(".wh..."), "").unwrap();
+        let rootfs = tempdir.path().join("bundle").join("rootfs");
+        fs::create_dir_all(rootfs.join("a")).unwrap();
+        fs::write(rootfs.join("a").join("foo"), "foo").unwrap();
+
+        assert!(apply_layer(&layer, &rootfs).is_err());
+        assert!(rootfs.join("a").join("foo").exists());
     }
 }
@@ -51,7 +51,7 @@ async fn test_decrypt_layers(#[case] kbc: KBC, #[case] image: &str) {
     common::clean_configs()
         .await
         .expect("Delete configs failed.");
-    let mut image_client = ImageClient::default();
+    let image_client = ImageClient::default();
     assert!(image_client
         .pull_image(image, bundle_dir.path(), &None, &Some(&aa_parameters))
         .await
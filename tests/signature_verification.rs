@@ -98,11 +98,11 @@ async fn signature_verification_one_kbc(#[case] kbc: KBC) {
         std::env::set_var("CC_IMAGE_WORK_DIR", &work_dir.path());
 
         // a new client for every pulling, avoid effection
-        // of cache of old client.
-        let mut image_client = ImageClient::default();
-
-        // enable signature verification
-        image_client.config.security_validate = true;
+        // of cache of old client, with signature verification enabled.
+        let image_client = ImageClient::builder()
+            .security_validate(true)
+            .build()
+            .unwrap();
 
         let bundle_dir = tempfile::tempdir().unwrap();
 